@@ -22,6 +22,7 @@ pub mod sys;
 pub fn init(boot_info: &'static BootInfo) {
     sys::gdt::init();
     sys::idt::init();
+    sys::syscall::init();
     sys::pic::init();
     sys::time::init();
     sys::vga::init();
@@ -29,7 +30,16 @@ pub fn init(boot_info: &'static BootInfo) {
     log!("{} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
 
     sys::memory::init(boot_info);
+    sys::acpi::init();
+    sys::apic::init();
+    sys::thread::init();
+    sys::keyboard::init();
+    sys::mouse::init();
+    if let Err(e) = sys::ps2::init() {
+        log!("PS/2 controller: {:?}", e);
+    }
     sys::clock::init();
+    sys::smp::init();
 }
 
 pub trait Testable {
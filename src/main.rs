@@ -6,7 +6,10 @@
 
 extern crate alloc;
 
-use aaos::log;
+use aaos::{
+    log,
+    sys::{keyboard, task::executor::Executor},
+};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 
@@ -20,7 +23,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     log!("it did not crash");
 
-    aaos::hlt_loop()
+    let mut executor = Executor::new();
+    executor.spawn(keyboard::print_keypresses());
+    executor.run()
 }
 
 /// This function is called on panic.
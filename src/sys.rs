@@ -2,22 +2,87 @@
 pub mod serial;
 #[macro_use]
 pub mod vga;
+#[macro_use]
+pub mod percpu;
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
 pub mod clock;
+pub mod elf;
+pub mod file;
 pub mod gdt;
 pub mod idt;
+pub mod initrd;
+pub mod ipi;
 pub mod keyboard;
 pub mod memory;
+pub mod mouse;
 pub mod pic;
+pub mod pipe;
+pub mod process;
+pub mod ps2;
+pub mod signal;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod thread;
 pub mod time;
+pub mod trap;
+pub mod user;
+pub mod vma;
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static LOG_WALL_CLOCK: AtomicBool = AtomicBool::new(false);
+
+/// What [`log!`] prefixes each message with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogTimestamp {
+    /// Seconds since boot.
+    Uptime,
+    /// Local wall-clock time (see [`clock::estimated_local_time`]).
+    WallClock,
+}
+
+pub fn set_log_timestamp(timestamp: LogTimestamp) {
+    LOG_WALL_CLOCK.store(timestamp == LogTimestamp::WallClock, Ordering::Relaxed);
+}
+
+#[doc(hidden)]
+pub fn log_fmt(args: fmt::Arguments) {
+    // reading the RTC is slow and takes the CMOS lock, which the
+    // code logging may hold
+    let wall_clock = LOG_WALL_CLOCK
+        .load(Ordering::Relaxed)
+        .then(clock::estimated_local_time)
+        .flatten();
+
+    match wall_clock {
+        Some(now) => vga::print_fmt(format_args!(
+            "\x1b[92m[{} {:02}:{:02}:{:02}]\x1b[0m {}\n",
+            now.date(),
+            now.hour(),
+            now.minute(),
+            now.second(),
+            args
+        )),
+        None => vga::print_fmt(format_args!(
+            "\x1b[92m[{:>12.6}]\x1b[0m {}\n",
+            clock::uptime(),
+            args
+        )),
+    }
+}
 
 #[macro_export]
 macro_rules! log {
     ($($arg:tt)*) => ({
         if !cfg!(test) {
-            let uptime = $crate::sys::clock::uptime();
-
-            $crate::sys::vga::print_fmt(format_args!("\x1b[92m[{:>12.6}]\x1b[0m {}\n", uptime, format_args!($($arg)*)));
+            $crate::sys::log_fmt(format_args!($($arg)*));
         }
     });
 }
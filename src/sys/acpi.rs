@@ -0,0 +1,172 @@
+//! Just enough ACPI to locate the system description tables.
+//! [OSDev.org](https://wiki.osdev.org/RSDP)
+
+use alloc::vec::Vec;
+use core::{mem::size_of, slice};
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::sys::memory::phys_to_virt;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+static TABLES: Once<Vec<Sdt>> = Once::new();
+
+/// Root System Description Pointer.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The header shared by all system description tables.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// A system description table with a valid checksum.
+#[derive(Debug, Clone, Copy)]
+pub struct Sdt {
+    bytes: &'static [u8],
+}
+
+impl Sdt {
+    /// # Safety
+    ///
+    /// `addr` must point to a readable table header.
+    unsafe fn at(addr: PhysAddr) -> Option<Self> {
+        let header = phys_to_virt(addr).as_ptr::<SdtHeader>().read_unaligned();
+        let bytes =
+            slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), header.length as usize);
+
+        (bytes.len() >= size_of::<SdtHeader>() && is_valid_checksum(bytes))
+            .then_some(Self { bytes })
+    }
+
+    #[must_use]
+    pub const fn header(&self) -> SdtHeader {
+        unsafe { self.bytes.as_ptr().cast::<SdtHeader>().read_unaligned() }
+    }
+
+    #[must_use]
+    pub const fn signature(&self) -> [u8; 4] {
+        self.header().signature
+    }
+
+    /// The entire table, including the header.
+    #[must_use]
+    pub const fn bytes(&self) -> &'static [u8] {
+        self.bytes
+    }
+
+    /// The table contents following the header.
+    #[must_use]
+    pub fn data(&self) -> &'static [u8] {
+        &self.bytes[size_of::<SdtHeader>()..]
+    }
+}
+
+fn is_valid_checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// # Safety
+///
+/// `addr` must be readable for at least 36 bytes.
+unsafe fn rsdp_at(addr: PhysAddr) -> Option<Rsdp> {
+    let ptr = phys_to_virt(addr).as_ptr::<u8>();
+    let rsdp = ptr.cast::<Rsdp>().read_unaligned();
+
+    if &rsdp.signature != RSDP_SIGNATURE || !is_valid_checksum(slice::from_raw_parts(ptr, 20)) {
+        return None;
+    }
+
+    if rsdp.revision >= 2 && !is_valid_checksum(slice::from_raw_parts(ptr, rsdp.length as usize)) {
+        return None;
+    }
+
+    Some(rsdp)
+}
+
+/// Search the first KiB of the EBDA and the main BIOS area below
+/// 1 MiB for the RSDP.
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda = u64::from(unsafe {
+        phys_to_virt(PhysAddr::new(0x40e))
+            .as_ptr::<u16>()
+            .read_unaligned()
+    }) << 4;
+
+    let areas = [(ebda, 0x400), (0xe0000, 0x20000)];
+
+    areas
+        .into_iter()
+        .filter(|(start, _)| *start != 0)
+        .find_map(|(start, len)| {
+            (start..start + len)
+                .step_by(16)
+                .find_map(|addr| unsafe { rsdp_at(PhysAddr::new(addr)) })
+        })
+}
+
+/// Read the tables pointed to by the RSDT or XSDT.
+fn read_tables(rsdp: &Rsdp) -> Vec<Sdt> {
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(u64::from(rsdp.rsdt_address)), 4)
+    };
+
+    let Some(root) = (unsafe { Sdt::at(root) }) else {
+        return Vec::new();
+    };
+
+    root.data()
+        .chunks_exact(entry_size)
+        .filter_map(|entry| {
+            let addr = match *entry {
+                [a, b, c, d] => u64::from(u32::from_le_bytes([a, b, c, d])),
+                _ => u64::from_le_bytes(entry.try_into().ok()?),
+            };
+            unsafe { Sdt::at(PhysAddr::new(addr)) }
+        })
+        .collect()
+}
+
+/// Find a table by its signature, e.g. `b"FACP"` for the FADT.
+#[must_use]
+pub fn find_table(signature: &[u8; 4]) -> Option<Sdt> {
+    TABLES
+        .get()?
+        .iter()
+        .find(|table| &table.signature() == signature)
+        .copied()
+}
+
+/// Locate the ACPI tables. Must be called after [`crate::sys::memory::init`].
+pub fn init() {
+    TABLES.call_once(|| {
+        find_rsdp()
+            .map(|rsdp| read_tables(&rsdp))
+            .unwrap_or_default()
+    });
+}
@@ -1,5 +1,11 @@
-use linked_list_allocator::LockedHeap;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+};
+use linked_list_allocator::Heap;
+use spin::Mutex;
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
         mapper::{MapToError, Mapper},
         FrameAllocator, Page, PageTableFlags, Size4KiB,
@@ -8,10 +14,39 @@ use x86_64::{
 };
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Allocator = Allocator {
+    heap: Mutex::new(Heap::empty()),
+};
+
+/// The kernel heap. Interrupt handlers may allocate too, so it is only
+/// ever locked with interrupts disabled. It isn't an
+/// [`IrqSafeMutex`](crate::sys::sync::IrqSafeMutex), since its lock
+/// order checks would record every lock held while allocating.
+struct Allocator {
+    heap: Mutex<Heap>,
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| {
+            self.heap
+                .lock()
+                .allocate_first_fit(layout)
+                .map_or(ptr::null_mut(), NonNull::as_ptr)
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| {
+            self.heap
+                .lock()
+                .deallocate(NonNull::new_unchecked(ptr), layout);
+        });
+    }
+}
 
 pub const HEAP_START: usize = 0x4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
 
 /// # Errors
 ///
@@ -36,9 +71,9 @@ pub fn init_heap(
         unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
     }
 
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
-    }
+    interrupts::without_interrupts(|| unsafe {
+        ALLOCATOR.heap.lock().init(HEAP_START, HEAP_SIZE);
+    });
 
     Ok(())
 }
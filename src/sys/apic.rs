@@ -0,0 +1,188 @@
+//! The local APIC of each CPU, used to start up and interrupt the
+//! other CPUs.
+//! [OSDev.org](https://wiki.osdev.org/APIC)
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::{instructions::interrupts, structures::idt::InterruptStackFrame, PhysAddr};
+
+use crate::sys::{acpi, memory};
+
+/// Vector of the interrupt the local APIC raises when an interrupt
+/// disappears before it could be delivered. It needs no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const DEFAULT_ADDRESS: u64 = 0xfee0_0000;
+
+/// Virtual address of the local APIC registers, or 0 before [`init`].
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// APIC IDs of the usable processors listed in the MADT.
+static PROCESSORS: Once<Vec<u8>> = Once::new();
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+enum Register {
+    Id = 0x20,
+    TaskPriority = 0x80,
+    EndOfInterrupt = 0xb0,
+    SpuriousInterruptVector = 0xf0,
+    ErrorStatus = 0x280,
+    InterruptCommandLow = 0x300,
+    InterruptCommandHigh = 0x310,
+}
+
+/// Delivery modes of an inter-processor interrupt.
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+enum DeliveryMode {
+    Fixed = 0b000 << 8,
+    Init = 0b101 << 8,
+    Startup = 0b110 << 8,
+}
+
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+fn read(register: Register) -> u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe { core::ptr::read_volatile((base + register as u64) as *const u32) }
+}
+
+fn write(register: Register, value: u32) {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe { core::ptr::write_volatile((base + register as u64) as *mut u32, value) };
+}
+
+/// Whether the local APIC has been mapped by [`init`].
+#[must_use]
+pub fn is_initialized() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// The APIC ID of the current CPU.
+#[must_use]
+pub fn id() -> u8 {
+    #[allow(clippy::cast_possible_truncation)]
+    let id = (read(Register::Id) >> 24) as u8;
+    id
+}
+
+/// APIC IDs of all usable processors, including the current one.
+#[must_use]
+pub fn processors() -> &'static [u8] {
+    PROCESSORS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Acknowledge an interrupt raised by the local APIC.
+pub fn notify_end_of_interrupt() {
+    write(Register::EndOfInterrupt, 0);
+}
+
+fn send_ipi(apic_id: u8, command: u32) {
+    // an interrupt handler sending an IPI in between would change
+    // the destination
+    interrupts::without_interrupts(|| {
+        write(Register::InterruptCommandHigh, u32::from(apic_id) << 24);
+        write(Register::InterruptCommandLow, command);
+        while read(Register::InterruptCommandLow) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Raise interrupt `vector` on another CPU.
+pub fn send_fixed(apic_id: u8, vector: u8) {
+    send_ipi(
+        apic_id,
+        DeliveryMode::Fixed as u32 | ICR_LEVEL_ASSERT | u32::from(vector),
+    );
+}
+
+/// Send an INIT IPI, resetting the processor into its wait-for-SIPI state.
+pub fn send_init(apic_id: u8) {
+    send_ipi(apic_id, DeliveryMode::Init as u32 | ICR_LEVEL_ASSERT);
+}
+
+/// Send a startup IPI, making a processor in the wait-for-SIPI state
+/// start executing in real mode at `page * 4096`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(
+        apic_id,
+        DeliveryMode::Startup as u32 | ICR_LEVEL_ASSERT | u32::from(page),
+    );
+}
+
+#[allow(clippy::missing_const_for_fn)]
+pub(crate) extern "x86-interrupt" fn handle_spurious_interrupt(_stack_frame: InterruptStackFrame) {}
+
+/// The address of the local APIC and the processors listed in the
+/// MADT. [OSDev.org](https://wiki.osdev.org/MADT)
+fn parse_madt() -> Option<(PhysAddr, Vec<u8>)> {
+    let madt = acpi::find_table(b"APIC")?;
+    let data = madt.data();
+
+    let mut address = u64::from(u32::from_le_bytes(data.get(0..4)?.try_into().ok()?));
+    let mut processors = Vec::new();
+
+    let mut entries = data.get(8..)?;
+    while let [kind, len, ..] = *entries {
+        let len = usize::from(len);
+        let entry = match entries.get(..len) {
+            Some(entry) if len >= 2 => entry,
+            _ => break,
+        };
+
+        match (kind, entry) {
+            // processor local APIC, usable if enabled or online capable
+            (0, [_, _, _processor_id, apic_id, flags, ..]) if flags & 0b11 != 0 => {
+                processors.push(*apic_id);
+            }
+            // local APIC address override
+            (5, [_, _, _, _, address_bytes @ ..]) if address_bytes.len() >= 8 => {
+                address = u64::from_le_bytes(address_bytes[..8].try_into().ok()?);
+            }
+            _ => {}
+        }
+
+        entries = &entries[len..];
+    }
+
+    Some((PhysAddr::new(address), processors))
+}
+
+/// Enable the local APIC of the current CPU.
+pub fn enable() {
+    write(Register::TaskPriority, 0);
+    write(Register::ErrorStatus, 0);
+    write(
+        Register::SpuriousInterruptVector,
+        (1 << 8) | u32::from(SPURIOUS_VECTOR),
+    );
+}
+
+/// Map the local APIC and enable it on the bootstrap processor.
+/// Must be called after [`acpi::init`].
+///
+/// # Panics
+///
+/// Panics if the local APIC's registers can't be mapped.
+pub fn init() {
+    let (address, processors) =
+        parse_madt().unwrap_or_else(|| (PhysAddr::new(DEFAULT_ADDRESS), Vec::new()));
+
+    let base = memory::map_mmio(address, 4096).expect("failed to map the local APIC");
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+
+    PROCESSORS.call_once(|| {
+        if processors.is_empty() {
+            // no MADT, so assume there is only this CPU
+            alloc::vec![id()]
+        } else {
+            processors
+        }
+    });
+
+    enable();
+}
@@ -1,48 +1,379 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicI32, AtomicI64, AtomicU64, Ordering};
 
 use crate::sys;
 
-use time::{Duration, PrimitiveDateTime};
-use x86_64::structures::idt::InterruptStackFrame;
+use time::{error::ComponentRange, Duration, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
+use x86_64::{instructions::interrupts, structures::idt::InterruptStackFrame};
 
-use sys::time::{ticks, PIT_INTERVAL_SECS};
+use sys::{
+    sync::IrqSafeMutex,
+    time::{cycles, PIT_FREQUENCY},
+};
 
-use self::cmos::Cmos;
+use self::cmos::{Cmos, Interrupt, Rtc};
 
 mod cmos;
 
-static LAST_RTC_UPDATE: AtomicUsize = AtomicUsize::new(0);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The RTC can't represent the year. Without a century register,
+    /// it is limited to 1970 through 2069.
+    YearOutOfRange(i32),
+    /// The periodic interrupt rate must be a power of two between 2 and 8192 Hz.
+    InvalidPeriodicRate(u16),
+    /// The RTC holds an invalid date or time.
+    InvalidDate(ComponentRange),
+}
+
+impl From<ComponentRange> for Error {
+    fn from(e: ComponentRange) -> Self {
+        Self::InvalidDate(e)
+    }
+}
+
+/// PIT cycle count at the last RTC update interrupt.
+static LAST_RTC_UPDATE: AtomicU64 = AtomicU64::new(0);
+
+/// The wall-clock time when [`uptime`] was 0 (nanoseconds since the
+/// Unix epoch), or [`UNKNOWN_BOOT_TIME`] if the RTC couldn't be read.
+/// Set by [`init`] and [`set_realtime`].
+static BOOT_TIME: AtomicI64 = AtomicI64::new(UNKNOWN_BOOT_TIME);
+const UNKNOWN_BOOT_TIME: i64 = i64::MIN;
+
+/// Offset of local time from UTC (seconds).
+static UTC_OFFSET: AtomicI32 = AtomicI32::new(0);
+
+static ALARM_CALLBACK: IrqSafeMutex<Option<fn()>> = IrqSafeMutex::new(None);
+static PERIODIC_CALLBACK: IrqSafeMutex<Option<fn()>> = IrqSafeMutex::new(None);
 
 /// System uptime (seconds).
 #[must_use]
 #[allow(clippy::cast_precision_loss)]
 pub fn uptime() -> f64 {
-    PIT_INTERVAL_SECS * ticks() as f64
+    cycles() as f64 / PIT_FREQUENCY
 }
 
-/// # Panics
+/// Read the wall-clock time from the RTC.
+///
+/// # Errors
 ///
 /// If the date returned by CMOS isn't a valid date,
-/// this function panics.
-#[must_use]
+/// an error is returned.
 #[allow(clippy::cast_precision_loss)]
 #[allow(clippy::cast_possible_truncation)]
-pub fn realtime() -> PrimitiveDateTime {
-    let datetime: PrimitiveDateTime = Cmos::new()
-        .rtc_checked()
-        .try_into()
-        .expect("invalid date returned by CMOS");
-    let fract = PIT_INTERVAL_SECS * (ticks() - LAST_RTC_UPDATE.load(Ordering::Relaxed)) as f64;
+pub fn realtime() -> Result<PrimitiveDateTime, Error> {
+    let datetime: PrimitiveDateTime = Cmos::new().rtc_checked().try_into()?;
+    let fract = (cycles() - LAST_RTC_UPDATE.load(Ordering::Relaxed)) as f64 / PIT_FREQUENCY;
     let nanos = (fract * 1_000_000_000.0) as _;
-    datetime + Duration::nanoseconds(nanos)
+    Ok(datetime + Duration::nanoseconds(nanos))
+}
+
+/// A point in time, measured from the Unix epoch
+/// (1970-01-01 00:00:00 UTC).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime {
+    secs: i64,
+    nanos: u32,
+}
+
+impl SystemTime {
+    pub const UNIX_EPOCH: Self = Self { secs: 0, nanos: 0 };
+
+    /// The current wall-clock time.
+    ///
+    /// # Errors
+    ///
+    /// See [`realtime`].
+    pub fn now() -> Result<Self, Error> {
+        Ok(realtime()?.assume_utc().into())
+    }
+
+    /// Whole seconds since the Unix epoch.
+    #[must_use]
+    pub const fn unix_timestamp(self) -> i64 {
+        self.secs
+    }
+
+    /// Nanoseconds past [`SystemTime::unix_timestamp`], always
+    /// less than one second.
+    #[must_use]
+    pub const fn subsec_nanos(self) -> u32 {
+        self.nanos
+    }
+
+    /// Time elapsed since `earlier`, which is negative if `earlier`
+    /// is actually later.
+    #[must_use]
+    pub fn duration_since(self, earlier: Self) -> Duration {
+        Duration::new(self.secs - earlier.secs, 0)
+            + Duration::nanoseconds(i64::from(self.nanos) - i64::from(earlier.nanos))
+    }
+}
+
+impl From<OffsetDateTime> for SystemTime {
+    fn from(datetime: OffsetDateTime) -> Self {
+        Self {
+            secs: datetime.unix_timestamp(),
+            nanos: datetime.nanosecond(),
+        }
+    }
+}
+
+/// The offset of local time from UTC, set by [`set_utc_offset`].
+#[must_use]
+pub fn utc_offset() -> UtcOffset {
+    // only ever set from a valid offset
+    UtcOffset::from_whole_seconds(UTC_OFFSET.load(Ordering::Relaxed)).unwrap_or(UtcOffset::UTC)
+}
+
+/// Set the offset of local time from UTC. The RTC itself is
+/// always assumed to keep UTC.
+pub fn set_utc_offset(offset: UtcOffset) {
+    UTC_OFFSET.store(offset.whole_seconds(), Ordering::Relaxed);
+}
+
+/// The current wall-clock time, at the offset set by [`set_utc_offset`].
+///
+/// # Errors
+///
+/// See [`realtime`].
+pub fn local_time() -> Result<OffsetDateTime, Error> {
+    Ok(realtime()?.assume_utc().to_offset(utc_offset()))
+}
+
+/// The current wall-clock time, at the offset set by [`set_utc_offset`],
+/// estimated from the RTC time read at boot and the [`uptime`] since.
+/// Unlike [`local_time`], this doesn't access the RTC, so it is fast
+/// and can be called from interrupt handlers. `None` if the RTC
+/// couldn't be read.
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub fn estimated_local_time() -> Option<OffsetDateTime> {
+    let boot = BOOT_TIME.load(Ordering::Relaxed);
+    if boot == UNKNOWN_BOOT_TIME {
+        return None;
+    }
+
+    let now = i128::from(boot) + (uptime() * 1_000_000_000.0) as i128;
+    let now = OffsetDateTime::from_unix_timestamp_nanos(now).ok()?;
+    Some(now.to_offset(utc_offset()))
+}
+
+/// Remember that the wall-clock time is `now`, for [`estimated_local_time`].
+#[allow(clippy::cast_possible_truncation)]
+fn set_boot_time(now: PrimitiveDateTime) {
+    let boot = now.assume_utc().unix_timestamp_nanos() - (uptime() * 1_000_000_000.0) as i128;
+    let boot = i64::try_from(boot).unwrap_or(UNKNOWN_BOOT_TIME);
+    BOOT_TIME.store(boot, Ordering::Relaxed);
+}
+
+/// Set the wall-clock time stored in the RTC.
+///
+/// # Errors
+///
+/// If the RTC can't represent the date, an error is returned.
+pub fn set_realtime(datetime: PrimitiveDateTime) -> Result<(), Error> {
+    let rtc = Rtc::try_from(datetime)?;
+    Cmos::new().set_rtc(rtc)?;
+    LAST_RTC_UPDATE.store(cycles(), Ordering::Relaxed);
+    set_boot_time(datetime);
+    Ok(())
+}
+
+/// Call `callback` (in interrupt context) the next time the
+/// wall clock reads `time`. Only one alarm can be set at a time;
+/// setting a new one replaces the previous.
+pub fn set_alarm(time: Time, callback: fn()) {
+    interrupts::without_interrupts(|| {
+        let mut cmos = Cmos::new();
+        *ALARM_CALLBACK.lock() = Some(callback);
+        cmos.set_alarm(time.hour(), time.minute(), time.second());
+        cmos.enable_interrupt(Interrupt::Alarm);
+    });
+}
+
+pub fn clear_alarm() {
+    interrupts::without_interrupts(|| {
+        Cmos::new().disable_interrupt(Interrupt::Alarm);
+        *ALARM_CALLBACK.lock() = None;
+    });
+}
+
+/// Call `callback` (in interrupt context) `hz` times per second,
+/// using the periodic RTC interrupt.
+///
+/// # Errors
+///
+/// `hz` must be a power of two between 2 and 8192.
+pub fn enable_periodic(hz: u16, callback: fn()) -> Result<(), Error> {
+    if !hz.is_power_of_two() || !(2..=8192).contains(&hz) {
+        return Err(Error::InvalidPeriodicRate(hz));
+    }
+
+    // the frequency is 32768 >> (rate - 1), i.e. 2^(16 - rate)
+    #[allow(clippy::cast_possible_truncation)]
+    let rate = 16 - hz.trailing_zeros() as u8;
+
+    interrupts::without_interrupts(|| {
+        let mut cmos = Cmos::new();
+        *PERIODIC_CALLBACK.lock() = Some(callback);
+        cmos.set_periodic_rate(rate);
+        cmos.enable_interrupt(Interrupt::Periodic);
+    });
+
+    Ok(())
+}
+
+pub fn disable_periodic() {
+    interrupts::without_interrupts(|| {
+        Cmos::new().disable_interrupt(Interrupt::Periodic);
+        *PERIODIC_CALLBACK.lock() = None;
+    });
 }
 
 /// Handle an RTC interrupt.
 pub(crate) extern "x86-interrupt" fn handle_rtc_interrupt(_stack_frame: InterruptStackFrame) {
-    Cmos::new().notify_end_of_interrupt();
-    LAST_RTC_UPDATE.store(ticks(), Ordering::Relaxed);
+    let mut cmos = Cmos::new();
+    let pending = cmos.pending_interrupts();
+
+    if Interrupt::Update.is_pending(pending) {
+        LAST_RTC_UPDATE.store(cycles(), Ordering::Relaxed);
+    }
+
+    if Interrupt::Alarm.is_pending(pending) {
+        // alarms are one-shot
+        let callback = ALARM_CALLBACK.lock().take();
+        cmos.disable_interrupt(Interrupt::Alarm);
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+
+    if Interrupt::Periodic.is_pending(pending) {
+        let callback = *PERIODIC_CALLBACK.lock();
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+
+    cmos::notify_end_of_interrupt();
 }
 
 pub fn init() {
-    Cmos::new().enable_update_interrupt();
+    cmos::init();
+    let mut cmos = Cmos::new();
+    // `realtime` isn't accurate until the first update interrupt
+    if let Ok(now) = cmos.rtc_checked().try_into() {
+        set_boot_time(now);
+    }
+    cmos.enable_interrupt(Interrupt::Update);
+}
+
+#[test_case]
+fn set_realtime_round_trip() {
+    use time::{Date, Month};
+
+    let original = realtime().unwrap();
+    let start = uptime();
+
+    let datetime = Date::from_calendar_date(2022, Month::May, 26)
+        .unwrap()
+        .with_hms(23, 59, 58)
+        .unwrap();
+    set_realtime(datetime).unwrap();
+
+    let now = realtime().unwrap();
+    assert!(now >= datetime);
+    assert!(now - datetime < Duration::seconds(2));
+
+    #[allow(clippy::cast_possible_truncation)]
+    let elapsed = Duration::milliseconds(((uptime() - start) * 1000.0) as _);
+    set_realtime(original + elapsed).unwrap();
+}
+
+#[test_case]
+fn estimated_local_time_follows_rtc() {
+    let estimate = estimated_local_time().unwrap();
+    let now = local_time().unwrap();
+    assert!((now - estimate).abs() < Duration::seconds(2));
+}
+
+#[test_case]
+fn set_realtime_out_of_range() {
+    use time::{Date, Month};
+
+    let datetime = Date::from_calendar_date(-1, Month::January, 1)
+        .unwrap()
+        .midnight();
+    assert_eq!(set_realtime(datetime), Err(Error::YearOutOfRange(-1)));
+}
+
+#[test_case]
+fn periodic_interrupt() {
+    use core::sync::atomic::AtomicUsize;
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    assert_eq!(
+        enable_periodic(1000, || {}),
+        Err(Error::InvalidPeriodicRate(1000))
+    );
+
+    enable_periodic(1024, || {
+        COUNT.fetch_add(1, Ordering::Relaxed);
+    })
+    .unwrap();
+    sys::time::sleep(0.1);
+    disable_periodic();
+
+    let count = COUNT.load(Ordering::Relaxed);
+    assert!(count > 50, "only {count} periodic interrupts");
+}
+
+#[test_case]
+fn alarm() {
+    use core::sync::atomic::AtomicBool;
+
+    static FIRED: AtomicBool = AtomicBool::new(false);
+
+    let at = realtime().unwrap() + Duration::seconds(2);
+    set_alarm(at.time(), || FIRED.store(true, Ordering::Relaxed));
+
+    let start = uptime();
+    while !FIRED.load(Ordering::Relaxed) && uptime() - start < 4.0 {
+        sys::time::halt();
+    }
+    assert!(FIRED.load(Ordering::Relaxed));
+    assert!(ALARM_CALLBACK.lock().is_none());
+}
+
+#[test_case]
+fn unix_time() {
+    use time::{Date, Month};
+
+    let datetime = Date::from_calendar_date(2022, Month::May, 26)
+        .unwrap()
+        .with_hms_nano(12, 0, 0, 500)
+        .unwrap()
+        .assume_utc();
+    let time = SystemTime::from(datetime);
+    assert_eq!(time.unix_timestamp(), 1_653_566_400);
+    assert_eq!(time.subsec_nanos(), 500);
+    assert_eq!(
+        time.duration_since(SystemTime::UNIX_EPOCH),
+        Duration::new(1_653_566_400, 500)
+    );
+
+    assert!(SystemTime::now().unwrap() > time);
+}
+
+#[test_case]
+fn local_time_offset() {
+    let offset = UtcOffset::from_hms(2, 0, 0).unwrap();
+    set_utc_offset(offset);
+    let local = local_time().unwrap();
+    set_utc_offset(UtcOffset::UTC);
+
+    assert_eq!(local.offset(), offset);
+    assert!(local - realtime().unwrap().assume_utc() < Duration::seconds(1));
 }
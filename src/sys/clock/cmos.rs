@@ -1,16 +1,27 @@
-use core::hint::spin_loop;
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicU8, Ordering},
+};
 
 use bit_field::BitField;
 use time::{Date, PrimitiveDateTime, Time};
 use x86_64::instructions::{interrupts, port::Port};
 
-use crate::sys::pic::{Irq, PICS};
+use crate::sys::{
+    acpi,
+    pic::{Irq, PICS},
+};
+
+use super::Error;
 
 #[repr(u8)]
 enum Register {
     Second = 0x00,
+    SecondAlarm = 0x01,
     Minute = 0x02,
+    MinuteAlarm = 0x03,
     Hour = 0x04,
+    HourAlarm = 0x05,
     Day = 0x07,
     Month = 0x08,
     Year = 0x09,
@@ -19,14 +30,28 @@ enum Register {
     C = 0x0c,
 }
 
+/// Offset of the century register index in the FADT.
+const FADT_CENTURY_OFFSET: usize = 108;
+
+/// CMOS index of the century register, or 0 if there is none.
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
-#[allow(unused)]
-enum Interrupt {
+pub enum Interrupt {
     Periodic = 1 << 6,
     Alarm = 1 << 5,
     Update = 1 << 4,
 }
 
+impl Interrupt {
+    /// Check if the interrupt is flagged in a value read from register C.
+    #[must_use]
+    pub const fn is_pending(self, c: u8) -> bool {
+        c & self as u8 != 0
+    }
+}
+
 pub struct Cmos {
     addr: Port<u8>,
     data: Port<u8>,
@@ -40,11 +65,7 @@ impl Cmos {
         }
     }
 
-    pub fn enable_update_interrupt(&mut self) {
-        self.enable_interrupt(Interrupt::Update);
-    }
-
-    fn enable_interrupt(&mut self, interrupt: Interrupt) {
+    pub fn enable_interrupt(&mut self, interrupt: Interrupt) {
         interrupts::without_interrupts(|| {
             self.set_nmi(false);
             unsafe {
@@ -56,18 +77,91 @@ impl Cmos {
         });
     }
 
+    pub fn disable_interrupt(&mut self, interrupt: Interrupt) {
+        interrupts::without_interrupts(|| {
+            let prev = self.read_register(Register::B);
+            self.write_register(Register::B, prev & !(interrupt as u8));
+        });
+    }
+
+    /// Set the rate of the periodic interrupt, which fires at
+    /// `32768 >> (rate - 1)` Hz. `rate` must be between 3 and 15
+    /// (8192 Hz to 2 Hz).
+    pub fn set_periodic_rate(&mut self, rate: u8) {
+        debug_assert!((3..=15).contains(&rate));
+
+        interrupts::without_interrupts(|| {
+            let prev = self.read_register(Register::A);
+            self.write_register(Register::A, (prev & 0xf0) | (rate & 0x0f));
+        });
+    }
+
+    /// Make the alarm interrupt fire the next time the clock
+    /// reads `hour:minute:second`.
+    pub fn set_alarm(&mut self, hour: u8, minute: u8, second: u8) {
+        interrupts::without_interrupts(|| {
+            let b = self.read_register(Register::B);
+            self.write_register(Register::SecondAlarm, encode(second, b));
+            self.write_register(Register::MinuteAlarm, encode(minute, b));
+            self.write_register(Register::HourAlarm, encode_hour(hour, b));
+        });
+    }
+
+    /// Write the date and time to the RTC, in whatever format
+    /// (BCD or binary, 12 or 24 hour) it is configured to use.
+    ///
+    /// # Errors
+    ///
+    /// Without a century register, only the years 1970 through
+    /// 2069 can be stored.
+    pub fn set_rtc(&mut self, rtc: Rtc) -> Result<(), Error> {
+        let century_register = century_register();
+        if century_register.is_none() && rtc.century != Some(guess_century(rtc.year)) {
+            return Err(Error::YearOutOfRange(rtc.full_year()));
+        }
+
+        interrupts::without_interrupts(|| {
+            self.wait_for_update();
+
+            // inhibit updates so the clock doesn't tick halfway through
+            let b = self.read_register(Register::B);
+            self.write_register(Register::B, b | 0x80);
+
+            let [second, minute, hour, day, month, year] = rtc.to_registers(b);
+            self.write_register(Register::Second, second);
+            self.write_register(Register::Minute, minute);
+            self.write_register(Register::Hour, hour);
+            self.write_register(Register::Day, day);
+            self.write_register(Register::Month, month);
+            self.write_register(Register::Year, year);
+            if let (Some(register), Some(century)) = (century_register, rtc.century) {
+                self.write_index(register, encode(century, b));
+            }
+
+            self.write_register(Register::B, b & !0x80);
+        });
+
+        Ok(())
+    }
+
     /// Read from the CMOS registers without checking if an
     /// update is in progress, etc.
     fn rtc_unchecked(&mut self) -> Rtc {
-        Rtc::from_registers(
-            self.read_register(Register::Second),
-            self.read_register(Register::Minute),
-            self.read_register(Register::Hour),
-            self.read_register(Register::Day),
-            self.read_register(Register::Month),
-            self.read_register(Register::Year),
-            self.read_register(Register::B),
-        )
+        let b = self.read_register(Register::B);
+        let century = century_register().map(|register| decode(self.read_index(register), b));
+
+        Rtc {
+            century,
+            ..Rtc::from_registers(
+                self.read_register(Register::Second),
+                self.read_register(Register::Minute),
+                self.read_register(Register::Hour),
+                self.read_register(Register::Day),
+                self.read_register(Register::Month),
+                self.read_register(Register::Year),
+                b,
+            )
+        }
     }
 
     pub fn rtc_checked(&mut self) -> Rtc {
@@ -84,12 +178,11 @@ impl Cmos {
         }
     }
 
-    pub fn notify_end_of_interrupt(&mut self) {
-        unsafe {
-            self.select_register(Register::C);
-            self.data.read();
-            PICS.lock().notify_end_of_interrupt(Irq::Rtc.as_u8());
-        }
+    /// Read register C, which flags the pending interrupts (see
+    /// [`Interrupt::is_pending`]). Until it has been read, the RTC
+    /// won't raise any more interrupts.
+    pub fn pending_interrupts(&mut self) -> u8 {
+        self.read_register(Register::C)
     }
 
     #[inline]
@@ -102,12 +195,27 @@ impl Cmos {
     }
 
     fn read_register(&mut self, register: Register) -> u8 {
+        self.read_index(register as u8)
+    }
+
+    fn write_register(&mut self, register: Register, value: u8) {
+        self.write_index(register as u8, value);
+    }
+
+    fn read_index(&mut self, index: u8) -> u8 {
         unsafe {
-            self.select_register(register);
+            self.addr.write(index);
             self.data.read()
         }
     }
 
+    fn write_index(&mut self, index: u8, value: u8) {
+        unsafe {
+            self.addr.write(index);
+            self.data.write(value);
+        }
+    }
+
     #[inline]
     unsafe fn select_register(&mut self, register: Register) {
         self.addr.write(register as u8);
@@ -139,6 +247,8 @@ pub struct Rtc {
     month: u8,
     /// Year (0-99)
     year: u8,
+    /// Century (e.g. 20), if the RTC has a century register.
+    century: Option<u8>,
 }
 
 impl Rtc {
@@ -161,9 +271,17 @@ impl Rtc {
             year = (year & 0x0F) + ((year / 16) * 10);
         }
 
-        let is_12h = !b.get_bit(1) && hour.get_bit(7);
+        let is_12h = !b.get_bit(1);
         if is_12h {
-            hour = ((hour & 0x7f) + 12) % 24;
+            // 12 AM is midnight and 12 PM is noon
+            let pm = hour.get_bit(7);
+            hour &= 0x7f;
+            if hour == 12 {
+                hour = 0;
+            }
+            if pm {
+                hour += 12;
+            }
         }
 
         Self {
@@ -173,26 +291,130 @@ impl Rtc {
             day,
             month,
             year,
+            century: None,
+        }
+    }
+
+    /// The full year, using the century register if available and
+    /// otherwise assuming it is between 1970 and 2069.
+    fn full_year(self) -> i32 {
+        let century = self.century.unwrap_or_else(|| guess_century(self.year));
+        i32::from(century) * 100 + i32::from(self.year)
+    }
+
+    /// The inverse of [`Rtc::from_registers`]: encode the date and
+    /// time according to the format specified by register B.
+    fn to_registers(self, b: u8) -> [u8; 6] {
+        [
+            encode(self.second, b),
+            encode(self.minute, b),
+            encode_hour(self.hour, b),
+            encode(self.day, b),
+            encode(self.month, b),
+            encode(self.year, b),
+        ]
+    }
+}
+
+const fn guess_century(year: u8) -> u8 {
+    if year < 70 {
+        20
+    } else {
+        19
+    }
+}
+
+/// Decode a BCD or binary value, depending on register B.
+fn decode(value: u8, b: u8) -> u8 {
+    let is_bcd = !b.get_bit(2);
+    if is_bcd {
+        (value & 0x0F) + ((value / 16) * 10)
+    } else {
+        value
+    }
+}
+
+/// Encode a value as BCD or binary, depending on register B.
+fn encode(value: u8, b: u8) -> u8 {
+    let is_bcd = !b.get_bit(2);
+    if is_bcd {
+        (value / 10) << 4 | (value % 10)
+    } else {
+        value
+    }
+}
+
+/// Encode an hour (0-23) in 12 or 24 hour format, depending on register B.
+fn encode_hour(hour: u8, b: u8) -> u8 {
+    let is_12h = !b.get_bit(1);
+    if is_12h {
+        let pm = if hour >= 12 { 0x80 } else { 0 };
+        let hour = match hour % 12 {
+            0 => 12,
+            hour => hour,
+        };
+        encode(hour, b) | pm
+    } else {
+        encode(hour, b)
+    }
+}
+
+impl TryFrom<PrimitiveDateTime> for Rtc {
+    type Error = Error;
+
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn try_from(value: PrimitiveDateTime) -> Result<Self, Self::Error> {
+        let year = value.year();
+        if !(0..10_000).contains(&year) {
+            return Err(Error::YearOutOfRange(year));
         }
+
+        Ok(Self {
+            second: value.second(),
+            minute: value.minute(),
+            hour: value.hour(),
+            day: value.day(),
+            month: value.month().into(),
+            year: (year % 100) as u8,
+            century: Some((year / 100) as u8),
+        })
     }
 }
 
 impl TryFrom<Rtc> for PrimitiveDateTime {
-    type Error = time::Error;
+    type Error = Error;
 
     fn try_from(value: Rtc) -> Result<Self, Self::Error> {
-        let century = if value.year < 70 { 2000 } else { 1900 };
-        let date = Date::from_calendar_date(
-            i32::from(value.year) + century,
-            value.month.try_into()?,
-            value.day,
-        )?;
+        let date = Date::from_calendar_date(value.full_year(), value.month.try_into()?, value.day)?;
         let time = Time::from_hms(value.hour, value.minute, value.second)?;
 
         Ok(Self::new(date, time))
     }
 }
 
+/// Acknowledge an RTC interrupt at the PIC.
+pub fn notify_end_of_interrupt() {
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(Irq::Rtc.as_u8());
+    }
+}
+
+/// Look up the century register in the FADT, if there is one.
+pub fn init() {
+    let register = acpi::find_table(b"FACP")
+        .and_then(|fadt| fadt.bytes().get(FADT_CENTURY_OFFSET).copied())
+        .unwrap_or(0);
+    CENTURY_REGISTER.store(register, Ordering::Relaxed);
+}
+
+fn century_register() -> Option<u8> {
+    match CENTURY_REGISTER.load(Ordering::Relaxed) {
+        0 => None,
+        register => Some(register),
+    }
+}
+
 #[test_case]
 fn rtc_bcd() {
     assert_eq!(
@@ -211,7 +433,55 @@ fn rtc_bcd() {
             hour: 31,
             day: 31,
             month: 3,
-            year: 70
+            year: 70,
+            century: None,
+        }
+    );
+}
+
+#[test_case]
+fn rtc_register_round_trip() {
+    let rtc = Rtc {
+        second: 7,
+        minute: 59,
+        hour: 0,
+        day: 26,
+        month: 5,
+        year: 22,
+        century: None,
+    };
+
+    for b in [0b000, 0b010, 0b100, 0b110] {
+        for hour in [0, 1, 11, 12, 13, 23] {
+            let rtc = Rtc { hour, ..rtc };
+            let [second, minute, hour, day, month, year] = rtc.to_registers(b);
+            assert_eq!(
+                Rtc::from_registers(second, minute, hour, day, month, year, b),
+                rtc
+            );
+        }
+    }
+}
+
+#[test_case]
+fn rtc_century() {
+    let rtc = Rtc {
+        second: 0,
+        minute: 0,
+        hour: 0,
+        day: 1,
+        month: 1,
+        year: 70,
+        century: None,
+    };
+    assert_eq!(rtc.full_year(), 1970);
+    assert_eq!(Rtc { year: 69, ..rtc }.full_year(), 2069);
+    assert_eq!(
+        Rtc {
+            century: Some(21),
+            ..rtc
         }
+        .full_year(),
+        2170
     );
 }
@@ -0,0 +1,441 @@
+//! Loading and running statically linked ELF64 executables in user mode.
+//! [OSDev.org](https://wiki.osdev.org/ELF)
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::mem::size_of;
+use x86_64::{
+    structures::paging::{mapper::MapToError, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use crate::sys::{
+    memory, process,
+    user::{self, AddressSpace, STACK_TOP},
+};
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3e;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+// auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// Arguments, environment and auxiliary vector have to fit into this
+/// much of the user stack.
+const MAX_ARGS_SIZE: usize = 4096 * 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The file ends before a header or segment does.
+    Truncated,
+    /// Not an ELF file at all.
+    BadMagic,
+    /// An ELF file, but not a 64-bit little-endian x86-64 executable.
+    Unsupported,
+    /// A segment is invalid or lies outside of user memory.
+    BadSegment,
+    /// The arguments and environment don't fit on the user stack.
+    ArgumentsTooLong,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for Error {
+    fn from(e: MapToError<Size4KiB>) -> Self {
+        Self::Map(e)
+    }
+}
+
+/// The ELF64 file header.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Header {
+    pub ident: [u8; 16],
+    pub kind: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+/// An ELF64 program header, describing a segment.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    /// The page flags the segment asks for (in addition to user access).
+    #[must_use]
+    pub const fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.flags & PF_W != 0 {
+            flags = flags.union(PageTableFlags::WRITABLE);
+        }
+        if self.flags & PF_X == 0 {
+            flags = flags.union(PageTableFlags::NO_EXECUTE);
+        }
+        flags
+    }
+}
+
+/// A validated executable.
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: Header,
+}
+
+impl<'a> Elf<'a> {
+    /// Check that `data` is an x86-64 executable whose loadable segments
+    /// all fit into user memory (below the user stack).
+    ///
+    /// # Errors
+    ///
+    /// If it isn't, an error is returned.
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        if data.len() < size_of::<Header>() {
+            return Err(if data.starts_with(MAGIC) {
+                Error::Truncated
+            } else {
+                Error::BadMagic
+            });
+        }
+        let header = unsafe { data.as_ptr().cast::<Header>().read_unaligned() };
+
+        if &header.ident[..4] != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        if header.ident[4] != CLASS_64
+            || header.ident[5] != DATA_LITTLE_ENDIAN
+            || header.ident[6] != VERSION_CURRENT
+            || header.kind != TYPE_EXECUTABLE
+            || header.machine != MACHINE_X86_64
+            || usize::from(header.phentsize) != size_of::<ProgramHeader>()
+        {
+            return Err(Error::Unsupported);
+        }
+
+        let phdrs_end = usize::try_from(header.phoff).ok().and_then(|phoff| {
+            phoff.checked_add(usize::from(header.phnum) * size_of::<ProgramHeader>())
+        });
+        if phdrs_end.map_or(true, |end| end > data.len()) {
+            return Err(Error::Truncated);
+        }
+
+        let elf = Self { data, header };
+        for segment in elf.segments().filter(|s| s.kind == PT_LOAD) {
+            elf.check_segment(&segment)?;
+        }
+        if !elf.is_mapped(header.entry) {
+            return Err(Error::BadSegment);
+        }
+
+        Ok(elf)
+    }
+
+    fn check_segment(&self, segment: &ProgramHeader) -> Result<(), Error> {
+        let file_end = segment.offset.checked_add(segment.filesz);
+        if file_end.map_or(true, |end| end > self.data.len() as u64) {
+            return Err(Error::Truncated);
+        }
+
+        let mem_end = segment.vaddr.checked_add(segment.memsz);
+        let user = memory::USER_START..=user::STACK_TOP - user::STACK_SIZE;
+        if segment.filesz > segment.memsz
+            || !user.contains(&segment.vaddr)
+            || !mem_end.map_or(false, |end| user.contains(&end))
+        {
+            return Err(Error::BadSegment);
+        }
+        Ok(())
+    }
+
+    fn is_mapped(&self, addr: u64) -> bool {
+        self.segments()
+            .any(|s| s.kind == PT_LOAD && (s.vaddr..s.vaddr + s.memsz).contains(&addr))
+    }
+
+    #[must_use]
+    pub const fn header(&self) -> Header {
+        self.header
+    }
+
+    #[must_use]
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.header.entry)
+    }
+
+    /// All program headers.
+    pub fn segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        #[allow(clippy::cast_possible_truncation)]
+        let phoff = self.header.phoff as usize;
+        (0..usize::from(self.header.phnum)).map(move |i| {
+            let offset = phoff + i * size_of::<ProgramHeader>();
+            unsafe {
+                data[offset..]
+                    .as_ptr()
+                    .cast::<ProgramHeader>()
+                    .read_unaligned()
+            }
+        })
+    }
+
+    /// Where the program headers end up in memory, if they are loaded.
+    fn phdr_addr(&self) -> Option<u64> {
+        let phoff = self.header.phoff;
+        self.segments()
+            .find(|s| s.kind == PT_PHDR)
+            .map(|s| s.vaddr)
+            .or_else(|| {
+                self.segments()
+                    .find(|s| s.kind == PT_LOAD && (s.offset..s.offset + s.filesz).contains(&phoff))
+                    .map(|s| s.vaddr + (phoff - s.offset))
+            })
+    }
+
+    /// Map the loadable segments into `space` and copy them there.
+    ///
+    /// # Errors
+    ///
+    /// Mapping errors will be propagated.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a segment can't be written to the pages mapped for it.
+    pub fn load(&self, space: &mut AddressSpace) -> Result<(), Error> {
+        // segments can share pages, which then get the flags of both
+        let mut pages: BTreeMap<Page<Size4KiB>, PageTableFlags> = BTreeMap::new();
+        for segment in self.segments().filter(|s| s.kind == PT_LOAD) {
+            let flags = segment.page_flags();
+            for page in user::page_range(segment.vaddr, segment.memsz) {
+                let entry = pages.entry(page).or_insert(flags);
+                *entry = merge_flags(*entry, flags);
+            }
+        }
+
+        // writable until the contents have been copied
+        for &page in pages.keys() {
+            space.map(Page::range(page, page + 1), PageTableFlags::WRITABLE)?;
+        }
+
+        for segment in self.segments().filter(|s| s.kind == PT_LOAD) {
+            #[allow(clippy::cast_possible_truncation)]
+            let (offset, filesz) = (segment.offset as usize, segment.filesz as usize);
+            // the rest of the segment is already zeroed
+            let written = space.write(
+                VirtAddr::new(segment.vaddr),
+                &self.data[offset..offset + filesz],
+            );
+            assert!(written, "segment not mapped");
+        }
+
+        for (&page, &flags) in &pages {
+            space
+                .protect(Page::range(page, page + 1), flags)
+                .expect("segment not mapped");
+        }
+
+        if let Some(&last) = pages.keys().next_back() {
+            space.set_break_start((last + 1).start_address());
+        }
+        Ok(())
+    }
+
+    /// Map the user stack into `space`, and lay out `argv`, `envp` and
+    /// the auxiliary vector on it the way the System V ABI prescribes.
+    /// Returns the initial stack pointer.
+    ///
+    /// # Errors
+    ///
+    /// If the stack can't be mapped or the arguments don't fit, an error
+    /// is returned.
+    pub fn setup_stack(
+        &self,
+        space: &mut AddressSpace,
+        argv: &[&str],
+        envp: &[&str],
+    ) -> Result<VirtAddr, Error> {
+        space.map_stack()?;
+        let mut stack = StackBuilder {
+            space,
+            sp: STACK_TOP,
+        };
+
+        let argv_ptrs = argv
+            .iter()
+            .map(|arg| stack.push_str(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        let envp_ptrs = envp
+            .iter()
+            .map(|var| stack.push_str(var))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut auxv = Vec::new();
+        if let Some(phdr) = self.phdr_addr() {
+            auxv.extend([
+                AT_PHDR,
+                phdr,
+                AT_PHENT,
+                size_of::<ProgramHeader>() as u64,
+                AT_PHNUM,
+                u64::from(self.header.phnum),
+            ]);
+        }
+        auxv.extend([AT_PAGESZ, 4096, AT_ENTRY, self.header.entry, AT_NULL, 0]);
+
+        let mut words = Vec::new();
+        words.push(argv.len() as u64);
+        words.extend(&argv_ptrs);
+        words.push(0);
+        words.extend(&envp_ptrs);
+        words.push(0);
+        words.extend(auxv);
+
+        // the stack pointer must end up 16-byte aligned
+        stack.sp &= !0xf;
+        stack.sp -= (words.len() as u64 * 8) % 16;
+        stack.push_words(&words).map(VirtAddr::new)
+    }
+}
+
+/// The flags of a page shared by two segments: writable or executable
+/// if either of them is.
+fn merge_flags(a: PageTableFlags, b: PageTableFlags) -> PageTableFlags {
+    let no_execute =
+        a.contains(PageTableFlags::NO_EXECUTE) && b.contains(PageTableFlags::NO_EXECUTE);
+    let mut flags = (a | b) - PageTableFlags::NO_EXECUTE;
+    flags.set(PageTableFlags::NO_EXECUTE, no_execute);
+    flags
+}
+
+/// Builds the initial user stack downwards from [`STACK_TOP`]: the
+/// strings at the top, and below them what `_start` expects to find
+/// at the stack pointer.
+struct StackBuilder<'a> {
+    space: &'a mut AddressSpace,
+    /// The lowest address written so far.
+    sp: u64,
+}
+
+impl StackBuilder<'_> {
+    fn push_bytes(&mut self, bytes: &[u8]) -> Result<u64, Error> {
+        self.sp -= bytes.len() as u64;
+        if STACK_TOP - self.sp > MAX_ARGS_SIZE as u64 {
+            return Err(Error::ArgumentsTooLong);
+        }
+        let written = self.space.write(VirtAddr::new(self.sp), bytes);
+        assert!(written, "user stack not mapped");
+        Ok(self.sp)
+    }
+
+    /// Push a NUL-terminated copy of `s` and return its address.
+    fn push_str(&mut self, s: &str) -> Result<u64, Error> {
+        self.push_bytes(&[0])?;
+        self.push_bytes(s.as_bytes())
+    }
+
+    fn push_words(&mut self, words: &[u64]) -> Result<u64, Error> {
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_ne_bytes()).collect();
+        self.push_bytes(&bytes)
+    }
+}
+
+/// Run the executable `data` as a new process with the arguments `argv`
+/// and the environment `envp`, and wait for it to exit. Returns the exit
+/// code.
+///
+/// # Errors
+///
+/// If the executable is invalid or can't be loaded, an error is returned.
+///
+/// # Panics
+///
+/// Panics if the new process can't be waited for, e.g. because another
+/// thread of the caller waited for it first.
+pub fn exec(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<i32, Error> {
+    let pid = process::spawn(data, argv, envp)?;
+    let (_, code) = process::wait(Some(pid)).expect("spawned process not found");
+    Ok(code)
+}
+
+#[cfg(test)]
+pub(crate) static HELLO: &[u8] = include_bytes!("./elf/hello.elf");
+#[cfg(test)]
+pub(crate) static FORK: &[u8] = include_bytes!("./elf/fork.elf");
+#[cfg(test)]
+pub(crate) static MMAP: &[u8] = include_bytes!("./elf/mmap.elf");
+#[cfg(test)]
+pub(crate) static PIPE: &[u8] = include_bytes!("./elf/pipe.elf");
+#[cfg(test)]
+pub(crate) static SIGNAL: &[u8] = include_bytes!("./elf/signal.elf");
+
+#[test_case]
+fn exec_hello() {
+    let status = exec(HELLO, &["hello", "world\n"], &["HOME=/"]).unwrap();
+    assert_eq!(status, 2);
+}
+
+#[test_case]
+fn parse_hello() {
+    let elf = Elf::parse(HELLO).unwrap();
+    assert_eq!(elf.entry(), VirtAddr::new(0x1000_0000_1000));
+
+    let segments: Vec<_> = elf.segments().filter(|s| s.kind == PT_LOAD).collect();
+    assert_eq!(segments.len(), 3);
+    assert!(segments[1].page_flags().contains(PageTableFlags::PRESENT));
+    assert!(!segments[1]
+        .page_flags()
+        .contains(PageTableFlags::NO_EXECUTE));
+    assert!(segments[2].page_flags().contains(PageTableFlags::WRITABLE));
+    assert!(segments[2].memsz > segments[2].filesz);
+}
+
+#[test_case]
+fn reject_invalid() {
+    assert!(matches!(Elf::parse(b"#!/bin/sh\n"), Err(Error::BadMagic)));
+    assert!(matches!(Elf::parse(&HELLO[..32]), Err(Error::Truncated)));
+    assert!(matches!(
+        Elf::parse(&HELLO[..0x1000]),
+        Err(Error::Truncated)
+    ));
+
+    let mut data = HELLO.to_vec();
+    data[18] = 0x28; // ARM
+    assert!(matches!(Elf::parse(&data), Err(Error::Unsupported)));
+
+    // the first segment, moved into kernel memory
+    let mut data = HELLO.to_vec();
+    let vaddr = 64 + 16;
+    data[vaddr..vaddr + 8].copy_from_slice(&0x20_0000_u64.to_le_bytes());
+    assert!(matches!(Elf::parse(&data), Err(Error::BadSegment)));
+}
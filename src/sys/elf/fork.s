@@ -0,0 +1,75 @@
+# The test program for `fork`. Forks, and exits with 0 if the parent
+# and the child each only see their own writes to memory and have the
+# same registers, or 255 if anything is unexpected.
+#
+# as fork.s -o fork.o
+# ld -static -nostdlib -s -z max-page-size=4096 -Ttext-segment=0x100000000000 fork.o -o fork.elf
+
+    .intel_syntax noprefix
+
+    .set SYS_EXIT, 0
+    .set SYS_WAIT, 4
+    .set SYS_FORK, 5
+
+    .text
+    .global _start
+_start:
+    mov qword ptr [rip + value], 1
+    push 1
+    mov r13, 0x1234
+
+    mov eax, SYS_FORK
+    syscall
+    cmp r13, 0x1234
+    jne fail
+    test rax, rax
+    js fail
+    jz child
+
+    # the parent: copy the data and stack pages before the child does
+    mov r12, rax
+    mov qword ptr [rip + value], 2
+    mov qword ptr [rsp], 2
+
+    # wait(pid, &status), which makes the kernel write to a
+    # copy-on-write page
+    mov rdi, r12
+    lea rsi, [rip + status]
+    mov eax, SYS_WAIT
+    syscall
+    cmp rax, r12
+    jne fail
+    cmp dword ptr [rip + status], 3
+    jne fail
+    cmp qword ptr [rip + value], 2
+    jne fail
+    cmp qword ptr [rsp], 2
+    jne fail
+    xor edi, edi
+    jmp exit
+
+child:
+    cmp qword ptr [rip + value], 1
+    jne fail
+    cmp qword ptr [rsp], 1
+    jne fail
+    mov qword ptr [rip + value], 3
+    mov qword ptr [rsp], 3
+    mov edi, 3
+    jmp exit
+
+fail:
+    mov edi, 255
+exit:
+    mov eax, SYS_EXIT
+    syscall
+    ud2
+
+    .data
+value:
+    .quad 0
+
+    .bss
+    .balign 4096
+status:
+    .long 0
@@ -0,0 +1,87 @@
+# The test program for `sys::elf`. Writes its first argument and
+# exits with its argument count, or 255 if anything about its memory
+# or the auxiliary vector is unexpected.
+#
+# as hello.s -o hello.o
+# ld -static -nostdlib -s -z max-page-size=4096 -Ttext-segment=0x100000000000 hello.o -o hello.elf
+
+    .intel_syntax noprefix
+
+    .set SYS_EXIT, 0
+    .set SYS_WRITE, 1
+    .set AT_NULL, 0
+    .set AT_PAGESZ, 6
+    .set AT_ENTRY, 9
+
+    .text
+    .global _start
+_start:
+    mov r12, [rsp]                  # argc
+    cmp r12, 2
+    jb fail
+
+    # write(1, argv[1], strlen(argv[1]))
+    mov rsi, [rsp + 16]
+    xor edx, edx
+1:  cmp byte ptr [rsi + rdx], 0
+    je 2f
+    inc rdx
+    jmp 1b
+2:  mov eax, SYS_WRITE
+    mov edi, 1
+    syscall
+    test rax, rax
+    js fail
+
+    # .bss starts zeroed, .data is initialized and writable
+    cmp qword ptr [rip + counter], 0
+    jne fail
+    cmp qword ptr [rip + magic], 0x1234
+    jne fail
+    inc qword ptr [rip + magic]
+
+    # skip argv and envp
+    lea rbx, [rsp + r12 * 8 + 16]
+3:  mov rax, [rbx]
+    add rbx, 8
+    test rax, rax
+    jnz 3b
+
+    # check the auxiliary vector
+    xor r13d, r13d
+4:  mov rax, [rbx]
+    mov rcx, [rbx + 8]
+    add rbx, 16
+    cmp rax, AT_NULL
+    je 6f
+    cmp rax, AT_PAGESZ
+    jne 5f
+    cmp rcx, 4096
+    jne fail
+    or r13d, 1
+5:  cmp rax, AT_ENTRY
+    jne 4b
+    lea rdx, [rip + _start]
+    cmp rcx, rdx
+    jne fail
+    or r13d, 2
+    jmp 4b
+6:  cmp r13d, 3
+    jne fail
+
+    mov rdi, r12
+    jmp exit
+fail:
+    mov edi, 255
+exit:
+    mov eax, SYS_EXIT
+    syscall
+    ud2
+
+    .data
+magic:
+    .quad 0x1234
+
+    .bss
+counter:
+    .quad 0
@@ -0,0 +1,127 @@
+# The test program for the memory system calls. Exits with 0 if
+# mapped memory behaves as expected, or 255 if anything doesn't.
+#
+# as mmap.s -o mmap.o
+# ld -static -nostdlib -s -z max-page-size=4096 -Ttext-segment=0x100000000000 mmap.o -o mmap.elf
+
+    .intel_syntax noprefix
+
+    .set SYS_EXIT, 0
+    .set SYS_WRITE, 1
+    .set SYS_WAIT, 4
+    .set SYS_MMAP, 6
+    .set SYS_MUNMAP, 7
+    .set SYS_MPROTECT, 8
+    .set SYS_BRK, 9
+    .set PROT_READ, 1
+    .set PROT_WRITE, 2
+    .set MAP_PRIVATE, 0x02
+    .set MAP_FIXED, 0x10
+    .set MAP_ANONYMOUS, 0x20
+    .set EFAULT, -14
+    .set ENODEV, -19
+
+    .text
+    .global _start
+_start:
+    # mmap(0, 8192, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+    xor edi, edi
+    mov esi, 8192
+    mov edx, PROT_READ | PROT_WRITE
+    mov r10d, MAP_PRIVATE | MAP_ANONYMOUS
+    mov r8, -1
+    xor r9d, r9d
+    mov eax, SYS_MMAP
+    syscall
+    test rax, rax
+    js fail
+    mov r12, rax
+
+    # both pages are allocated on the first access
+    cmp qword ptr [r12], 0
+    jne fail
+    mov qword ptr [r12 + 4096], 42
+    cmp qword ptr [r12 + 4096], 42
+    jne fail
+
+    # mprotect(r12, 4096, PROT_READ): the kernel can't write to it
+    # either, like with wait(-1, r12)
+    mov rdi, r12
+    mov esi, 4096
+    mov edx, PROT_READ
+    mov eax, SYS_MPROTECT
+    syscall
+    test rax, rax
+    jnz fail
+    mov rdi, -1
+    mov rsi, r12
+    mov eax, SYS_WAIT
+    syscall
+    cmp rax, EFAULT
+    jne fail
+
+    # munmap(r12, 8192), after which the memory is gone
+    mov rdi, r12
+    mov esi, 8192
+    mov eax, SYS_MUNMAP
+    syscall
+    test rax, rax
+    jnz fail
+    mov edi, 1
+    mov rsi, r12
+    mov edx, 1
+    mov eax, SYS_WRITE
+    syscall
+    cmp rax, EFAULT
+    jne fail
+
+    # mmap(r12, 4096, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED, -1, 0)
+    mov rdi, r12
+    mov esi, 4096
+    mov edx, PROT_READ | PROT_WRITE
+    mov r10d, MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED
+    mov r8, -1
+    xor r9d, r9d
+    mov eax, SYS_MMAP
+    syscall
+    cmp rax, r12
+    jne fail
+    cmp qword ptr [r12], 0
+    jne fail
+
+    # the console can't be mapped
+    xor edi, edi
+    mov esi, 4096
+    mov edx, PROT_READ
+    mov r10d, MAP_PRIVATE
+    mov r8d, 1
+    xor r9d, r9d
+    mov eax, SYS_MMAP
+    syscall
+    cmp rax, ENODEV
+    jne fail
+
+    # brk(0), then grow the heap by two pages and use it
+    xor edi, edi
+    mov eax, SYS_BRK
+    syscall
+    mov r13, rax
+    lea rdi, [r13 + 8192]
+    mov eax, SYS_BRK
+    syscall
+    lea rdx, [r13 + 8192]
+    cmp rax, rdx
+    jne fail
+    mov qword ptr [r13 + 8184], 7
+    cmp qword ptr [r13 + 8184], 7
+    jne fail
+
+    xor edi, edi
+    jmp exit
+
+fail:
+    mov edi, 255
+exit:
+    mov eax, SYS_EXIT
+    syscall
+    ud2
@@ -0,0 +1,175 @@
+# The test program for pipes. Without arguments, exits with 0 if a
+# forked child can send more than fits in a pipe through it, or 255 if
+# anything about it is unexpected. With one argument it reads from an
+# empty pipe until it is killed, and with two it writes to a pipe
+# without a reader.
+#
+# as pipe.s -o pipe.o
+# ld -static -nostdlib -s -z max-page-size=4096 -Ttext-segment=0x100000000000 pipe.o -o pipe.elf
+
+    .intel_syntax noprefix
+
+    .set SYS_EXIT, 0
+    .set SYS_WRITE, 1
+    .set SYS_CLOSE, 2
+    .set SYS_WAIT, 4
+    .set SYS_FORK, 5
+    .set SYS_SIGACTION, 11
+    .set SYS_READ, 14
+    .set SYS_PIPE, 15
+    .set SIG_IGN, 1
+    .set SIGPIPE, 13
+    .set EPIPE, -32
+    .set BLOCK, 4096
+    .set BLOCKS, 4
+
+    .text
+    .global _start
+_start:
+    # pipe(fds)
+    lea rdi, [rip + fds]
+    mov eax, SYS_PIPE
+    syscall
+    test rax, rax
+    jnz fail
+
+    mov rax, [rsp]                  # argc
+    cmp rax, 2
+    je read_forever
+    cmp rax, 3
+    je broken_pipe
+
+    # fill the block to send with i % 256
+    lea rdi, [rip + block]
+    xor ecx, ecx
+fill:
+    mov [rdi + rcx], cl
+    inc ecx
+    cmp ecx, BLOCK
+    jne fill
+
+    mov eax, SYS_FORK
+    syscall
+    test rax, rax
+    js fail
+    jz child
+    mov r12, rax
+
+    # close(fds[1]), and read until the child closes it too
+    mov edi, [rip + fds + 4]
+    mov eax, SYS_CLOSE
+    syscall
+    xor r13d, r13d                  # bytes read
+read_loop:
+    mov edi, [rip + fds]
+    lea rsi, [rip + buffer]
+    mov edx, 1000
+    mov eax, SYS_READ
+    syscall
+    test rax, rax
+    js fail
+    jz read_all
+    # each byte is its offset in the block
+    xor ecx, ecx
+check:
+    lea rdx, [r13 + rcx]
+    cmp byte ptr [rsi + rcx], dl
+    jne fail
+    inc rcx
+    cmp rcx, rax
+    jne check
+    add r13, rax
+    jmp read_loop
+read_all:
+    cmp r13, BLOCK * BLOCKS
+    jne fail
+
+    # wait(child, status)
+    mov rdi, r12
+    lea rsi, [rip + status]
+    mov eax, SYS_WAIT
+    syscall
+    cmp rax, r12
+    jne fail
+    cmp dword ptr [rip + status], 0
+    jne fail
+
+    # with SIGPIPE ignored, writing without a reader fails with EPIPE
+    mov edi, SIGPIPE
+    mov esi, SIG_IGN
+    xor edx, edx
+    mov eax, SYS_SIGACTION
+    syscall
+    lea rdi, [rip + fds]
+    mov eax, SYS_PIPE
+    syscall
+    mov edi, [rip + fds]
+    mov eax, SYS_CLOSE
+    syscall
+    mov edi, [rip + fds + 4]
+    lea rsi, [rip + block]
+    mov edx, 1
+    mov eax, SYS_WRITE
+    syscall
+    cmp rax, EPIPE
+    jne fail
+
+    xor edi, edi
+    jmp exit
+
+child:
+    # close(fds[0]), and write the block BLOCKS times, which blocks
+    # until the parent has read enough
+    mov edi, [rip + fds]
+    mov eax, SYS_CLOSE
+    syscall
+    mov r12d, BLOCKS
+write_loop:
+    mov edi, [rip + fds + 4]
+    lea rsi, [rip + block]
+    mov edx, BLOCK
+    mov eax, SYS_WRITE
+    syscall
+    cmp rax, BLOCK
+    jne fail
+    dec r12d
+    jnz write_loop
+    xor edi, edi
+    jmp exit
+
+read_forever:
+    mov edi, [rip + fds]
+    lea rsi, [rip + buffer]
+    mov edx, 1
+    mov eax, SYS_READ
+    syscall
+    jmp fail
+
+broken_pipe:
+    # close(fds[0]), then write(fds[1], buffer, 1), which SIGPIPE kills
+    mov edi, [rip + fds]
+    mov eax, SYS_CLOSE
+    syscall
+    mov edi, [rip + fds + 4]
+    lea rsi, [rip + buffer]
+    mov edx, 1
+    mov eax, SYS_WRITE
+    syscall
+
+fail:
+    mov edi, 255
+exit:
+    mov eax, SYS_EXIT
+    syscall
+    ud2
+
+    .bss
+fds:
+    .zero 8
+status:
+    .zero 4
+    .balign 16
+block:
+    .zero BLOCK
+buffer:
+    .zero 1000
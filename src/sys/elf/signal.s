@@ -0,0 +1,149 @@
+# The test program for signals. Without arguments, exits with 0 if
+# its handlers are called as expected, or 255 if anything isn't. With
+# one argument it waits to be killed, and with two it writes to its
+# read-only code without a handler.
+#
+# as signal.s -o signal.o
+# ld -static -nostdlib -s -z max-page-size=4096 -Ttext-segment=0x100000000000 signal.o -o signal.elf
+
+    .intel_syntax noprefix
+
+    .set SYS_EXIT, 0
+    .set SYS_GETPID, 3
+    .set SYS_KILL, 10
+    .set SYS_SIGACTION, 11
+    .set SYS_SIGPROCMASK, 12
+    .set SYS_SIGRETURN, 13
+    .set SIG_IGN, 1
+    .set SIG_BLOCK, 0
+    .set SIG_UNBLOCK, 1
+    .set SIGUSR1, 10
+    .set SIGSEGV, 11
+    .set SIGUSR2, 12
+
+    .text
+    .global _start
+_start:
+    mov rax, [rsp]                  # argc
+    cmp rax, 2
+    je spin
+    cmp rax, 3
+    je fault
+
+    # sigaction(SIGUSR1, on_usr1, restore)
+    mov edi, SIGUSR1
+    lea rsi, [rip + on_usr1]
+    lea rdx, [rip + restore]
+    mov eax, SYS_SIGACTION
+    syscall
+    test rax, rax
+    jnz fail
+
+    # kill(getpid(), SIGUSR1), which calls the handler on the way back,
+    # and the registers are restored after it
+    mov eax, SYS_GETPID
+    syscall
+    mov r12, rax
+    mov rbx, 0x1234
+    mov r13, 0x5678
+    mov rdi, r12
+    mov esi, SIGUSR1
+    mov eax, SYS_KILL
+    syscall
+    test rax, rax
+    jnz fail
+    cmp rbx, 0x1234
+    jne fail
+    cmp r13, 0x5678
+    jne fail
+    cmp qword ptr [rip + usr1_count], 1
+    jne fail
+
+    # sigprocmask(SIG_BLOCK, 1 << SIGUSR1) keeps it pending until it's
+    # unblocked
+    mov edi, SIG_BLOCK
+    mov esi, 1 << SIGUSR1
+    mov eax, SYS_SIGPROCMASK
+    syscall
+    test rax, rax
+    jnz fail
+    mov rdi, r12
+    mov esi, SIGUSR1
+    mov eax, SYS_KILL
+    syscall
+    cmp qword ptr [rip + usr1_count], 1
+    jne fail
+    mov edi, SIG_UNBLOCK
+    mov esi, 1 << SIGUSR1
+    mov eax, SYS_SIGPROCMASK
+    syscall
+    cmp rax, 1 << SIGUSR1
+    jne fail
+    cmp qword ptr [rip + usr1_count], 2
+    jne fail
+
+    # sigaction(SIGUSR2, SIG_IGN), after which it does nothing
+    mov edi, SIGUSR2
+    mov esi, SIG_IGN
+    xor edx, edx
+    mov eax, SYS_SIGACTION
+    syscall
+    mov rdi, r12
+    mov esi, SIGUSR2
+    mov eax, SYS_KILL
+    syscall
+    test rax, rax
+    jnz fail
+
+    # sigaction(SIGSEGV, on_segv, restore), and fault
+    mov edi, SIGSEGV
+    lea rsi, [rip + on_segv]
+    lea rdx, [rip + restore]
+    mov eax, SYS_SIGACTION
+    syscall
+    test rax, rax
+    jnz fail
+    mov byte ptr [rip + _start], 0
+    jmp fail
+
+fault:
+    mov byte ptr [rip + _start], 0
+    jmp fail
+
+spin:
+    jmp spin
+
+# Called with the stack aligned as for a function call, clobbering
+# what the code it interrupted has to find restored.
+on_usr1:
+    cmp edi, SIGUSR1
+    jne fail
+    lea rax, [rsp + 8]
+    test al, 15
+    jnz fail
+    inc qword ptr [rip + usr1_count]
+    xor ebx, ebx
+    xor r13d, r13d
+    ret
+
+on_segv:
+    cmp edi, SIGSEGV
+    jne fail
+    xor edi, edi
+    jmp exit
+
+restore:
+    mov eax, SYS_SIGRETURN
+    syscall
+    ud2
+
+fail:
+    mov edi, 255
+exit:
+    mov eax, SYS_EXIT
+    syscall
+    ud2
+
+    .data
+usr1_count:
+    .quad 0
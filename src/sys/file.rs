@@ -0,0 +1,192 @@
+//! Open files, as seen by user code through file descriptors.
+
+use alloc::{borrow::Cow, string::String, sync::Arc, vec::Vec};
+
+use crate::sys::{sync::Mutex, syscall::Error};
+
+/// The most files a process can have open at a time.
+pub const MAX_FILES: usize = 64;
+
+/// Something that can be read from or written to through a file
+/// descriptor. Reading and writing fail with
+/// [`Error::BadFileDescriptor`] unless implemented.
+pub trait File: Send + Sync {
+    /// Read into `buf`, returning the number of bytes read.
+    ///
+    /// # Errors
+    ///
+    /// Depends on the kind of file.
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::BadFileDescriptor)
+    }
+
+    /// Write (some of) `buf`, returning the number of bytes written.
+    ///
+    /// # Errors
+    ///
+    /// Depends on the kind of file.
+    fn write(&self, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::BadFileDescriptor)
+    }
+
+    /// Read into `buf` from `offset` bytes into the file, returning the
+    /// number of bytes read, which is 0 past the end. Files that support
+    /// it can be mapped into memory.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::NoDevice`] unless implemented.
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::NoDevice)
+    }
+}
+
+/// The screen, for standard output and standard error.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Console;
+
+impl File for Console {
+    fn write(&self, buf: &[u8]) -> Result<usize, Error> {
+        print!("{}", String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+}
+
+/// A read-only file with its contents in memory, either owned or
+/// borrowed, e.g. from the [`crate::sys::initrd`].
+#[allow(clippy::module_name_repetitions)]
+pub struct MemoryFile {
+    data: Cow<'static, [u8]>,
+    /// Where `read` continues.
+    position: Mutex<usize>,
+}
+
+impl MemoryFile {
+    #[must_use]
+    pub fn new(data: impl Into<Cow<'static, [u8]>>) -> Self {
+        Self {
+            data: data.into(),
+            position: Mutex::new(0),
+        }
+    }
+}
+
+impl File for MemoryFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut position = self.position.lock();
+        let read = self.read_at(*position as u64, buf)?;
+        *position += read;
+        Ok(read)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let rest = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.data.get(offset..))
+            .unwrap_or_default();
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        Ok(len)
+    }
+}
+
+/// The files of a process, indexed by file descriptor.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Clone, Default)]
+pub struct FileTable {
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl FileTable {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { files: Vec::new() }
+    }
+
+    /// A table with the [`Console`] as standard input, output and error.
+    #[must_use]
+    pub fn with_console() -> Self {
+        let console: Arc<dyn File> = Arc::new(Console);
+        Self {
+            files: alloc::vec![Some(console.clone()), Some(console.clone()), Some(console)],
+        }
+    }
+
+    /// The file open as `fd`.
+    ///
+    /// # Errors
+    ///
+    /// If there is none, [`Error::BadFileDescriptor`] is returned.
+    pub fn get(&self, fd: usize) -> Result<Arc<dyn File>, Error> {
+        self.files
+            .get(fd)
+            .and_then(Clone::clone)
+            .ok_or(Error::BadFileDescriptor)
+    }
+
+    /// Open `file` as the lowest free file descriptor, and return it.
+    ///
+    /// # Errors
+    ///
+    /// If [`MAX_FILES`] are open already, [`Error::TooManyFiles`] is
+    /// returned.
+    pub fn insert(&mut self, file: Arc<dyn File>) -> Result<usize, Error> {
+        if let Some(fd) = self.files.iter().position(Option::is_none) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() == MAX_FILES {
+            return Err(Error::TooManyFiles);
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    /// Close `fd`.
+    ///
+    /// # Errors
+    ///
+    /// If it isn't open, [`Error::BadFileDescriptor`] is returned.
+    pub fn close(&mut self, fd: usize) -> Result<(), Error> {
+        self.files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .map(drop)
+            .ok_or(Error::BadFileDescriptor)
+    }
+
+    /// Close all files.
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+}
+
+#[test_case]
+fn descriptor_table() {
+    let mut files = FileTable::with_console();
+    assert!(files.get(1).is_ok());
+    assert!(files.get(3).is_err());
+
+    files.close(0).unwrap();
+    assert_eq!(files.close(0), Err(Error::BadFileDescriptor));
+    assert_eq!(files.insert(Arc::new(Console)), Ok(0));
+    assert_eq!(files.insert(Arc::new(Console)), Ok(3));
+
+    while files.files.len() < MAX_FILES {
+        files.insert(Arc::new(Console)).unwrap();
+    }
+    assert_eq!(files.insert(Arc::new(Console)), Err(Error::TooManyFiles));
+}
+
+#[test_case]
+fn read_from_memory() {
+    let file = MemoryFile::new(b"hello".to_vec());
+    let mut buf = [0; 3];
+    assert_eq!(file.read(&mut buf), Ok(3));
+    assert_eq!(file.read(&mut buf), Ok(2));
+    assert_eq!(&buf[..2], b"lo");
+    assert_eq!(file.read(&mut buf), Ok(0));
+    assert_eq!(file.read_at(1, &mut buf), Ok(3));
+    assert_eq!(&buf, b"ell");
+    assert_eq!(Console.read_at(0, &mut buf), Err(Error::NoDevice));
+}
@@ -1,52 +1,134 @@
+use alloc::{boxed::Box, vec};
+use core::{
+    ptr::{self, addr_of, addr_of_mut},
+    sync::atomic::{AtomicPtr, Ordering},
+};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::sys::percpu;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-
-            stack_start + STACK_SIZE
-        };
-        tss
-    };
-}
+/// The TSS of the BSP. Its kernel stack pointer changes whenever a
+/// thread running in user mode is switched to.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
+        static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+        unsafe {
+            let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
+            let tss = &mut *addr_of_mut!(TSS);
+            init_tss(tss, stack_start + DOUBLE_FAULT_STACK_SIZE);
+            new_gdt(tss)
+        }
     };
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+percpu! {
+    /// The TSS of the CPU.
+    static CPU_TSS: AtomicPtr<TaskStateSegment> = AtomicPtr::new(ptr::null_mut());
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+/// The segments shared by all CPUs. The order of the kernel and user
+/// segments is dictated by `syscall`/`sysret`.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+fn init_tss(tss: &mut TaskStateSegment, double_fault_stack_end: VirtAddr) {
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_end;
+}
+
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            kernel_code,
+            kernel_data,
+            user_data,
+            user_code,
+            tss,
+        },
+    )
+}
+
+/// # Safety
+///
+/// `tss` must be the TSS of `gdt`, and must not be used by another CPU.
+unsafe fn load(gdt: &'static (GlobalDescriptorTable, Selectors), tss: *mut TaskStateSegment) {
+    use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(gdt.1.kernel_code);
+        SS::set_reg(gdt.1.kernel_data);
+        DS::set_reg(gdt.1.kernel_data);
+        ES::set_reg(gdt.1.kernel_data);
+        load_tss(gdt.1.tss);
     }
+
+    CPU_TSS.with(|cpu_tss| cpu_tss.store(tss, Ordering::Relaxed));
+}
+
+/// The segment selectors, which are the same on every CPU.
+#[must_use]
+pub fn selectors() -> Selectors {
+    GDT.1
+}
+
+/// Where the CPU stores the kernel stack pointer to switch to when an
+/// interrupt arrives in user mode.
+#[must_use]
+pub fn kernel_stack_slot() -> *mut u64 {
+    CPU_TSS.with(|tss| {
+        let tss = tss.load(Ordering::Relaxed);
+        unsafe { addr_of_mut!((*tss).privilege_stack_table).cast::<u64>() }
+    })
+}
+
+/// Set the kernel stack pointer of the current CPU, used when an
+/// interrupt or system call arrives in user mode.
+pub fn set_kernel_stack(rsp: VirtAddr) {
+    unsafe { kernel_stack_slot().write_unaligned(rsp.as_u64()) };
+    percpu::set_syscall_stack(rsp);
+}
+
+/// Load the GDT and TSS of the BSP, and set up its per-CPU data.
+pub fn init() {
+    percpu::init(0);
+    unsafe { load(&GDT, addr_of_mut!(TSS)) };
+}
+
+/// Give an application processor its own GDT and TSS, since a TSS
+/// can't be shared between CPUs, and set up its per-CPU data.
+/// Requires the heap.
+pub fn init_ap(cpu: usize) {
+    percpu::init(cpu);
+
+    let stack = vec![0_u8; DOUBLE_FAULT_STACK_SIZE].leak();
+    let stack_end = VirtAddr::from_ptr(stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE;
+
+    let tss = Box::leak(Box::new(TaskStateSegment::new()));
+    init_tss(tss, stack_end.align_down(16_u64));
+    let tss: *mut TaskStateSegment = tss;
+    unsafe { load(Box::leak(Box::new(new_gdt(&*tss))), tss) };
 }
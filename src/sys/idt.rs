@@ -1,27 +1,54 @@
 use crate::sys;
+use alloc::boxed::Box;
 use lazy_static::lazy_static;
 use sys::pic::Irq;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use sys::{signal, trap::TrapFrame};
+use x86_64::{
+    instructions::interrupts,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+};
 
 use crate::hlt_loop;
 
 lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(handle_breakpoint);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(handle_double_fault)
-                .set_stack_index(sys::gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt.page_fault.set_handler_fn(handle_page_fault);
+    static ref IDT: InterruptDescriptorTable = new_idt();
+}
 
-        idt[Irq::Timer.as_usize()].set_handler_fn(sys::time::handle_timer_interrupt);
-        idt[Irq::Keyboard.as_usize()].set_handler_fn(sys::keyboard::handle_interrupt);
-        idt[Irq::Rtc.as_usize()].set_handler_fn(sys::clock::handle_rtc_interrupt);
+fn new_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+    idt.breakpoint.set_handler_fn(handle_breakpoint);
+    // these reload the GS base when entered from user mode, and the
+    // fault and timer entries also save all registers, for signal
+    // handlers (see `sys::trap`)
+    unsafe {
+        idt.double_fault
+            .set_handler_addr(sys::trap::double_fault_entry())
+            .set_stack_index(sys::gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.page_fault
+            .set_handler_addr(sys::trap::page_fault_entry());
+        idt.divide_error
+            .set_handler_addr(sys::trap::divide_error_entry());
+        idt.bound_range_exceeded
+            .set_handler_addr(sys::trap::bound_range_entry());
+        idt.invalid_opcode
+            .set_handler_addr(sys::trap::invalid_opcode_entry());
+        idt.device_not_available
+            .set_handler_addr(sys::trap::device_not_available_entry());
+        idt.stack_segment_fault
+            .set_handler_addr(sys::trap::stack_segment_entry());
+        idt.general_protection_fault
+            .set_handler_addr(sys::trap::general_protection_entry());
+        idt.alignment_check
+            .set_handler_addr(sys::trap::alignment_check_entry());
+        idt[Irq::Timer.as_usize()].set_handler_addr(sys::trap::timer_entry());
+        idt[Irq::Keyboard.as_usize()].set_handler_addr(sys::trap::keyboard_entry());
+        idt[Irq::Rtc.as_usize()].set_handler_addr(sys::trap::rtc_entry());
+        idt[Irq::Mouse.as_usize()].set_handler_addr(sys::trap::mouse_entry());
+        idt[usize::from(sys::ipi::CALL_VECTOR)].set_handler_addr(sys::trap::call_entry());
+        idt[usize::from(sys::apic::SPURIOUS_VECTOR)].set_handler_addr(sys::trap::spurious_entry());
+    }
 
-        idt
-    };
+    idt
 }
 
 /// Initialize the Interrupt Descriptor Table (IDT).
@@ -29,31 +56,90 @@ pub fn init() {
     IDT.load();
 }
 
+/// Give an application processor its own IDT. Requires the heap.
+pub fn init_ap() {
+    Box::leak(Box::new(new_idt())).load();
+}
+
 extern "x86-interrupt" fn handle_breakpoint(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn handle_double_fault(
+pub(crate) extern "x86-interrupt" fn handle_double_fault(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame)
 }
 
-extern "x86-interrupt" fn handle_page_fault(
-    stack_fame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
+/// Called from `sys::trap::page_fault_entry`.
+#[no_mangle]
+extern "C" fn aaos_handle_page_fault(frame: &mut TrapFrame) {
     use x86_64::registers::control::Cr2;
 
+    // Faults in user memory, from user mode or while a system call
+    // accesses it, are handled by the process, which can sleep. Other
+    // faults in user mode send it SIGSEGV.
+    let addr = Cr2::read();
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    if frame.interrupts_enabled() {
+        interrupts::enable();
+        let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+        let handled = (sys::memory::USER_START..sys::memory::USER_END).contains(&addr.as_u64())
+            && sys::process::handle_page_fault(addr, write);
+        if frame.is_user() {
+            if !handled {
+                signal::force(signal::SIGSEGV);
+            }
+            signal::deliver(frame);
+        }
+        interrupts::disable();
+        if handled || frame.is_user() {
+            return;
+        }
+    }
+
     println!("EXCEPTION: PAGE FAULT");
-    println!("accessed address: {:?}", Cr2::read());
+    println!("accessed address: {:?}", addr);
     println!("error code: {:?}", error_code);
-    println!("{:#?}", stack_fame);
+    println!("{:#?}", frame);
 
     hlt_loop();
 }
 
+/// Send `sig` to the process whose user code caused `exception`, and
+/// panic if kernel code did.
+fn handle_fault(frame: &mut TrapFrame, exception: &str, sig: u32) {
+    assert!(frame.is_user(), "EXCEPTION: {exception}\n{frame:#?}");
+
+    interrupts::enable();
+    signal::force(sig);
+    signal::deliver(frame);
+    interrupts::disable();
+}
+
+macro_rules! fault_handlers {
+    ($($handler:ident => $exception:literal, $sig:expr;)*) => {
+        $(
+            /// Called from the entry point of the same name in `sys::trap`.
+            #[no_mangle]
+            extern "C" fn $handler(frame: &mut TrapFrame) {
+                handle_fault(frame, $exception, $sig);
+            }
+        )*
+    };
+}
+
+fault_handlers! {
+    aaos_handle_divide_error => "DIVIDE ERROR", signal::SIGFPE;
+    aaos_handle_bound_range => "BOUND RANGE EXCEEDED", signal::SIGSEGV;
+    aaos_handle_invalid_opcode => "INVALID OPCODE", signal::SIGILL;
+    aaos_handle_device_not_available => "DEVICE NOT AVAILABLE", signal::SIGFPE;
+    aaos_handle_stack_segment => "STACK SEGMENT FAULT", signal::SIGSEGV;
+    aaos_handle_general_protection => "GENERAL PROTECTION FAULT", signal::SIGSEGV;
+    aaos_handle_alignment_check => "ALIGNMENT CHECK", signal::SIGSEGV;
+}
+
 #[test_case]
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
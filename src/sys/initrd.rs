@@ -0,0 +1,34 @@
+//! Files packed into the kernel image, until there is a file system.
+//!
+//! The programs are built from `user` (see `user/.cargo/config.toml`)
+//! and copied here by `user/build-initrd.sh`, which CI runs with
+//! `--check` to make sure they match their sources.
+
+static FILES: &[(&str, &[u8])] = &[
+    ("cat", include_bytes!("./initrd/cat")),
+    ("echo", include_bytes!("./initrd/echo")),
+    ("ls", include_bytes!("./initrd/ls")),
+    ("motd", include_bytes!("./initrd/motd")),
+];
+
+/// The names and contents of the files, sorted by name.
+pub fn files() -> impl Iterator<Item = (&'static str, &'static [u8])> {
+    FILES.iter().copied()
+}
+
+/// The contents of the file `name`.
+#[must_use]
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    FILES
+        .iter()
+        .find(|(file, _)| *file == name)
+        .map(|(_, data)| *data)
+}
+
+#[test_case]
+fn find_files() {
+    assert!(FILES.windows(2).all(|files| files[0].0 < files[1].0));
+    assert_eq!(find("motd"), Some(&b"Welcome to aaos!\n"[..]));
+    assert!(find("echo").map_or(false, |data| data.starts_with(b"\x7fELF")));
+    assert_eq!(find("missing"), None);
+}
@@ -0,0 +1,198 @@
+//! Inter-processor interrupts: running functions on other CPUs, e.g.
+//! to flush their TLBs after a mapping has changed.
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::{
+    instructions::{interrupts, tlb},
+    structures::{
+        idt::InterruptStackFrame,
+        paging::{page::PageRange, Size4KiB},
+    },
+};
+
+use crate::sys::{
+    apic,
+    smp::{self, MAX_CPUS},
+    sync::IrqSafeMutex,
+};
+
+/// Vector of the interrupt telling a CPU to run the calls queued for it.
+pub const CALL_VECTOR: u8 = 0xf0;
+
+/// Above this many pages, flushing the entire TLB is cheaper.
+const MAX_SHOOTDOWN_PAGES: usize = 32;
+
+struct Call {
+    f: Box<dyn Fn() + Send + Sync>,
+    /// How many CPUs have yet to run `f`.
+    pending: AtomicUsize,
+}
+
+/// The calls queued for each CPU.
+#[allow(clippy::declare_interior_mutable_const)]
+static QUEUES: [IrqSafeMutex<VecDeque<Arc<Call>>>; MAX_CPUS] = {
+    const EMPTY: IrqSafeMutex<VecDeque<Arc<Call>>> = IrqSafeMutex::new(VecDeque::new());
+    [EMPTY; MAX_CPUS]
+};
+
+/// Run the calls queued for the current CPU.
+fn run_queued_calls() {
+    let queue = &QUEUES[smp::current_cpu()];
+    while let Some(call) = queue.lock().pop_front() {
+        (call.f)();
+        call.pending.fetch_sub(1, Ordering::Release);
+    }
+}
+
+pub(crate) extern "x86-interrupt" fn handle_call_interrupt(_stack_frame: InterruptStackFrame) {
+    run_queued_calls();
+    apic::notify_end_of_interrupt();
+}
+
+/// Run `f` on each of `cpus` and wait until all of them are done. The
+/// current CPU runs it directly, with interrupts disabled, like the
+/// other CPUs do in their interrupt handler.
+fn call(mut cpus: impl Iterator<Item = usize> + Clone, f: impl Fn() + Send + Sync + 'static) {
+    let current = smp::current_cpu();
+    let targets = cpus
+        .clone()
+        .filter(|&cpu| cpu != current && smp::apic_id(cpu).is_some());
+
+    let call = Arc::new(Call {
+        f: Box::new(f),
+        pending: AtomicUsize::new(targets.clone().count()),
+    });
+    for cpu in targets {
+        QUEUES[cpu].lock().push_back(call.clone());
+        if let Some(apic_id) = smp::apic_id(cpu) {
+            apic::send_fixed(apic_id, CALL_VECTOR);
+        }
+    }
+
+    if cpus.any(|cpu| cpu == current) {
+        interrupts::without_interrupts(|| (call.f)());
+    }
+
+    while call.pending.load(Ordering::Acquire) != 0 {
+        // if interrupts are disabled, another CPU waiting for this
+        // one would never be done
+        if !interrupts::are_enabled() {
+            run_queued_calls();
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Run `f` on `cpu`, and wait until it's done. Does nothing if
+/// `cpu` isn't online.
+pub fn call_on(cpu: usize, f: impl Fn() + Send + Sync + 'static) {
+    call(core::iter::once(cpu), f);
+}
+
+/// Run `f` on all online CPUs, including the current one, and wait
+/// until all of them are done.
+pub fn call_on_all(f: impl Fn() + Send + Sync + 'static) {
+    call(0..smp::cpu_count(), f);
+}
+
+/// Run `f` on all online CPUs except the current one, and wait until
+/// all of them are done.
+pub fn call_on_others(f: impl Fn() + Send + Sync + 'static) {
+    let current = smp::current_cpu();
+    call((0..smp::cpu_count()).filter(move |&cpu| cpu != current), f);
+}
+
+/// Flush `pages` from the TLBs of all other CPUs. Mappings must only
+/// be changed (or the frames they mapped be reused) after they have
+/// been flushed everywhere, which [`crate::sys::memory::unmap`] and
+/// [`crate::sys::memory::protect`] take care of.
+///
+/// Must not be called while holding an [`IrqSafeMutex`] that other
+/// CPUs might be spinning on, since they can't handle the IPI then.
+pub fn shootdown(pages: PageRange<Size4KiB>) {
+    if smp::cpu_count() == 1 {
+        return;
+    }
+
+    let count = pages.count();
+    call_on_others(move || {
+        if count > MAX_SHOOTDOWN_PAGES {
+            tlb::flush_all();
+        } else {
+            for page in pages {
+                tlb::flush(page.start_address());
+            }
+        }
+    });
+}
+
+#[test_case]
+fn call_on_every_cpu() {
+    static CALLS: [AtomicUsize; MAX_CPUS] = {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicUsize = AtomicUsize::new(0);
+        [ZERO; MAX_CPUS]
+    };
+
+    call_on_all(|| {
+        CALLS[smp::current_cpu()].fetch_add(1, Ordering::Relaxed);
+    });
+    call_on(smp::cpu_count() - 1, || {
+        CALLS[smp::current_cpu()].fetch_add(1, Ordering::Relaxed);
+    });
+
+    let calls: usize = CALLS.iter().map(|c| c.load(Ordering::Relaxed)).sum();
+    assert_eq!(calls, smp::cpu_count() + 1);
+    assert_eq!(CALLS[smp::cpu_count() - 1].load(Ordering::Relaxed), 2);
+}
+
+#[test_case]
+fn tlb_shootdown() {
+    use crate::sys::memory;
+    use core::sync::atomic::AtomicU64;
+    use x86_64::{
+        structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags},
+        VirtAddr,
+    };
+
+    static SEEN: [AtomicU64; MAX_CPUS] = {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        [ZERO; MAX_CPUS]
+    };
+
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x5555_0000_0000));
+    let pages = Page::range(page, page + 1);
+    let ptr = page.start_address().as_ptr::<u64>() as usize;
+    let read_everywhere = |value: u64| {
+        call_on_all(move || {
+            let value = unsafe { (ptr as *const u64).read_volatile() };
+            SEEN[smp::current_cpu()].store(value, Ordering::Relaxed);
+        });
+        SEEN[..smp::cpu_count()]
+            .iter()
+            .all(|seen| seen.load(Ordering::Relaxed) == value)
+    };
+
+    for value in [1, 2] {
+        memory::with_mapper(|mapper, frame_allocator| {
+            let frame = frame_allocator.allocate_frame().unwrap();
+            unsafe {
+                memory::phys_to_virt(frame.start_address())
+                    .as_mut_ptr::<u64>()
+                    .write(value);
+                let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+                mapper
+                    .map_to(page, frame, flags, frame_allocator)
+                    .unwrap()
+                    .flush();
+            }
+        });
+
+        // every CPU must see the new frame, not a stale TLB entry
+        assert!(read_everywhere(value));
+
+        memory::unmap(pages).unwrap();
+    }
+}
@@ -1,33 +1,565 @@
 use crate::sys;
-use lazy_static::lazy_static;
-use sys::pic::{Irq, PICS};
+use core::{
+    ops::BitOr,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker, StreamExt};
+use pc_keyboard::{layouts, HandleControl, Keyboard, KeyboardLayout, ScancodeSet1};
+pub use pc_keyboard::{DecodedKey, KeyCode, KeyEvent, KeyState};
+use spin::Once;
+use sys::{
+    pic::{Irq, PICS},
+    ps2::{self, Leds},
+};
 use x86_64::structures::idt::InterruptStackFrame;
 
+mod nordic;
+
+const SCANCODE_QUEUE_CAPACITY: usize = 128;
+
+static SCANCODE_QUEUE: Once<ArrayQueue<u8>> = Once::new();
+static SCANCODE_WAKER: AtomicWaker = AtomicWaker::new();
+static DROPPED_SCANCODES: AtomicUsize = AtomicUsize::new(0);
+static STREAM_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Number of scancodes dropped because they arrived while the
+/// queue was full (or before it was initialized).
+pub fn dropped_scancodes() -> usize {
+    DROPPED_SCANCODES.load(Ordering::Relaxed)
+}
+
+/// Queue a scancode for [`ScancodeStream`]. Called from the
+/// interrupt handler, so it must neither block nor allocate.
+pub(crate) fn add_scancode(scancode: u8) {
+    let queued = SCANCODE_QUEUE
+        .get()
+        .is_some_and(|queue| queue.push(scancode).is_ok());
+
+    if queued {
+        SCANCODE_WAKER.wake();
+    } else {
+        DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 pub(crate) extern "x86-interrupt" fn handle_interrupt(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
     use x86_64::instructions::port::Port;
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-            Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    if !ps2::handle_response(scancode) {
+        add_scancode(scancode);
+    }
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(Irq::Keyboard.as_u8());
+    }
+}
+
+/// The raw scancodes received from the keyboard.
+///
+/// There can only be one stream at a time, since every scancode is
+/// delivered only once.
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    /// # Panics
+    ///
+    /// Panics if another [`ScancodeStream`] exists.
+    #[must_use]
+    pub fn new() -> Self {
+        assert!(
+            !STREAM_TAKEN.swap(true, Ordering::Acquire),
+            "only one ScancodeStream may exist at a time"
         );
+        Self { _private: () }
     }
+}
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-                DecodedKey::Unicode(character) => print!("{}", character),
+impl Drop for ScancodeStream {
+    fn drop(&mut self) {
+        STREAM_TAKEN.store(false, Ordering::Release);
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = SCANCODE_QUEUE.get().expect("keyboard not initialized");
+
+        // fast path
+        if let Some(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        SCANCODE_WAKER.register(cx.waker());
+        queue.pop().map_or(Poll::Pending, |scancode| {
+            SCANCODE_WAKER.take();
+            Poll::Ready(Some(scancode))
+        })
+    }
+}
+
+/// Key presses and releases, decoded from [`ScancodeStream`].
+pub struct KeyEventStream {
+    scancodes: ScancodeStream,
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+}
+
+impl KeyEventStream {
+    /// # Panics
+    ///
+    /// Panics if a [`ScancodeStream`] already exists.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            scancodes: ScancodeStream::new(),
+            // only used to assemble events, not to decode them
+            keyboard: Keyboard::new(
+                ScancodeSet1::new(),
+                layouts::Us104Key,
+                HandleControl::Ignore,
+            ),
+        }
+    }
+}
+
+impl Default for KeyEventStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for KeyEventStream {
+    type Item = KeyEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<KeyEvent>> {
+        // a key event can span several scancodes
+        while let Poll::Ready(scancode) = self.scancodes.poll_next_unpin(cx) {
+            let scancode = scancode.expect("scancode stream ended");
+            if let Ok(Some(event)) = self.keyboard.add_byte(scancode) {
+                return Poll::Ready(Some(event));
             }
         }
+
+        Poll::Pending
+    }
+}
+
+/// The keyboard layouts [`KeyStream`] can decode keys with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+    German,
+    French,
+    Dvorak,
+    Colemak,
+    Japanese,
+    /// Finnish and Swedish.
+    Swedish,
+    Norwegian,
+}
+
+impl Layout {
+    pub const ALL: [Self; 9] = [
+        Self::Us,
+        Self::Uk,
+        Self::German,
+        Self::French,
+        Self::Dvorak,
+        Self::Colemak,
+        Self::Japanese,
+        Self::Swedish,
+        Self::Norwegian,
+    ];
+
+    /// A short name, like `us` or `de`.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Us => "us",
+            Self::Uk => "uk",
+            Self::German => "de",
+            Self::French => "fr",
+            Self::Dvorak => "dvorak",
+            Self::Colemak => "colemak",
+            Self::Japanese => "jp",
+            Self::Swedish => "se",
+            Self::Norwegian => "no",
+        }
     }
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(Irq::Keyboard.as_u8());
+    /// The layout called `name` (see [`Layout::name`]).
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|layout| layout.name() == name)
     }
 }
+
+impl KeyboardLayout for Layout {
+    fn map_keycode(
+        &self,
+        keycode: KeyCode,
+        modifiers: &pc_keyboard::Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        let layout: &dyn KeyboardLayout = match self {
+            Self::Us => &layouts::Us104Key,
+            Self::Uk => &layouts::Uk105Key,
+            Self::German => &layouts::De105Key,
+            Self::French => &layouts::Azerty,
+            Self::Dvorak => &layouts::Dvorak104Key,
+            Self::Colemak => &layouts::Colemak,
+            Self::Japanese => &layouts::Jis109Key,
+            Self::Swedish => &nordic::FiSe105Key,
+            Self::Norwegian => &nordic::No105Key,
+        };
+        layout.map_keycode(keycode, modifiers, handle_ctrl)
+    }
+}
+
+static LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us as u8);
+
+/// The layout keys are decoded with, initially [`Layout::Us`].
+pub fn layout() -> Layout {
+    Layout::ALL[usize::from(LAYOUT.load(Ordering::Relaxed))]
+}
+
+/// Decode the following keys with `layout`.
+pub fn set_layout(layout: Layout) {
+    LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+/// The modifier keys held, and the lock keys turned on, when a key was
+/// pressed or released.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    bits: u8,
+}
+
+impl Modifiers {
+    pub const NONE: Self = Self { bits: 0 };
+    pub const SHIFT: Self = Self { bits: 1 };
+    pub const CTRL: Self = Self { bits: 1 << 1 };
+    /// The left Alt key. The right one is [`Modifiers::ALT_GR`], which
+    /// the layout uses to select more characters.
+    pub const ALT: Self = Self { bits: 1 << 2 };
+    pub const ALT_GR: Self = Self { bits: 1 << 3 };
+    pub const CAPS_LOCK: Self = Self { bits: 1 << 4 };
+    pub const NUM_LOCK: Self = Self { bits: 1 << 5 };
+    pub const SCROLL_LOCK: Self = Self { bits: 1 << 6 };
+
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    #[must_use]
+    pub const fn union(self, other: Self) -> Self {
+        Self {
+            bits: self.bits | other.bits,
+        }
+    }
+
+    fn set(&mut self, other: Self, on: bool) {
+        if on {
+            self.bits |= other.bits;
+        } else {
+            self.bits &= !other.bits;
+        }
+    }
+
+    /// The LEDs of the lock keys that are on.
+    fn leds(self) -> Leds {
+        [
+            (Self::CAPS_LOCK, Leds::CAPS_LOCK),
+            (Self::NUM_LOCK, Leds::NUM_LOCK),
+            (Self::SCROLL_LOCK, Leds::SCROLL_LOCK),
+        ]
+        .into_iter()
+        .filter(|&(lock, _)| self.contains(lock))
+        .fold(Leds::NONE, |leds, (_, led)| leds | led)
+    }
+
+    /// The modifier `code` changes, and whether it's a lock key, which
+    /// is toggled by pressing it.
+    const fn of(code: KeyCode) -> Option<(Self, bool)> {
+        match code {
+            KeyCode::LShift | KeyCode::RShift => Some((Self::SHIFT, false)),
+            KeyCode::LControl | KeyCode::RControl => Some((Self::CTRL, false)),
+            KeyCode::LAlt => Some((Self::ALT, false)),
+            KeyCode::RAltGr => Some((Self::ALT_GR, false)),
+            KeyCode::CapsLock => Some((Self::CAPS_LOCK, true)),
+            KeyCode::NumpadLock => Some((Self::NUM_LOCK, true)),
+            KeyCode::ScrollLock => Some((Self::SCROLL_LOCK, true)),
+            _ => None,
+        }
+    }
+}
+
+impl BitOr for Modifiers {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
+/// A key press or release, decoded with the current [`layout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// Including the change made by this key, if it's a modifier.
+    pub modifiers: Modifiers,
+    /// The character the key types, or the key itself if it doesn't
+    /// type one, like the arrow keys. `None` for releases and modifiers.
+    pub decoded: Option<DecodedKey>,
+}
+
+impl Key {
+    #[must_use]
+    pub const fn is_press(&self) -> bool {
+        !matches!(self.state, KeyState::Up)
+    }
+
+    /// The character typed, without Ctrl or Alt held (Ctrl+C decodes as
+    /// `c`, with [`Modifiers::CTRL`]).
+    #[must_use]
+    pub const fn char(&self) -> Option<char> {
+        match self.decoded {
+            Some(DecodedKey::Unicode(character)) => Some(character),
+            _ => None,
+        }
+    }
+
+    /// Whether Ctrl or Alt is held, so that the key is a combination
+    /// rather than typed.
+    #[must_use]
+    pub const fn is_combination(&self) -> bool {
+        self.modifiers.contains(Modifiers::CTRL) || self.modifiers.contains(Modifiers::ALT)
+    }
+}
+
+/// Key presses and releases, decoded from [`KeyEventStream`] with the
+/// current [`layout`].
+pub struct KeyStream {
+    events: KeyEventStream,
+    layout: Layout,
+    decoder: Keyboard<Layout, ScancodeSet1>,
+    modifiers: Modifiers,
+}
+
+impl KeyStream {
+    /// # Panics
+    ///
+    /// Panics if a [`ScancodeStream`] already exists.
+    #[must_use]
+    pub fn new() -> Self {
+        let layout = layout();
+        Self {
+            events: KeyEventStream::new(),
+            layout,
+            decoder: Self::decoder(layout),
+            modifiers: Modifiers::NUM_LOCK,
+        }
+    }
+
+    const fn decoder(layout: Layout) -> Keyboard<Layout, ScancodeSet1> {
+        Keyboard::new(ScancodeSet1::new(), layout, HandleControl::Ignore)
+    }
+
+    /// The modifiers held, and lock keys turned on, after the last key.
+    #[must_use]
+    pub const fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    fn decode(&mut self, event: KeyEvent) -> Key {
+        let current = layout();
+        if current != self.layout {
+            // a new decoder starts with the default lock keys
+            self.layout = current;
+            self.decoder = Self::decoder(current);
+            for (lock, code, default) in [
+                (Modifiers::CAPS_LOCK, KeyCode::CapsLock, false),
+                (Modifiers::NUM_LOCK, KeyCode::NumpadLock, true),
+            ] {
+                if self.modifiers.contains(lock) != default {
+                    self.decoder
+                        .process_keyevent(KeyEvent::new(code, KeyState::Down));
+                    self.decoder
+                        .process_keyevent(KeyEvent::new(code, KeyState::Up));
+                }
+            }
+        }
+
+        let (code, state) = (event.code, event.state);
+        let decoded = self.decoder.process_keyevent(event);
+        let is_press = !matches!(state, KeyState::Up);
+        let modifier = Modifiers::of(code);
+        match modifier {
+            Some((lock, true)) if is_press => {
+                let on = !self.modifiers.contains(lock);
+                self.modifiers.set(lock, on);
+                // the keyboard doesn't turn them on itself
+                let _ = ps2::set_leds(self.modifiers.leds());
+            }
+            Some((modifier, false)) => self.modifiers.set(modifier, is_press),
+            Some((_, true)) | None => {}
+        }
+
+        Key {
+            code,
+            state,
+            modifiers: self.modifiers,
+            decoded: decoded.filter(|_| is_press && modifier.is_none()),
+        }
+    }
+}
+
+impl Default for KeyStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = Key;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Key>> {
+        match self.events.poll_next_unpin(cx) {
+            Poll::Ready(Some(event)) => Poll::Ready(Some(self.decode(event))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Print typed characters to the screen. Ctrl combinations are shown
+/// like `^C`, and other keys that don't type anything are ignored.
+///
+/// # Panics
+///
+/// Panics if a [`ScancodeStream`] already exists.
+pub async fn print_keypresses() {
+    let mut keys = KeyStream::new();
+
+    while let Some(key) = keys.next().await {
+        let Some(character) = key.char() else {
+            continue;
+        };
+        if key.modifiers.contains(Modifiers::CTRL) && character.is_ascii_alphabetic() {
+            print!("^{}", character.to_ascii_uppercase());
+        } else if !key.is_combination()
+            && (!character.is_control() || matches!(character, '\n' | '\t' | '\x08'))
+        {
+            print!("{}", character);
+        }
+    }
+}
+
+pub fn init() {
+    SCANCODE_QUEUE.call_once(|| ArrayQueue::new(SCANCODE_QUEUE_CAPACITY));
+}
+
+#[test_case]
+fn key_event_stream() {
+    use sys::task::executor::Executor;
+
+    let mut executor = Executor::new();
+    executor.spawn(async {
+        let mut events = KeyEventStream::new();
+
+        let event = events.next().await.unwrap();
+        assert_eq!(event, KeyEvent::new(KeyCode::A, KeyState::Down));
+        let event = events.next().await.unwrap();
+        assert_eq!(event, KeyEvent::new(KeyCode::A, KeyState::Up));
+    });
+
+    assert_eq!(executor.run_until_idle(), 1);
+    add_scancode(0x1e);
+    add_scancode(0x9e);
+    assert_eq!(executor.run_until_idle(), 0);
+}
+
+#[test_case]
+fn layouts_and_modifiers() {
+    use sys::task::executor::Executor;
+
+    let mut executor = Executor::new();
+    executor.spawn(async {
+        let mut keys = KeyStream::new();
+
+        // shift + a
+        let key = keys.next().await.unwrap();
+        assert_eq!((key.code, key.decoded), (KeyCode::LShift, None));
+        let key = keys.next().await.unwrap();
+        assert_eq!(key.char(), Some('A'));
+        assert_eq!(key.modifiers, Modifiers::SHIFT | Modifiers::NUM_LOCK);
+        let key = keys.next().await.unwrap();
+        assert!(!key.is_press() && key.decoded.is_none());
+        let key = keys.next().await.unwrap();
+        assert_eq!(key.modifiers, Modifiers::NUM_LOCK);
+
+        // ctrl + c
+        keys.next().await.unwrap();
+        let key = keys.next().await.unwrap();
+        assert_eq!(key.char(), Some('c'));
+        assert!(key.is_combination());
+        keys.next().await.unwrap();
+        keys.next().await.unwrap();
+
+        // the y key types z in German, and caps lock stays on
+        let key = keys.next().await.unwrap();
+        assert!(key.modifiers.contains(Modifiers::CAPS_LOCK));
+        keys.next().await.unwrap();
+        set_layout(Layout::German);
+        let key = keys.next().await.unwrap();
+        assert_eq!(key.char(), Some('Z'));
+        keys.next().await.unwrap();
+        // and the key right of L types ö in Swedish
+        set_layout(Layout::Swedish);
+        let key = keys.next().await.unwrap();
+        assert_eq!(key.char(), Some('Ö'));
+        keys.next().await.unwrap();
+        set_layout(Layout::Us);
+    });
+
+    assert_eq!(executor.run_until_idle(), 1);
+    for scancode in [
+        0x2a, 0x1e, 0x9e, 0xaa, // shift + a
+        0x1d, 0x2e, 0xae, 0x9d, // ctrl + c
+        0x3a, 0xba, 0x15, 0x95, // caps lock, y
+        0x27, 0xa7, // ;
+    ] {
+        add_scancode(scancode);
+    }
+    assert_eq!(executor.run_until_idle(), 0);
+    assert_eq!(Layout::from_name("se"), Some(Layout::Swedish));
+}
+
+#[test_case]
+fn scancode_overflow() {
+    let dropped = dropped_scancodes();
+    for _ in 0..=SCANCODE_QUEUE_CAPACITY {
+        add_scancode(0x1e);
+    }
+    assert_eq!(dropped_scancodes(), dropped + 1);
+
+    let queue = SCANCODE_QUEUE.get().unwrap();
+    while queue.pop().is_some() {}
+}
@@ -0,0 +1,88 @@
+//! The Finnish/Swedish and Norwegian layouts, which pc-keyboard 0.7
+//! lacks. Both are ISO layouts that differ from the US one only in
+//! their symbols and extra letters.
+
+use pc_keyboard::{
+    layouts::Us104Key, DecodedKey, HandleControl, KeyCode, KeyboardLayout, Modifiers,
+};
+
+/// The Finnish and Swedish 105-key layout. Dead keys type their accent.
+pub struct FiSe105Key;
+
+impl KeyboardLayout for FiSe105Key {
+    fn map_keycode(
+        &self,
+        keycode: KeyCode,
+        modifiers: &Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        let chars = match keycode {
+            KeyCode::Oem8 => ('§', '½', None),
+            KeyCode::Key2 => ('2', '"', Some('@')),
+            KeyCode::Key3 => ('3', '#', Some('£')),
+            KeyCode::Key4 => ('4', '¤', Some('$')),
+            KeyCode::Key5 => ('5', '%', Some('€')),
+            KeyCode::Key6 => ('6', '&', None),
+            KeyCode::Key7 => ('7', '/', Some('{')),
+            KeyCode::Key8 => ('8', '(', Some('[')),
+            KeyCode::Key9 => ('9', ')', Some(']')),
+            KeyCode::Key0 => ('0', '=', Some('}')),
+            KeyCode::OemMinus => ('+', '?', Some('\\')),
+            KeyCode::OemPlus => ('´', '`', None),
+            KeyCode::Oem4 => ('å', 'Å', None),
+            KeyCode::Oem6 => ('¨', '^', Some('~')),
+            KeyCode::Oem1 => ('ö', 'Ö', None),
+            KeyCode::Oem3 => ('ä', 'Ä', None),
+            KeyCode::Oem7 => ('\'', '*', None),
+            KeyCode::Oem5 => ('<', '>', Some('|')),
+            KeyCode::OemComma => (',', ';', None),
+            KeyCode::OemPeriod => ('.', ':', None),
+            KeyCode::Oem2 => ('-', '_', None),
+            code => return Us104Key.map_keycode(code, modifiers, handle_ctrl),
+        };
+        decode(chars, modifiers)
+    }
+}
+
+/// The Norwegian 105-key layout, which differs from [`FiSe105Key`] in
+/// a few keys.
+pub struct No105Key;
+
+impl KeyboardLayout for No105Key {
+    fn map_keycode(
+        &self,
+        keycode: KeyCode,
+        modifiers: &Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        let chars = match keycode {
+            KeyCode::Oem8 => ('|', '§', None),
+            KeyCode::OemMinus => ('+', '?', None),
+            KeyCode::OemPlus => ('\\', '`', Some('´')),
+            KeyCode::Oem1 => ('ø', 'Ø', None),
+            KeyCode::Oem3 => ('æ', 'Æ', None),
+            KeyCode::Oem5 => ('<', '>', None),
+            code => return FiSe105Key.map_keycode(code, modifiers, handle_ctrl),
+        };
+        decode(chars, modifiers)
+    }
+}
+
+/// Pick the character a key types without and with Shift, and with
+/// `AltGr` if it types one then. Letters follow Caps Lock too.
+fn decode(
+    (normal, shifted, alt_gr): (char, char, Option<char>),
+    modifiers: &Modifiers,
+) -> DecodedKey {
+    let shift = if normal.is_alphabetic() {
+        modifiers.is_caps()
+    } else {
+        modifiers.is_shifted()
+    };
+    let character = match alt_gr {
+        Some(character) if modifiers.alt_gr => character,
+        _ if shift => shifted,
+        _ => normal,
+    };
+    DecodedKey::Unicode(character)
+}
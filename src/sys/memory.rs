@@ -1,20 +1,285 @@
-use crate::sys;
+use crate::sys::{self, sync::IrqSafeMutex};
+use alloc::{collections::BTreeMap, vec::Vec};
 use bootloader::{
     bootinfo::{MemoryMap, MemoryRegionType},
     BootInfo,
 };
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    registers::control::{Cr0, Cr0Flags, Cr3},
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, Translate, UnmapError},
+        page::PageRange,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
+/// Frames below 1 MiB are never allocated, as real-mode code (like
+/// the AP startup trampoline) has to live there.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+pub const MMIO_START: u64 = 0x4444_8888_0000;
+
+/// User mode memory lives in between the kernel's own mappings: the
+/// first free level 4 entries, which the bootloader uses for the
+/// physical memory mapping, its stack and the boot info, and the heap.
+pub const USER_START: u64 = 0x1000_0000_0000;
+pub const USER_END: u64 = 0x4000_0000_0000;
+
+/// Marks user pages that are writable, but share their frame with other
+/// address spaces until the first write (see
+/// [`crate::sys::user::AddressSpace::fork`]). They are mapped read-only.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+static MEMORY_MAP: Once<&'static MemoryMap> = Once::new();
+static KERNEL_PAGE_TABLE: Once<PhysFrame> = Once::new();
+static MAPPER: IrqSafeMutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> =
+    IrqSafeMutex::new(None);
+
 pub fn init(boot_info: &'static BootInfo) {
+    PHYSICAL_MEMORY_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    MEMORY_MAP.call_once(|| &boot_info.memory_map);
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    KERNEL_PAGE_TABLE.call_once(|| Cr3::read().0);
+    // so that the kernel's writes to copy-on-write pages fault too
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
     let mut mapper = unsafe { mapper(phys_mem_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
 
     sys::allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+
+    *MAPPER.lock() = Some((mapper, frame_allocator));
+}
+
+/// Run `f` with exclusive access to the active page table and the
+/// frame allocator.
+///
+/// # Panics
+///
+/// Panics if [`init`] hasn't been called.
+pub fn with_mapper<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> R {
+    let mut guard = MAPPER.lock();
+    let (mapper, frame_allocator) = guard.as_mut().expect("memory not initialized");
+    f(mapper, frame_allocator)
+}
+
+/// The level 4 page table set up by the bootloader, which holds the
+/// kernel's mappings (see [`crate::sys::user::AddressSpace`]).
+///
+/// # Panics
+///
+/// Panics if [`init`] hasn't been called.
+#[must_use]
+pub fn kernel_page_table() -> PhysFrame {
+    *KERNEL_PAGE_TABLE.get().expect("memory not initialized")
+}
+
+/// Allocate a physical frame, for use outside of the kernel's page
+/// table.
+#[must_use]
+pub fn allocate_frame() -> Option<PhysFrame> {
+    with_mapper(|_, frame_allocator| frame_allocator.allocate_frame())
+}
+
+/// Free a frame returned by [`allocate_frame`].
+///
+/// # Safety
+///
+/// The frame must no longer be in use.
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    with_mapper(|_, frame_allocator| frame_allocator.deallocate_frame(frame));
+}
+
+/// Take another reference to a frame returned by [`allocate_frame`],
+/// which is then only freed once every reference has been released
+/// with [`release_frame`].
+pub fn share_frame(frame: PhysFrame) {
+    with_mapper(|_, frame_allocator| frame_allocator.share(frame));
+}
+
+/// Release a reference to a frame returned by [`allocate_frame`], and
+/// free it if it was the last one. Returns whether it was freed.
+///
+/// # Safety
+///
+/// The reference must no longer be in use.
+#[allow(clippy::must_use_candidate)]
+pub unsafe fn release_frame(frame: PhysFrame) -> bool {
+    with_mapper(|_, frame_allocator| frame_allocator.release(frame))
+}
+
+/// The number of references to a frame returned by [`allocate_frame`].
+#[must_use]
+pub fn frame_references(frame: PhysFrame) -> usize {
+    with_mapper(|_, frame_allocator| frame_allocator.references(frame))
+}
+
+/// Allocates frames with [`allocate_frame`], for mapping memory into
+/// page tables other than the kernel's.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        deallocate_frame(frame);
+    }
+}
+
+/// The type of the memory region containing `addr`, according to
+/// the bootloader's memory map.
+#[must_use]
+pub fn region_type(addr: PhysAddr) -> Option<MemoryRegionType> {
+    MEMORY_MAP
+        .get()?
+        .iter()
+        .find(|r| (r.range.start_addr()..r.range.end_addr()).contains(&addr.as_u64()))
+        .map(|r| r.region_type)
+}
+
+/// Map `size` bytes of memory-mapped I/O registers at `addr`,
+/// uncached, and return their virtual address.
+///
+/// # Errors
+///
+/// Mapping errors will be propagated.
+pub fn map_mmio(addr: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let first = PhysFrame::<Size4KiB>::containing_address(addr);
+    let last = PhysFrame::<Size4KiB>::containing_address(addr + size - 1u64);
+    let frames = PhysFrame::range_inclusive(first, last);
+    let len = (last.start_address() - first.start_address()) + 4096;
+
+    let start = VirtAddr::new(NEXT_MMIO.fetch_add(len, Ordering::Relaxed));
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+
+    with_mapper(|mapper, frame_allocator| {
+        for (i, frame) in frames.enumerate() {
+            let page = Page::containing_address(start + i as u64 * 4096);
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        }
+        Ok::<(), MapToError<Size4KiB>>(())
+    })?;
+
+    Ok(start + (addr - first.start_address()))
+}
+
+/// Whether the `len` bytes at `addr` are all user memory, mapped in
+/// the active page table and accessible from user mode (and writable,
+/// if `write` is set).
+#[must_use]
+pub fn is_user_accessible(addr: VirtAddr, len: u64, write: bool) -> bool {
+    let end = match addr.as_u64().checked_add(len) {
+        Some(end) if addr.as_u64() >= USER_START && end <= USER_END => end,
+        _ => return false,
+    };
+    if len == 0 {
+        return true;
+    }
+
+    let (level_4_table, _) = Cr3::read();
+    let first = Page::<Size4KiB>::containing_address(addr);
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    Page::range_inclusive(first, last).all(|page| {
+        user_page_writable(level_4_table, page).map_or(false, |writable| writable || !write)
+    })
+}
+
+/// Whether `page` is writable, if it is mapped and accessible from user
+/// mode at every level of the page tables at `level_4_table`. Only
+/// reads the tables, so that they don't need to be locked.
+fn user_page_writable(level_4_table: PhysFrame, page: Page<Size4KiB>) -> Option<bool> {
+    let indices = [
+        page.p4_index(),
+        page.p3_index(),
+        page.p2_index(),
+        page.p1_index(),
+    ];
+
+    let mut frame = level_4_table;
+    let mut writable = true;
+    for index in indices {
+        let table: &PageTable = unsafe { &*phys_to_virt(frame.start_address()).as_ptr() };
+        let entry = &table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) {
+            return None;
+        }
+        writable &= flags.contains(PageTableFlags::WRITABLE);
+        // user memory is never mapped with huge pages
+        frame = entry.frame().ok()?;
+    }
+    Some(writable)
+}
+
+/// Unmap `pages`, and flush them from the TLBs of all CPUs. The
+/// frames they were mapped to aren't freed.
+///
+/// # Errors
+///
+/// If one of the pages isn't mapped, an error is returned. The pages
+/// before it have been unmapped.
+pub fn unmap(pages: PageRange<Size4KiB>) -> Result<(), UnmapError> {
+    let result = with_mapper(|mapper, _| {
+        for page in pages {
+            mapper.unmap(page)?.1.flush();
+        }
+        Ok(())
+    });
+
+    // only once the mapper is unlocked, since other CPUs might be
+    // waiting for it with interrupts disabled
+    sys::ipi::shootdown(pages);
+    result
+}
+
+/// Change the flags of the mapped `pages`, and flush them from the
+/// TLBs of all CPUs.
+///
+/// # Errors
+///
+/// If one of the pages isn't mapped, an error is returned. The pages
+/// before it have been updated.
+pub fn protect(pages: PageRange<Size4KiB>, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+    let result = with_mapper(|mapper, _| {
+        for page in pages {
+            unsafe { mapper.update_flags(page, flags)? }.flush();
+        }
+        Ok(())
+    });
+
+    sys::ipi::shootdown(pages);
+    result
+}
+
+/// Translate a virtual address to the physical address it's mapped to.
+#[must_use]
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    with_mapper(|mapper, _| mapper.translate_addr(addr))
+}
+
+/// Translate a physical address to the virtual address at which
+/// the bootloader has mapped it.
+#[must_use]
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
 }
 
 /// # Safety
@@ -35,8 +300,6 @@ pub unsafe fn mapper(physical_memory_offset: VirtAddr) -> OffsetPageTable<'stati
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    use x86_64::registers::control::Cr3;
-
     let (level_4_table_frame, _) = Cr3::read();
 
     let phys = level_4_table_frame.start_address();
@@ -49,6 +312,10 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    /// Frames that have been freed, which are reused first.
+    free: Vec<PhysFrame>,
+    /// The number of references to frames that have more than one.
+    shared: BTreeMap<PhysFrame, usize>,
 }
 
 impl BootInfoFrameAllocator {
@@ -64,6 +331,8 @@ impl BootInfoFrameAllocator {
         Self {
             memory_map,
             next: 0,
+            free: Vec::new(),
+            shared: BTreeMap::new(),
         }
     }
 
@@ -71,16 +340,52 @@ impl BootInfoFrameAllocator {
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame<Size4KiB>> {
         let regions = self.memory_map.iter();
         let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+        let addr_ranges =
+            usable_regions.map(|r| r.range.start_addr().max(LOW_MEMORY_END)..r.range.end_addr());
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    fn share(&mut self, frame: PhysFrame) {
+        *self.shared.entry(frame).or_insert(1) += 1;
+    }
+
+    /// # Safety
+    ///
+    /// See [`release_frame`].
+    unsafe fn release(&mut self, frame: PhysFrame) -> bool {
+        match self.shared.get_mut(&frame) {
+            Some(references) if *references > 2 => *references -= 1,
+            Some(_) => {
+                self.shared.remove(&frame);
+            }
+            None => {
+                self.deallocate_frame(frame);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn references(&self, frame: PhysFrame) -> usize {
+        self.shared.get(&frame).copied().unwrap_or(1)
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if let Some(frame) = self.free.pop() {
+            return Some(frame);
+        }
+
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.free.push(frame);
+    }
+}
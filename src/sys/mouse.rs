@@ -0,0 +1,405 @@
+//! The PS/2 mouse, on the second port of the controller (see
+//! [`sys::ps2`](crate::sys::ps2)).
+//!
+//! The mouse sends packets of three bytes, or four with a scroll wheel
+//! once it has been switched to `IntelliMouse` mode. The interrupt
+//! handler decodes them, and queues the events for [`MouseEventStream`].
+
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use bit_field::BitField;
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+use spin::Once;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::sys::{
+    pic::{self, Irq, PICS},
+    ps2::{Controller, Error, PortId},
+    sync::IrqSafeMutex,
+};
+
+const EVENT_QUEUE_CAPACITY: usize = 128;
+
+/// Mouse commands.
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const GET_DEVICE_ID: u8 = 0xf2;
+const SET_DEFAULTS: u8 = 0xf6;
+const ENABLE_REPORTING: u8 = 0xf4;
+
+/// What [`GET_DEVICE_ID`] returns for each kind of mouse.
+const STANDARD: u8 = 0;
+const INTELLIMOUSE: u8 = 3;
+const INTELLIMOUSE_EXPLORER: u8 = 4;
+
+static EVENT_QUEUE: Once<ArrayQueue<MouseEvent>> = Once::new();
+static EVENT_WAKER: AtomicWaker = AtomicWaker::new();
+static DROPPED_EVENTS: AtomicUsize = AtomicUsize::new(0);
+static STREAM_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// The device id of the mouse found by [`init_device`], or `u8::MAX`
+/// if there is none.
+static DEVICE_ID: AtomicU8 = AtomicU8::new(u8::MAX);
+
+/// The buttons held.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Buttons {
+    bits: u8,
+}
+
+impl Buttons {
+    pub const NONE: Self = Self { bits: 0 };
+    pub const LEFT: Self = Self { bits: 1 };
+    pub const RIGHT: Self = Self { bits: 1 << 1 };
+    pub const MIDDLE: Self = Self { bits: 1 << 2 };
+    /// The side buttons of an `IntelliMouse` Explorer.
+    pub const FOURTH: Self = Self { bits: 1 << 3 };
+    pub const FIFTH: Self = Self { bits: 1 << 4 };
+
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    #[must_use]
+    pub const fn union(self, other: Self) -> Self {
+        Self {
+            bits: self.bits | other.bits,
+        }
+    }
+}
+
+impl core::ops::BitOr for Buttons {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
+/// The movement since the last event, and the buttons held.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// To the right.
+    pub dx: i16,
+    /// Down, like screen coordinates (the mouse reports it up).
+    pub dy: i16,
+    /// Scrolled down, in steps.
+    pub wheel: i8,
+    pub buttons: Buttons,
+}
+
+/// Assembles packets from the bytes the mouse sends.
+#[derive(Debug, Clone, Copy)]
+pub struct Decoder {
+    bytes: [u8; 4],
+    len: usize,
+    device_id: u8,
+}
+
+impl Decoder {
+    /// A decoder for the packets of a mouse with the device id
+    /// `device_id`: 0 for a standard mouse, 3 with a wheel, and 4 with
+    /// a wheel and two more buttons.
+    #[must_use]
+    pub const fn new(device_id: u8) -> Self {
+        Self {
+            bytes: [0; 4],
+            len: 0,
+            device_id,
+        }
+    }
+
+    const fn packet_len(&self) -> usize {
+        match self.device_id {
+            INTELLIMOUSE | INTELLIMOUSE_EXPLORER => 4,
+            _ => 3,
+        }
+    }
+
+    /// Add the next `byte`, and return the event once the packet is
+    /// complete.
+    pub fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // the first byte always has bit 3 set, which finds the start of
+        // a packet again after a lost byte
+        if self.len == 0 && !byte.get_bit(3) {
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.packet_len() {
+            return None;
+        }
+        self.len = 0;
+        Some(self.decode())
+    }
+
+    fn decode(&self) -> MouseEvent {
+        let [flags, x, y, extra] = self.bytes;
+        // 9-bit two's complement, meaningless if it overflowed
+        let axis = |value: u8, sign: usize, overflow: usize| {
+            if flags.get_bit(overflow) {
+                0
+            } else if flags.get_bit(sign) {
+                i16::from(value) - 256
+            } else {
+                i16::from(value)
+            }
+        };
+
+        let mut buttons = Buttons {
+            bits: flags & 0b111,
+        };
+        #[allow(clippy::cast_possible_wrap)]
+        let wheel = match self.device_id {
+            INTELLIMOUSE => extra as i8,
+            INTELLIMOUSE_EXPLORER => {
+                if extra.get_bit(4) {
+                    buttons = buttons | Buttons::FOURTH;
+                }
+                if extra.get_bit(5) {
+                    buttons = buttons | Buttons::FIFTH;
+                }
+                // 4-bit two's complement
+                ((extra << 4) as i8) >> 4
+            }
+            _ => 0,
+        };
+
+        MouseEvent {
+            dx: axis(x, 4, 6),
+            dy: -axis(y, 5, 7),
+            wheel,
+            buttons,
+        }
+    }
+}
+
+static DECODER: IrqSafeMutex<Decoder> = IrqSafeMutex::new(Decoder::new(STANDARD));
+
+/// Number of events dropped because they arrived while the queue was
+/// full (or before it was initialized).
+pub fn dropped_events() -> usize {
+    DROPPED_EVENTS.load(Ordering::Relaxed)
+}
+
+/// Decode `byte` from the mouse, and queue the event for
+/// [`MouseEventStream`] once a packet is complete. Called from the
+/// interrupt handler, so it must neither block nor allocate.
+pub(crate) fn add_byte(byte: u8) {
+    let Some(event) = DECODER.lock().add_byte(byte) else {
+        return;
+    };
+    let queued = EVENT_QUEUE
+        .get()
+        .is_some_and(|queue| queue.push(event).is_ok());
+
+    if queued {
+        EVENT_WAKER.wake();
+    } else {
+        DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) extern "x86-interrupt" fn handle_interrupt(_stack_frame: InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x60);
+    let byte: u8 = unsafe { port.read() };
+    add_byte(byte);
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(Irq::Mouse.as_u8());
+    }
+}
+
+/// Whether a mouse was found.
+pub fn is_present() -> bool {
+    DEVICE_ID.load(Ordering::Relaxed) != u8::MAX
+}
+
+/// Whether the mouse has a scroll wheel.
+pub fn has_wheel() -> bool {
+    matches!(
+        DEVICE_ID.load(Ordering::Relaxed),
+        INTELLIMOUSE | INTELLIMOUSE_EXPLORER
+    )
+}
+
+/// The events from the mouse.
+///
+/// There can only be one stream at a time, since every event is
+/// delivered only once.
+#[allow(clippy::module_name_repetitions)]
+pub struct MouseEventStream {
+    _private: (),
+}
+
+impl MouseEventStream {
+    /// # Panics
+    ///
+    /// Panics if another [`MouseEventStream`] exists.
+    #[must_use]
+    pub fn new() -> Self {
+        assert!(
+            !STREAM_TAKEN.swap(true, Ordering::Acquire),
+            "only one MouseEventStream may exist at a time"
+        );
+        Self { _private: () }
+    }
+}
+
+impl Default for MouseEventStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for MouseEventStream {
+    fn drop(&mut self) {
+        STREAM_TAKEN.store(false, Ordering::Release);
+    }
+}
+
+impl Stream for MouseEventStream {
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>> {
+        let queue = EVENT_QUEUE.get().expect("mouse not initialized");
+
+        // fast path
+        if let Some(event) = queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        EVENT_WAKER.register(cx.waker());
+        queue.pop().map_or(Poll::Pending, |event| {
+            EVENT_WAKER.take();
+            Poll::Ready(Some(event))
+        })
+    }
+}
+
+/// Reset the mouse on the second port, switch it to the mode with the
+/// most features it has, and make it report movement. Called by
+/// [`ps2::init`](crate::sys::ps2::init) with the port's interrupt
+/// disabled.
+///
+/// # Errors
+///
+/// If there is no mouse, or it doesn't respond, an error is returned.
+pub(crate) fn init_device(controller: &mut Controller) -> Result<(), Error> {
+    controller.reset(PortId::Second)?;
+    // followed by its device id
+    controller.read()?;
+
+    // each sequence of sample rates unlocks the next mode, if the mouse
+    // has it
+    let mut device_id = STANDARD;
+    for (rates, id) in [
+        ([200, 100, 80], INTELLIMOUSE),
+        ([200, 200, 80], INTELLIMOUSE_EXPLORER),
+    ] {
+        for rate in rates {
+            controller.send(PortId::Second, SET_SAMPLE_RATE)?;
+            controller.send(PortId::Second, rate)?;
+        }
+        controller.send(PortId::Second, GET_DEVICE_ID)?;
+        if controller.read()? != id {
+            break;
+        }
+        device_id = id;
+    }
+
+    // the sample rates above are changed back, but not the mode
+    controller.send(PortId::Second, SET_DEFAULTS)?;
+    controller.send(PortId::Second, ENABLE_REPORTING)?;
+
+    *DECODER.lock() = Decoder::new(device_id);
+    DEVICE_ID.store(device_id, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn init() {
+    EVENT_QUEUE.call_once(|| ArrayQueue::new(EVENT_QUEUE_CAPACITY));
+    pic::unmask(Irq::Mouse);
+}
+
+#[test_case]
+fn decode_packets() {
+    let mut decoder = Decoder::new(STANDARD);
+    // lost bytes are skipped until the start of a packet
+    assert_eq!(decoder.add_byte(0x00), None);
+    assert_eq!(decoder.add_byte(0b0000_1001), None);
+    assert_eq!(decoder.add_byte(5), None);
+    assert_eq!(
+        decoder.add_byte(3),
+        Some(MouseEvent {
+            dx: 5,
+            dy: -3,
+            wheel: 0,
+            buttons: Buttons::LEFT,
+        })
+    );
+
+    // overflowed
+    for byte in [0b0100_1000, 0x80] {
+        assert_eq!(decoder.add_byte(byte), None);
+    }
+    assert_eq!(decoder.add_byte(0), Some(MouseEvent::default()));
+
+    // negative
+    let mut decoder = Decoder::new(INTELLIMOUSE_EXPLORER);
+    for byte in [0b0011_1010, 0xfe, 0xff] {
+        assert_eq!(decoder.add_byte(byte), None);
+    }
+    assert_eq!(
+        decoder.add_byte(0x1f),
+        Some(MouseEvent {
+            dx: -2,
+            dy: 1,
+            wheel: -1,
+            buttons: Buttons::RIGHT | Buttons::FOURTH,
+        })
+    );
+
+    let mut decoder = Decoder::new(INTELLIMOUSE);
+    for byte in [0b0000_1100, 0, 0] {
+        assert_eq!(decoder.add_byte(byte), None);
+    }
+    let event = decoder.add_byte(2).unwrap();
+    assert_eq!((event.wheel, event.buttons), (2, Buttons::MIDDLE));
+}
+
+#[test_case]
+fn event_stream() {
+    use crate::sys::task::executor::Executor;
+    use futures_util::StreamExt;
+
+    // QEMU emulates a mouse with a wheel
+    assert!(is_present() && has_wheel());
+
+    let mut executor = Executor::new();
+    executor.spawn(async {
+        let mut events = MouseEventStream::new();
+        let event = events.next().await.unwrap();
+        assert_eq!((event.dx, event.dy), (1, -1));
+        assert!(event.buttons.contains(Buttons::LEFT));
+    });
+
+    assert_eq!(executor.run_until_idle(), 1);
+    let packet: &[u8] = if DEVICE_ID.load(Ordering::Relaxed) == STANDARD {
+        &[0b0000_1001, 1, 1]
+    } else {
+        &[0b0000_1001, 1, 1, 0]
+    };
+    for &byte in packet {
+        add_byte(byte);
+    }
+    assert_eq!(executor.run_until_idle(), 0);
+}
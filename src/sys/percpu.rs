@@ -0,0 +1,218 @@
+//! Per-CPU data. Each CPU's GS base points to its [`CpuArea`], so it
+//! can find its own data with a single instruction.
+//!
+//! Variables are declared with [`percpu!`], and can only be accessed
+//! by the CPU they belong to. While borrowed, the current thread
+//! can't be preempted, so it can't migrate to another CPU.
+//!
+//! User mode keeps running with the kernel's GS base, so that the
+//! `x86-interrupt` handlers, which can't `swapgs`, work unchanged. As
+//! user code can still reset the GS base by loading a selector into
+//! `gs`, every entry from user mode first reloads it from the kernel
+//! GS base, which user code can't change (see [`crate::sys::trap`]).
+
+use core::{
+    arch::asm,
+    marker::PhantomData,
+    ops::Deref,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+use x86_64::{
+    instructions::interrupts,
+    registers::model_specific::{GsBase, KernelGsBase},
+    VirtAddr,
+};
+
+use crate::sys::smp::MAX_CPUS;
+
+/// The data every CPU's GS base points to.
+#[repr(C)]
+pub struct CpuArea {
+    /// Read by [`cpu_number`] as `gs:[0]`.
+    cpu: AtomicUsize,
+    /// See [`disable_preemption`].
+    preempt_count: AtomicUsize,
+    /// The stack system calls run on, as `gs:[16]`.
+    syscall_stack: AtomicU64,
+    /// Scratch space for the user stack pointer on system call
+    /// entry, as `gs:[24]`.
+    user_rsp: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+static AREAS: [CpuArea; MAX_CPUS] = {
+    const AREA: CpuArea = CpuArea {
+        cpu: AtomicUsize::new(0),
+        preempt_count: AtomicUsize::new(0),
+        syscall_stack: AtomicU64::new(0),
+        user_rsp: AtomicU64::new(0),
+    };
+    [AREA; MAX_CPUS]
+};
+
+/// Whether the GS base of the BSP has been set. Until then, only the
+/// BSP is running.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// The number of the current CPU (see [`crate::sys::smp::current_cpu`]).
+#[must_use]
+#[inline]
+pub fn cpu_number() -> usize {
+    if !READY.load(Ordering::Relaxed) {
+        return 0;
+    }
+
+    let cpu: usize;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, readonly, preserves_flags)) };
+    cpu
+}
+
+fn area() -> &'static CpuArea {
+    &AREAS[cpu_number()]
+}
+
+/// Set the stack that system calls on the current CPU switch to
+/// (see [`crate::sys::gdt::set_kernel_stack`]).
+pub(crate) fn set_syscall_stack(rsp: VirtAddr) {
+    area().syscall_stack.store(rsp.as_u64(), Ordering::Relaxed);
+}
+
+/// Keeps the current thread from being preempted until dropped.
+#[must_use]
+pub struct PreemptGuard {
+    /// Must be dropped on the same CPU.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| area().preempt_count.fetch_sub(1, Ordering::Relaxed));
+    }
+}
+
+/// Keep the current thread from being preempted (and hence from
+/// migrating to another CPU) until the guard is dropped. Interrupts
+/// are still handled.
+pub fn disable_preemption() -> PreemptGuard {
+    interrupts::without_interrupts(|| area().preempt_count.fetch_add(1, Ordering::Relaxed));
+    PreemptGuard {
+        _not_send: PhantomData,
+    }
+}
+
+/// Whether the current thread must not be preempted.
+#[must_use]
+pub fn preemption_disabled() -> bool {
+    interrupts::without_interrupts(|| area().preempt_count.load(Ordering::Relaxed) != 0)
+}
+
+/// A variable with a separate value for every CPU. Declared with
+/// [`percpu!`].
+pub struct PerCpu<T> {
+    values: [T; MAX_CPUS],
+}
+
+// every value is only ever accessed by its own CPU
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(values: [T; MAX_CPUS]) -> Self {
+        Self { values }
+    }
+
+    /// Borrow the current CPU's value. Since interrupt handlers may
+    /// borrow it too, it usually needs interior mutability, such as
+    /// [`core::cell::Cell`].
+    #[must_use]
+    pub fn get(&self) -> PerCpuRef<'_, T> {
+        let guard = disable_preemption();
+        PerCpuRef {
+            value: &self.values[cpu_number()],
+            _guard: guard,
+        }
+    }
+
+    /// Run `f` with the current CPU's value.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.get())
+    }
+}
+
+/// A borrowed [`PerCpu`] value. The current thread isn't preempted
+/// while it exists.
+pub struct PerCpuRef<'a, T> {
+    value: &'a T,
+    _guard: PreemptGuard,
+}
+
+impl<T> Deref for PerCpuRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+/// Declare per-CPU variables of type [`PerCpu`], each initialized
+/// with a constant expression.
+///
+/// ```ignore
+/// percpu! {
+///     static COUNTER: Cell<usize> = Cell::new(0);
+/// }
+///
+/// COUNTER.with(|counter| counter.set(counter.get() + 1));
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::sys::percpu::PerCpu<$ty> = {
+                #[allow(clippy::declare_interior_mutable_const)]
+                const INIT: $ty = $init;
+                $crate::sys::percpu::PerCpu::new([INIT; $crate::sys::smp::MAX_CPUS])
+            };
+        )*
+    };
+}
+
+/// Point the GS base of the current CPU to its area. Called by
+/// [`crate::sys::gdt::init`] and [`crate::sys::gdt::init_ap`].
+pub(crate) fn init(cpu: usize) {
+    let area = &AREAS[cpu];
+    area.cpu.store(cpu, Ordering::Relaxed);
+
+    GsBase::write(VirtAddr::from_ptr(area));
+    // Interrupts from user mode reload the GS base from this copy,
+    // which user code can't change, instead of using `swapgs`: the
+    // `x86-interrupt` handlers couldn't swap back on return. (System
+    // calls do swap, and then restore this copy.)
+    KernelGsBase::write(VirtAddr::from_ptr(area));
+
+    if cpu == 0 {
+        READY.store(true, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn values_per_cpu() {
+    use core::cell::Cell;
+
+    percpu! {
+        static COUNTER: Cell<usize> = Cell::new(0);
+    }
+
+    COUNTER.with(|counter| counter.set(counter.get() + 1));
+    {
+        let counter = COUNTER.get();
+        assert!(preemption_disabled());
+        counter.set(counter.get() + 1);
+    }
+    assert!(!preemption_disabled());
+
+    assert_eq!(cpu_number(), 0);
+    assert_eq!(COUNTER.get().get(), 2);
+    assert!(COUNTER.values[1..].iter().all(|counter| counter.get() == 0));
+}
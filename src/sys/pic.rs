@@ -1,5 +1,6 @@
 use pic8259::ChainedPics;
-use spin::Mutex;
+
+use crate::sys::sync::IrqSafeMutex;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -11,6 +12,7 @@ pub enum Irq {
     Timer = PIC_1_OFFSET,
     Keyboard,
     Rtc = PIC_1_OFFSET + 8,
+    Mouse = PIC_1_OFFSET + 12,
 }
 
 impl Irq {
@@ -25,8 +27,31 @@ impl Irq {
     }
 }
 
-pub static PICS: Mutex<ChainedPics> =
-    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: IrqSafeMutex<ChainedPics> =
+    IrqSafeMutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// Let `irq` through, which the firmware may have masked.
+pub fn unmask(irq: Irq) {
+    use x86_64::instructions::port::Port;
+
+    let _pics = PICS.lock();
+    let line = irq.as_u8() - PIC_1_OFFSET;
+    // the second PIC is chained to line 2 of the first
+    let (mut port, bit, cascade) = if line < 8 {
+        (Port::<u8>::new(0x21), line, None)
+    } else {
+        (Port::<u8>::new(0xa1), line - 8, Some(2))
+    };
+    unsafe {
+        let mask = port.read();
+        port.write(mask & !(1 << bit));
+        if let Some(cascade) = cascade {
+            let mut port = Port::<u8>::new(0x21);
+            let mask = port.read();
+            port.write(mask & !(1 << cascade));
+        }
+    }
+}
 
 pub fn init() {
     unsafe { PICS.lock().initialize() };
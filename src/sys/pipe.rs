@@ -0,0 +1,266 @@
+//! Anonymous pipes: a buffer that bytes written to one end can be read
+//! from at the other, in order, opened as two files (see [`pipe`]).
+//!
+//! Reading blocks until there is something to read, and writing until
+//! there is room, either of which a signal interrupts. Once the writing
+//! end is closed, reading returns 0 at the end of the buffer, and once
+//! the reading end is closed, writing fails with [`Error::BrokenPipe`].
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use crate::sys::{
+    file::File,
+    process,
+    sync::{Condvar, Mutex, MutexGuard},
+    syscall::Error,
+};
+
+/// How many bytes a pipe holds. Writes of up to this many bytes aren't
+/// interleaved with others.
+pub const PIPE_CAPACITY: usize = 4096;
+
+struct State {
+    buffer: VecDeque<u8>,
+    /// Cleared once the reading end is closed.
+    reading: bool,
+    /// Cleared once the writing end is closed.
+    writing: bool,
+}
+
+impl State {
+    fn room(&self) -> usize {
+        PIPE_CAPACITY - self.buffer.len()
+    }
+}
+
+struct Pipe {
+    state: Mutex<State>,
+    /// Notified whenever bytes are written or read, or an end is closed.
+    changed: Condvar,
+}
+
+impl Pipe {
+    /// Block until `condition` returns `false`.
+    ///
+    /// # Errors
+    ///
+    /// If the calling thread is interrupted by a signal in the meantime,
+    /// [`Error::Interrupted`] is returned.
+    fn wait_while<'a>(
+        self: &'a Arc<Self>,
+        mut state: MutexGuard<'a, State>,
+        mut condition: impl FnMut(&State) -> bool,
+    ) -> Result<MutexGuard<'a, State>, Error> {
+        if !condition(&state) {
+            return Ok(state);
+        }
+
+        let pipe = self.clone();
+        let interrupt = Arc::new(move || {
+            // not between a waiter checking for signals and waiting
+            drop(pipe.state.lock());
+            pipe.changed.notify_all();
+        });
+        process::interruptible(interrupt, || {
+            while condition(&state) {
+                if process::interrupted() {
+                    return Err(Error::Interrupted);
+                }
+                state = self.changed.wait(state);
+            }
+            Ok(state)
+        })
+    }
+}
+
+/// The reading end of a pipe.
+#[allow(clippy::module_name_repetitions)]
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+}
+
+/// The writing end of a pipe.
+#[allow(clippy::module_name_repetitions)]
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+}
+
+/// Create a pipe, and return its ends.
+#[must_use]
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(State {
+            buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+            reading: true,
+            writing: true,
+        }),
+        changed: Condvar::new(),
+    });
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
+
+impl File for PipeReader {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let pipe = &self.pipe;
+        let mut state = pipe.wait_while(pipe.state.lock(), |state| {
+            state.buffer.is_empty() && state.writing
+        })?;
+
+        let len = state.buffer.len().min(buf.len());
+        for (byte, read) in buf.iter_mut().zip(state.buffer.drain(..len)) {
+            *byte = read;
+        }
+        drop(state);
+        pipe.changed.notify_all();
+        Ok(len)
+    }
+}
+
+impl File for PipeWriter {
+    /// Write all of `buf`, unless interrupted after writing some of it.
+    fn write(&self, buf: &[u8]) -> Result<usize, Error> {
+        let pipe = &self.pipe;
+        // small writes wait until there is room for all of them
+        let needed = if buf.len() <= PIPE_CAPACITY {
+            buf.len()
+        } else {
+            1
+        };
+
+        let mut written = 0;
+        while written < buf.len() {
+            let state = pipe.wait_while(pipe.state.lock(), |state| {
+                state.reading && state.room() < needed
+            });
+            let mut state = match state {
+                Ok(state) if state.reading => state,
+                Ok(_) | Err(_) if written > 0 => break,
+                Ok(_) => return Err(Error::BrokenPipe),
+                Err(e) => return Err(e),
+            };
+
+            let len = state.room().min(buf.len() - written);
+            state.buffer.extend(&buf[written..written + len]);
+            written += len;
+            drop(state);
+            pipe.changed.notify_all();
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let mut state = self.pipe.state.lock();
+        state.reading = false;
+        state.buffer.clear();
+        drop(state);
+        self.pipe.changed.notify_all();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.pipe.state.lock().writing = false;
+        self.pipe.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+use crate::sys::thread;
+#[cfg(test)]
+use alloc::vec::Vec;
+
+#[test_case]
+fn read_and_write_ends() {
+    let (reader, writer) = pipe();
+    assert_eq!(writer.write(b"hello"), Ok(5));
+    let mut buf = [0; 8];
+    assert_eq!(reader.read(&mut buf), Ok(5));
+    assert_eq!(&buf[..5], b"hello");
+    assert_eq!(reader.write(b"!"), Err(Error::BadFileDescriptor));
+
+    writer.write(b"bye").unwrap();
+    drop(writer);
+    assert_eq!(reader.read(&mut buf), Ok(3));
+    assert_eq!(reader.read(&mut buf), Ok(0));
+
+    let (reader, writer) = pipe();
+    drop(reader);
+    assert_eq!(writer.write(b"hello"), Err(Error::BrokenPipe));
+}
+
+#[test_case]
+fn back_pressure() {
+    const LEN: usize = 16 * PIPE_CAPACITY;
+    let (reader, writer) = pipe();
+
+    let writer = thread::spawn(move || {
+        let data: Vec<u8> = (0..LEN).map(|i| u8::try_from(i % 251).unwrap()).collect();
+        for chunk in data.chunks(1000) {
+            assert_eq!(writer.write(chunk), Ok(chunk.len()));
+        }
+    });
+
+    let mut read = 0;
+    let mut buf = [0; 300];
+    loop {
+        match reader.read(&mut buf).unwrap() {
+            0 => break,
+            len => {
+                for (i, byte) in buf[..len].iter().enumerate() {
+                    assert_eq!(usize::from(*byte), (read + i) % 251);
+                }
+                read += len;
+            }
+        }
+        assert!(reader.pipe.state.lock().buffer.len() <= PIPE_CAPACITY);
+    }
+    assert_eq!(read, LEN);
+    writer.join();
+}
+
+#[test_case]
+fn writes_are_atomic() {
+    const RECORD: usize = 100;
+    let (reader, writer) = pipe();
+    let writer = Arc::new(writer);
+
+    let writers: Vec<_> = (0..4_u8)
+        .map(|id| {
+            let writer = writer.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    writer.write(&[id; RECORD]).unwrap();
+                }
+            })
+        })
+        .collect();
+    drop(writer);
+
+    let mut records = [0; 4];
+    let mut record = [0; RECORD];
+    loop {
+        let mut filled = 0;
+        while filled < RECORD {
+            match reader.read(&mut record[filled..]).unwrap() {
+                0 => break,
+                len => filled += len,
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+        assert_eq!(filled, RECORD);
+        assert!(record.iter().all(|&byte| byte == record[0]));
+        records[usize::from(record[0])] += 1;
+    }
+    assert_eq!(records, [50; 4]);
+
+    for writer in writers {
+        writer.join();
+    }
+}
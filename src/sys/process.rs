@@ -0,0 +1,470 @@
+//! User processes: an address space, the files open in it and the
+//! threads running in it.
+//!
+//! Processes are started from an executable with [`spawn`], or as a
+//! copy of the calling process with [`fork`].
+//!
+//! A process exits when its thread makes an `exit` system call, and is
+//! forgotten once its parent has collected the exit code with [`wait`].
+//! Processes spawned by the kernel have no parent, and are waited for
+//! by kernel threads. Processes whose parent has exited are forgotten
+//! as soon as they have exited too, unless a kernel thread is waiting.
+//!
+//! Each process has its own signal state (see [`crate::sys::signal`]).
+
+use crate::sys::{
+    elf::{self, Elf},
+    file::FileTable,
+    signal::{Signals, SIGCHLD},
+    sync::{Condvar, Mutex, MutexGuard},
+    syscall::Error,
+    thread::{self, ThreadId},
+    trap::TrapFrame,
+    user::{self, AddressSpace},
+};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::VirtAddr;
+
+type Processes = BTreeMap<Pid, Arc<Process>>;
+
+static PROCESSES: Mutex<Processes> = Mutex::new(BTreeMap::new());
+/// Notified whenever a process exits or is sent a signal, which
+/// interrupts `wait`.
+static EXITED: Condvar = Condvar::new();
+/// Number of kernel threads in [`wait`], which may collect orphans.
+/// Only changed while holding the lock on `PROCESSES`.
+static KERNEL_WAITERS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    #[must_use]
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    #[must_use]
+    pub const fn from_u64(pid: u64) -> Self {
+        Self(pid)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// Exited with the given code, but not waited for yet.
+    Exited(i32),
+}
+
+pub struct Process {
+    pid: Pid,
+    parent: Mutex<Option<Pid>>,
+    /// Whether the parent has exited, so that no process will wait for
+    /// this one.
+    orphaned: AtomicBool,
+    state: Mutex<State>,
+    /// `None` once the process has exited.
+    space: Mutex<Option<AddressSpace>>,
+    files: Mutex<FileTable>,
+    signals: Mutex<Signals>,
+    /// Wakes the thread of the process while it is blocked in an
+    /// interruptible system call (see [`interruptible`]).
+    interrupt: Mutex<Option<Interrupt>>,
+    threads: Mutex<Vec<ThreadId>>,
+}
+
+/// Wakes a thread blocked on something, so that it checks whether it has
+/// been [`interrupted`].
+pub type Interrupt = Arc<dyn Fn() + Send + Sync>;
+
+impl Process {
+    fn new(parent: Option<Pid>, space: AddressSpace, files: FileTable, signals: Signals) -> Self {
+        Self {
+            pid: Pid::new(),
+            parent: Mutex::new(parent),
+            orphaned: AtomicBool::new(false),
+            state: Mutex::new(State::Running),
+            space: Mutex::new(Some(space)),
+            files: Mutex::new(files),
+            signals: Mutex::new(signals),
+            interrupt: Mutex::new(None),
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    #[must_use]
+    pub const fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    #[must_use]
+    pub fn parent(&self) -> Option<Pid> {
+        *self.parent.lock()
+    }
+
+    #[must_use]
+    pub fn state(&self) -> State {
+        *self.state.lock()
+    }
+
+    /// The open files of the process.
+    pub fn files(&self) -> MutexGuard<FileTable> {
+        self.files.lock()
+    }
+
+    /// The address space of the process, which is `None` once it has
+    /// exited.
+    pub fn space(&self) -> MutexGuard<Option<AddressSpace>> {
+        self.space.lock()
+    }
+
+    /// The signal state of the process.
+    pub fn signals(&self) -> MutexGuard<Signals> {
+        self.signals.lock()
+    }
+
+    /// Send `sig` to the process (see [`Signals::send`]), interrupting
+    /// it if it is waiting for a child or blocked in another system call.
+    pub fn signal(&self, sig: u32) {
+        // with the lock `wait` checks for signals under
+        let processes = PROCESSES.lock();
+        self.signals.lock().send(sig);
+        drop(processes);
+        EXITED.notify_all();
+
+        let interrupt = self.interrupt.lock().clone();
+        if let Some(interrupt) = interrupt {
+            interrupt();
+        }
+    }
+
+    /// The threads running in the process.
+    #[must_use]
+    pub fn threads(&self) -> Vec<ThreadId> {
+        self.threads.lock().clone()
+    }
+
+    /// Start a thread in the process, entering user mode with the
+    /// registers in `frame`.
+    fn start_thread(self: &Arc<Self>, frame: TrapFrame) {
+        let process = self.clone();
+        let page_table = self
+            .space
+            .lock()
+            .as_ref()
+            .expect("process has exited")
+            .level_4_table();
+
+        let handle = thread::spawn(move || {
+            thread::set_process(Some(process.pid));
+            thread::set_page_table(Some(page_table));
+            let code = user::enter(&frame);
+            process.exit(code);
+        });
+        self.threads.lock().push(handle.id());
+    }
+
+    /// Tear down the process once its thread has left user mode.
+    fn exit(&self, code: i32) {
+        thread::set_page_table(None);
+        thread::set_process(None);
+        drop(self.space.lock().take());
+        self.files.lock().clear();
+
+        let mut processes = PROCESSES.lock();
+        // orphans are adopted by the kernel
+        for process in processes.values() {
+            let mut parent = process.parent.lock();
+            if *parent == Some(self.pid) {
+                *parent = None;
+                process.orphaned.store(true, Ordering::Relaxed);
+            }
+        }
+        *self.state.lock() = State::Exited(code);
+        if let Some(parent) = self.parent().and_then(|pid| processes.get(&pid)) {
+            parent.signals().send(SIGCHLD);
+        }
+        reap_orphans(&mut processes);
+        drop(processes);
+
+        EXITED.notify_all();
+    }
+}
+
+/// Load the executable `image` into a new process and run it with the
+/// arguments `argv` and the environment `envp`. The process is a child
+/// of the calling thread's process, if any, and has the console as its
+/// standard input, output and error.
+///
+/// # Errors
+///
+/// If the executable is invalid or can't be loaded, an error is returned.
+pub fn spawn(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, elf::Error> {
+    spawn_with_files(image, argv, envp, FileTable::with_console())
+}
+
+/// Like [`spawn`], but the process starts with the open `files`.
+///
+/// # Errors
+///
+/// If the executable is invalid or can't be loaded, an error is returned.
+pub fn spawn_with_files(
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+    files: FileTable,
+) -> Result<Pid, elf::Error> {
+    let elf = Elf::parse(image)?;
+
+    let mut space = AddressSpace::new()?;
+    elf.load(&mut space)?;
+    let stack = elf.setup_stack(&mut space, argv, envp)?;
+
+    let process = Arc::new(Process::new(
+        thread::current_process(),
+        space,
+        files,
+        Signals::new(),
+    ));
+    let pid = process.pid;
+    PROCESSES.lock().insert(pid, process.clone());
+
+    process.start_thread(TrapFrame::new(elf.entry(), stack));
+    Ok(pid)
+}
+
+/// Start a copy of the calling process as its child, with a
+/// copy-on-write copy of its address space (see [`AddressSpace::fork`])
+/// and the same open files. The child continues from the system call
+/// made with `frame`, returning 0 from it.
+///
+/// # Errors
+///
+/// If the caller isn't a process, [`Error::NoSuchProcess`] is returned,
+/// and if the address space can't be copied, [`Error::OutOfMemory`].
+pub fn fork(frame: &TrapFrame) -> Result<Pid, Error> {
+    let parent = current().ok_or(Error::NoSuchProcess)?;
+    let space = parent
+        .space
+        .lock()
+        .as_mut()
+        .ok_or(Error::NoSuchProcess)?
+        .fork()
+        .map_err(|_| Error::OutOfMemory)?;
+    let files = parent.files().clone();
+    let signals = parent.signals().fork();
+
+    let child = Arc::new(Process::new(Some(parent.pid), space, files, signals));
+    let pid = child.pid;
+    PROCESSES.lock().insert(pid, child.clone());
+
+    child.start_thread(TrapFrame {
+        rax: 0,
+        ..frame.clone()
+    });
+    Ok(pid)
+}
+
+/// Handle a page fault at `addr` in the calling thread's process (see
+/// [`AddressSpace::handle_fault`]). Returns `false` if the access isn't
+/// allowed, or the thread isn't in a process.
+#[must_use]
+pub fn handle_page_fault(addr: VirtAddr, write: bool) -> bool {
+    current().map_or(false, |process| {
+        process
+            .space()
+            .as_mut()
+            .map_or(false, |space| space.handle_fault(addr, write))
+    })
+}
+
+/// The process with the given id, unless it has been waited for.
+#[must_use]
+pub fn get(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+/// The process of the calling thread, if any.
+#[must_use]
+pub fn current() -> Option<Arc<Process>> {
+    thread::current_process().and_then(get)
+}
+
+/// Whether the calling thread's process has a signal pending that it
+/// doesn't block, which interrupts blocking system calls.
+#[must_use]
+pub fn interrupted() -> bool {
+    current().map_or(false, |process| process.signals().is_interrupted())
+}
+
+/// Run `f`, which blocks until it is woken, and checks whether the
+/// calling thread has been [`interrupted`] whenever it is. Signals sent
+/// to the calling process until `f` returns call `interrupt` to wake it.
+pub fn interruptible<R>(interrupt: Interrupt, f: impl FnOnce() -> R) -> R {
+    let process = current();
+    if let Some(process) = &process {
+        *process.interrupt.lock() = Some(interrupt);
+    }
+    let result = f();
+    if let Some(process) = &process {
+        *process.interrupt.lock() = None;
+    }
+    result
+}
+
+/// Block until the child `pid` (or any child, for `None`) of the calling
+/// thread's process has exited, forget it and return its id and exit
+/// code. Kernel threads wait for processes without a parent.
+///
+/// # Errors
+///
+/// If there is no such child, [`Error::NoChild`] is returned, and if a
+/// signal is sent to the calling process before one exits,
+/// [`Error::Interrupted`].
+pub fn wait(pid: Option<Pid>) -> Result<(Pid, i32), Error> {
+    let parent = thread::current_process();
+    let caller = current();
+    let processes = PROCESSES.lock();
+
+    if parent.is_some() {
+        return wait_locked(processes, parent, pid, caller.as_deref()).1;
+    }
+
+    KERNEL_WAITERS.fetch_add(1, Ordering::Relaxed);
+    let (mut processes, result) = wait_locked(processes, None, pid, None);
+    KERNEL_WAITERS.fetch_sub(1, Ordering::Relaxed);
+    reap_orphans(&mut processes);
+    result
+}
+
+/// Forget the orphans that have exited, unless a kernel thread is
+/// waiting and might collect them.
+fn reap_orphans(processes: &mut Processes) {
+    if KERNEL_WAITERS.load(Ordering::Relaxed) == 0 {
+        processes.retain(|_, p| {
+            !(p.orphaned.load(Ordering::Relaxed) && matches!(p.state(), State::Exited(_)))
+        });
+    }
+}
+
+/// [`wait`] for the process `parent`, with `PROCESSES` locked.
+fn wait_locked<'a>(
+    mut processes: MutexGuard<'a, Processes>,
+    parent: Option<Pid>,
+    pid: Option<Pid>,
+    caller: Option<&Process>,
+) -> (MutexGuard<'a, Processes>, Result<(Pid, i32), Error>) {
+    loop {
+        let mut children = processes
+            .values()
+            .filter(|p| p.parent() == parent && pid.map_or(true, |pid| p.pid == pid))
+            .peekable();
+        if children.peek().is_none() {
+            return (processes, Err(Error::NoChild));
+        }
+
+        let exited = children.find_map(|p| match p.state() {
+            State::Exited(code) => Some((p.pid, code)),
+            State::Running => None,
+        });
+        if let Some((pid, code)) = exited {
+            processes.remove(&pid);
+            return (processes, Ok((pid, code)));
+        }
+
+        if caller.map_or(false, |p| p.signals().is_interrupted()) {
+            return (processes, Err(Error::Interrupted));
+        }
+        processes = EXITED.wait(processes);
+    }
+}
+
+#[cfg(test)]
+use crate::sys::{
+    signal::{self, SIGPIPE, SIGSEGV, SIGTERM},
+    time,
+};
+#[cfg(test)]
+use elf::{FORK, HELLO, MMAP, PIPE, SIGNAL};
+
+#[test_case]
+fn spawn_and_wait() {
+    let pid = spawn(HELLO, &["hello", "from a process\n"], &[]).unwrap();
+    assert!(get(pid).is_some());
+    assert_eq!(wait(Some(pid)), Ok((pid, 2)));
+    assert!(get(pid).is_none());
+    assert_eq!(wait(Some(pid)), Err(Error::NoChild));
+}
+
+#[test_case]
+fn concurrent_processes() {
+    let pids: Vec<_> = (0..4)
+        .map(|_| spawn(HELLO, &["hello", "!", "!"], &["A=1"]).unwrap())
+        .collect();
+
+    for &pid in &pids {
+        assert_eq!(wait(Some(pid)), Ok((pid, 3)));
+    }
+    for &pid in &pids {
+        assert_eq!(wait(Some(pid)), Err(Error::NoChild));
+    }
+}
+
+#[test_case]
+fn exit_code() {
+    // too few arguments
+    let pid = spawn(HELLO, &["hello"], &[]).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, 255)));
+}
+
+#[test_case]
+fn fork_isolates_memory() {
+    let pid = spawn(FORK, &["fork"], &[]).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, 0)));
+}
+
+#[test_case]
+fn memory_syscalls() {
+    let pid = spawn(MMAP, &["mmap"], &[]).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, 0)));
+}
+
+#[test_case]
+fn signal_handlers() {
+    let pid = spawn(SIGNAL, &["signal"], &[]).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, 0)));
+}
+
+#[test_case]
+fn killed_by_signals() {
+    let pid = spawn(SIGNAL, &["signal", "spin"], &[]).unwrap();
+    signal::send(pid, 0).unwrap();
+    signal::send(pid, SIGTERM).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, signal::exit_code(SIGTERM))));
+    assert_eq!(signal::send(pid, SIGTERM), Err(Error::NoSuchProcess));
+
+    let pid = spawn(SIGNAL, &["signal", "fault", "!"], &[]).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, signal::exit_code(SIGSEGV))));
+}
+
+#[test_case]
+fn pipes() {
+    let pid = spawn(PIPE, &["pipe"], &[]).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, 0)));
+
+    // a signal interrupts a blocked read
+    let pid = spawn(PIPE, &["pipe", "read"], &[]).unwrap();
+    time::sleep(0.05);
+    signal::send(pid, SIGTERM).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, signal::exit_code(SIGTERM))));
+
+    let pid = spawn(PIPE, &["pipe", "write", "!"], &[]).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, signal::exit_code(SIGPIPE))));
+}
@@ -0,0 +1,506 @@
+//! The 8042 PS/2 controller, with the keyboard on its first port and
+//! the mouse on its second (see [`mouse`]).
+//!
+//! [`init`] sets up the controller and the keyboard by polling, with
+//! their interrupts disabled. After that the keyboard's responses
+//! arrive with its interrupt, so commands sent to it later (like
+//! [`set_leds`]) are queued, and sent one byte at a time as it
+//! acknowledges them (see [`handle_response`]).
+
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use bit_field::BitField;
+use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+
+use crate::sys::{mouse, sync::IrqSafeMutex};
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+/// Status register bits.
+const OUTPUT_FULL: usize = 0;
+const INPUT_FULL: usize = 1;
+
+/// Controller commands.
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND: u8 = 0xa7;
+const ENABLE_SECOND: u8 = 0xa8;
+const TEST_SECOND: u8 = 0xa9;
+const SELF_TEST: u8 = 0xaa;
+const TEST_FIRST: u8 = 0xab;
+const DISABLE_FIRST: u8 = 0xad;
+const ENABLE_FIRST: u8 = 0xae;
+const WRITE_SECOND: u8 = 0xd4;
+
+/// Configuration byte bits.
+const FIRST_INTERRUPT: usize = 0;
+const SECOND_INTERRUPT: usize = 1;
+const SECOND_CLOCK_DISABLED: usize = 5;
+/// Translate scancode set 2 from the keyboard to set 1.
+const TRANSLATION: usize = 6;
+
+/// Device commands and responses.
+const SET_LEDS: u8 = 0xed;
+const SCANCODE_SET: u8 = 0xf0;
+const SET_TYPEMATIC: u8 = 0xf3;
+const ENABLE_SCANNING: u8 = 0xf4;
+const DISABLE_SCANNING: u8 = 0xf5;
+const RESET: u8 = 0xff;
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+const SELF_TEST_PASSED: u8 = 0xaa;
+
+/// How often the status register is polled before giving up, which
+/// takes at least a microsecond each time.
+const TIMEOUT: usize = 1_000_000;
+/// How often a byte is resent to a device that asks for it.
+const RETRIES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The controller or device didn't respond in time.
+    Timeout,
+    /// The controller's self test returned this instead of 0x55.
+    SelfTest(u8),
+    /// Testing the port returned this error code instead of 0.
+    PortTest(PortId, u8),
+    /// The device responded with this instead of acknowledging.
+    UnexpectedResponse(u8),
+    /// The queue of commands for the device is full.
+    Busy,
+}
+
+/// One of the controller's two ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortId {
+    /// Where the keyboard is.
+    First,
+    /// The auxiliary port, where a mouse may be.
+    Second,
+}
+
+/// The keyboard LEDs, for [`set_leds`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Leds {
+    bits: u8,
+}
+
+impl Leds {
+    pub const NONE: Self = Self { bits: 0 };
+    pub const SCROLL_LOCK: Self = Self { bits: 1 };
+    pub const NUM_LOCK: Self = Self { bits: 1 << 1 };
+    pub const CAPS_LOCK: Self = Self { bits: 1 << 2 };
+
+    #[must_use]
+    pub const fn union(self, other: Self) -> Self {
+        Self {
+            bits: self.bits | other.bits,
+        }
+    }
+}
+
+impl core::ops::BitOr for Leds {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
+/// How fast a held key repeats, for [`set_typematic`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Typematic {
+    /// From 0, 30 repeats a second, to 31, 2 a second.
+    pub rate: u8,
+    /// From 0, 250 ms, to 3, 1 s before the key starts repeating.
+    pub delay: u8,
+}
+
+impl Typematic {
+    /// What keyboards start with, 10.9 repeats a second after 500 ms.
+    pub const DEFAULT: Self = Self {
+        rate: 0x0b,
+        delay: 1,
+    };
+
+    const fn as_u8(self) -> u8 {
+        ((self.delay & 0b11) << 5) | (self.rate & 0x1f)
+    }
+}
+
+/// The scancode sets a keyboard can send. With translation, which
+/// [`init`] enables, the controller turns set 2 into set 1, which
+/// [`sys::keyboard`](crate::sys::keyboard) decodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ScancodeSet {
+    Set1 = 1,
+    Set2 = 2,
+    Set3 = 3,
+}
+
+/// The controller's registers, used by polling.
+pub struct Controller {
+    data: Port<u8>,
+    status: PortReadOnly<u8>,
+    command: PortWriteOnly<u8>,
+}
+
+impl Controller {
+    /// # Safety
+    ///
+    /// Nothing else may use the controller at the same time, like the
+    /// keyboard interrupt handler.
+    #[must_use]
+    pub const unsafe fn new() -> Self {
+        Self {
+            data: Port::new(DATA_PORT),
+            status: PortReadOnly::new(STATUS_PORT),
+            command: PortWriteOnly::new(COMMAND_PORT),
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.status.read() }
+    }
+
+    fn wait_until(&mut self, bit: usize, set: bool) -> Result<(), Error> {
+        for _ in 0..TIMEOUT {
+            if self.status().get_bit(bit) == set {
+                return Ok(());
+            }
+            spin_loop();
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Read a byte from the controller or a device.
+    ///
+    /// # Errors
+    ///
+    /// If there is none in time, [`Error::Timeout`] is returned.
+    pub fn read(&mut self) -> Result<u8, Error> {
+        self.wait_until(OUTPUT_FULL, true)?;
+        Ok(unsafe { self.data.read() })
+    }
+
+    /// Discard any bytes waiting to be read.
+    pub fn flush(&mut self) {
+        while self.status().get_bit(OUTPUT_FULL) {
+            unsafe { self.data.read() };
+        }
+    }
+
+    fn write_data(&mut self, byte: u8) -> Result<(), Error> {
+        self.wait_until(INPUT_FULL, false)?;
+        unsafe { self.data.write(byte) };
+        Ok(())
+    }
+
+    /// Send `command` to the controller.
+    ///
+    /// # Errors
+    ///
+    /// If the controller isn't ready in time, [`Error::Timeout`] is
+    /// returned.
+    pub fn command(&mut self, command: u8) -> Result<(), Error> {
+        self.wait_until(INPUT_FULL, false)?;
+        unsafe { self.command.write(command) };
+        Ok(())
+    }
+
+    /// # Errors
+    ///
+    /// If the controller doesn't respond in time, [`Error::Timeout`] is
+    /// returned.
+    pub fn config(&mut self) -> Result<u8, Error> {
+        self.command(READ_CONFIG)?;
+        self.read()
+    }
+
+    /// # Errors
+    ///
+    /// If the controller isn't ready in time, [`Error::Timeout`] is
+    /// returned.
+    pub fn set_config(&mut self, config: u8) -> Result<(), Error> {
+        self.command(WRITE_CONFIG)?;
+        self.write_data(config)
+    }
+
+    /// Enable or disable `port`, and its interrupt.
+    ///
+    /// # Errors
+    ///
+    /// If the controller doesn't respond in time, [`Error::Timeout`] is
+    /// returned.
+    pub fn set_enabled(&mut self, port: PortId, enabled: bool) -> Result<(), Error> {
+        let (command, interrupt) = match (port, enabled) {
+            (PortId::First, true) => (ENABLE_FIRST, FIRST_INTERRUPT),
+            (PortId::First, false) => (DISABLE_FIRST, FIRST_INTERRUPT),
+            (PortId::Second, true) => (ENABLE_SECOND, SECOND_INTERRUPT),
+            (PortId::Second, false) => (DISABLE_SECOND, SECOND_INTERRUPT),
+        };
+        self.command(command)?;
+        let mut config = self.config()?;
+        config.set_bit(interrupt, enabled);
+        self.set_config(config)
+    }
+
+    /// Run the controller's self test, which may reset it, so the
+    /// configuration is restored afterwards.
+    ///
+    /// # Errors
+    ///
+    /// If the test fails, [`Error::SelfTest`] is returned.
+    pub fn self_test(&mut self) -> Result<(), Error> {
+        let config = self.config()?;
+        self.command(SELF_TEST)?;
+        match self.read()? {
+            0x55 => self.set_config(config),
+            result => Err(Error::SelfTest(result)),
+        }
+    }
+
+    /// Test the lines of `port`.
+    ///
+    /// # Errors
+    ///
+    /// If the test fails, [`Error::PortTest`] is returned.
+    pub fn test_port(&mut self, port: PortId) -> Result<(), Error> {
+        self.command(match port {
+            PortId::First => TEST_FIRST,
+            PortId::Second => TEST_SECOND,
+        })?;
+        match self.read()? {
+            0 => Ok(()),
+            result => Err(Error::PortTest(port, result)),
+        }
+    }
+
+    /// Whether there is a second port: its clock is only turned on by
+    /// enabling it if there is one.
+    ///
+    /// # Errors
+    ///
+    /// If the controller doesn't respond in time, [`Error::Timeout`] is
+    /// returned.
+    pub fn has_second_port(&mut self) -> Result<bool, Error> {
+        if !self.config()?.get_bit(SECOND_CLOCK_DISABLED) {
+            // it's already on, which it can't be without a second port
+            return Ok(true);
+        }
+        self.command(ENABLE_SECOND)?;
+        let found = !self.config()?.get_bit(SECOND_CLOCK_DISABLED);
+        self.command(DISABLE_SECOND)?;
+        Ok(found)
+    }
+
+    /// Send `byte` to the device on `port`, and wait for it to
+    /// acknowledge it.
+    ///
+    /// # Errors
+    ///
+    /// If it responds with something else, [`Error::UnexpectedResponse`]
+    /// is returned.
+    pub fn send(&mut self, port: PortId, byte: u8) -> Result<(), Error> {
+        for _ in 0..RETRIES {
+            if port == PortId::Second {
+                self.command(WRITE_SECOND)?;
+            }
+            self.write_data(byte)?;
+            match self.read()? {
+                ACK => return Ok(()),
+                RESEND => {}
+                response => return Err(Error::UnexpectedResponse(response)),
+            }
+        }
+        Err(Error::UnexpectedResponse(RESEND))
+    }
+
+    /// Reset the device on `port`, which then tests itself.
+    ///
+    /// # Errors
+    ///
+    /// If it doesn't pass its test, [`Error::UnexpectedResponse`] is
+    /// returned.
+    pub fn reset(&mut self, port: PortId) -> Result<(), Error> {
+        self.send(port, RESET)?;
+        match self.read()? {
+            SELF_TEST_PASSED => Ok(()),
+            response => Err(Error::UnexpectedResponse(response)),
+        }
+    }
+
+    /// Make the keyboard send scancodes from `set`.
+    ///
+    /// # Errors
+    ///
+    /// If the keyboard doesn't support it, an error is returned.
+    pub fn set_scancode_set(&mut self, set: ScancodeSet) -> Result<(), Error> {
+        self.send(PortId::First, SCANCODE_SET)?;
+        self.send(PortId::First, set as u8)
+    }
+}
+
+/// Whether there is a working second port, found by [`init`].
+static SECOND_PORT: AtomicBool = AtomicBool::new(false);
+
+/// Commands for the keyboard that haven't been acknowledged yet.
+struct Outbox {
+    bytes: [u8; 16],
+    len: usize,
+    /// How often the first byte has been sent.
+    sent: usize,
+}
+
+impl Outbox {
+    fn send_first(&mut self) {
+        if self.len > 0 {
+            self.sent += 1;
+            // the keyboard takes bytes as fast as the controller does
+            let mut controller = unsafe { Controller::new() };
+            let _ = controller.write_data(self.bytes[0]);
+        }
+    }
+
+    fn pop(&mut self) {
+        self.bytes.copy_within(1..self.len, 0);
+        self.len -= 1;
+        self.sent = 0;
+    }
+}
+
+static OUTBOX: IrqSafeMutex<Outbox> = IrqSafeMutex::new(Outbox {
+    bytes: [0; 16],
+    len: 0,
+    sent: 0,
+});
+
+/// Queue `bytes` for the keyboard, which receives them in order.
+fn queue(bytes: &[u8]) -> Result<(), Error> {
+    let mut outbox = OUTBOX.lock();
+    let idle = outbox.len == 0;
+    let start = outbox.len;
+    let end = start + bytes.len();
+    if end > outbox.bytes.len() {
+        return Err(Error::Busy);
+    }
+    outbox.bytes[start..end].copy_from_slice(bytes);
+    outbox.len = end;
+    if idle {
+        outbox.send_first();
+    }
+    Ok(())
+}
+
+/// Handle `byte` from the keyboard if it's a response to a command
+/// rather than a scancode, and send the next byte. Called from the
+/// keyboard interrupt handler.
+pub(crate) fn handle_response(byte: u8) -> bool {
+    if byte != ACK && byte != RESEND {
+        return false;
+    }
+
+    let mut outbox = OUTBOX.lock();
+    if outbox.len > 0 {
+        if byte == ACK || outbox.sent >= RETRIES {
+            outbox.pop();
+        }
+        outbox.send_first();
+    }
+    true
+}
+
+/// Turn the keyboard LEDs in `leds` on, and the others off.
+///
+/// # Errors
+///
+/// If too many commands are waiting to be sent, [`Error::Busy`] is
+/// returned.
+pub fn set_leds(leds: Leds) -> Result<(), Error> {
+    queue(&[SET_LEDS, leds.bits])
+}
+
+/// # Errors
+///
+/// If too many commands are waiting to be sent, [`Error::Busy`] is
+/// returned.
+pub fn set_typematic(typematic: Typematic) -> Result<(), Error> {
+    queue(&[SET_TYPEMATIC, typematic.as_u8()])
+}
+
+/// Whether [`init`] found a second port, for a mouse.
+pub fn has_second_port() -> bool {
+    SECOND_PORT.load(Ordering::Relaxed)
+}
+
+/// Initialize the controller and the keyboard, which sends scancode set
+/// 2, translated to set 1, and the mouse, if there is one (see
+/// [`mouse`]).
+///
+/// # Errors
+///
+/// If the controller or keyboard fails its tests or doesn't respond, an
+/// error is returned, and the keyboard may not work.
+pub fn init() -> Result<(), Error> {
+    // the keyboard interrupt handler mustn't take the responses
+    let _outbox = OUTBOX.lock();
+    let mut controller = unsafe { Controller::new() };
+
+    controller.command(DISABLE_FIRST)?;
+    controller.command(DISABLE_SECOND)?;
+    controller.flush();
+
+    let mut config = controller.config()?;
+    config.set_bit(FIRST_INTERRUPT, false);
+    config.set_bit(SECOND_INTERRUPT, false);
+    config.set_bit(TRANSLATION, true);
+    controller.set_config(config)?;
+
+    controller.self_test()?;
+    let second = controller.has_second_port()? && controller.test_port(PortId::Second).is_ok();
+    SECOND_PORT.store(second, Ordering::Relaxed);
+    controller.test_port(PortId::First)?;
+
+    controller.command(ENABLE_FIRST)?;
+    controller.reset(PortId::First)?;
+    controller.send(PortId::First, DISABLE_SCANNING)?;
+    controller.set_scancode_set(ScancodeSet::Set2)?;
+    controller.send(PortId::First, SET_TYPEMATIC)?;
+    controller.send(PortId::First, Typematic::DEFAULT.as_u8())?;
+    controller.send(PortId::First, SET_LEDS)?;
+    controller.send(PortId::First, Leds::NUM_LOCK.bits)?;
+    controller.send(PortId::First, ENABLE_SCANNING)?;
+
+    // the keyboard works without a mouse
+    let mouse = second
+        && controller.command(ENABLE_SECOND).is_ok()
+        && mouse::init_device(&mut controller).is_ok();
+    controller.flush();
+
+    controller.set_enabled(PortId::First, true)?;
+    controller.set_enabled(PortId::Second, mouse)
+}
+
+#[test_case]
+fn keyboard_commands() {
+    assert_eq!(Typematic::DEFAULT.as_u8(), 0x2b);
+
+    set_leds(Leds::CAPS_LOCK | Leds::NUM_LOCK).unwrap();
+    set_typematic(Typematic::DEFAULT).unwrap();
+    set_leds(Leds::NUM_LOCK).unwrap();
+
+    // the keyboard acknowledges each byte with an interrupt
+    for _ in 0..TIMEOUT {
+        if OUTBOX.lock().len == 0 {
+            return;
+        }
+        spin_loop();
+    }
+    panic!("keyboard didn't acknowledge the commands");
+}
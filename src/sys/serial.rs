@@ -1,27 +1,25 @@
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
 
+use crate::sys::sync::IrqSafeMutex;
+
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqSafeMutex<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSafeMutex::new(serial_port)
     };
 }
 
 #[doc(hidden)]
 pub fn print_fmt(args: fmt::Arguments) {
     use fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("printing to serial failed");
-    });
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("printing to serial failed");
 }
 
 /// Prints to the host through the serial interface.
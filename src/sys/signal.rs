@@ -0,0 +1,391 @@
+//! Signals sent to user processes.
+//!
+//! A signal sent to a process (see [`send`]) stays pending until its
+//! thread is about to return to user mode (see [`crate::sys::trap`]) and
+//! hasn't blocked it. It is then delivered (see [`deliver`]): ignored,
+//! the process is killed, or the handler set with the `sigaction` system
+//! call is called on the user stack, above a [`SignalFrame`] that the
+//! `sigreturn` system call restores once the handler returns.
+//!
+//! Faults in user code send `SIGSEGV`, `SIGILL` or `SIGFPE` (see
+//! [`force`]), which kill the process unless it handles them.
+
+use core::{mem::size_of, slice};
+use x86_64::registers::rflags::RFlags;
+
+use crate::sys::{
+    memory,
+    process::{self, Pid},
+    syscall::{self, Error},
+    trap::TrapFrame,
+    user,
+};
+
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGABRT: u32 = 6;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+/// Signals are numbered from 1 to `NSIG - 1`.
+pub const NSIG: u32 = 64;
+
+/// The user stack below the stack pointer that leaf functions may use,
+/// which signal frames are pushed below.
+const RED_ZONE: u64 = 128;
+
+/// What happens when a signal is delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Kill the process, or ignore the signal for `SIGCHLD`.
+    Default,
+    Ignore,
+    /// Call `handler(signal)`, returning to `restorer`, which has to make
+    /// a `sigreturn` system call.
+    Handler {
+        handler: u64,
+        restorer: u64,
+    },
+}
+
+/// The bit of `sig` in a set of signals.
+const fn bit(sig: u32) -> u64 {
+    1 << sig
+}
+
+/// The exit code of a process killed by `sig`, as shells report it.
+#[must_use]
+#[allow(clippy::cast_possible_wrap)]
+pub const fn exit_code(sig: u32) -> i32 {
+    128 + sig as i32
+}
+
+/// The pending and blocked signals of a process, and their actions.
+#[derive(Debug, Clone)]
+pub struct Signals {
+    pending: u64,
+    blocked: u64,
+    actions: [Action; NSIG as usize],
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Signals {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            actions: [Action::Default; NSIG as usize],
+        }
+    }
+
+    /// The signal state of a forked child: the same actions and blocked
+    /// signals, but nothing pending.
+    #[must_use]
+    pub fn fork(&self) -> Self {
+        Self {
+            pending: 0,
+            ..self.clone()
+        }
+    }
+
+    #[must_use]
+    pub const fn pending(&self) -> u64 {
+        self.pending
+    }
+
+    #[must_use]
+    pub const fn blocked(&self) -> u64 {
+        self.blocked
+    }
+
+    /// Block the signals in `mask`, except `SIGKILL`, which can't be.
+    pub fn set_blocked(&mut self, mask: u64) {
+        self.blocked = mask & !bit(SIGKILL) & !1;
+    }
+
+    #[must_use]
+    pub const fn action(&self, sig: u32) -> Action {
+        self.actions[sig as usize]
+    }
+
+    /// Set the action for `sig`, and return the previous one. Pending
+    /// signals that are now ignored are discarded.
+    ///
+    /// # Errors
+    ///
+    /// If `sig` isn't a signal, or is `SIGKILL`, whose action can't be
+    /// changed, [`Error::InvalidArgument`] is returned.
+    pub fn set_action(&mut self, sig: u32, action: Action) -> Result<Action, Error> {
+        if !(1..NSIG).contains(&sig) || sig == SIGKILL {
+            return Err(Error::InvalidArgument);
+        }
+        let old = core::mem::replace(&mut self.actions[sig as usize], action);
+        if self.is_ignored(sig) {
+            self.pending &= !bit(sig);
+        }
+        Ok(old)
+    }
+
+    /// Make `sig` pending, unless it is ignored.
+    pub fn send(&mut self, sig: u32) {
+        if !self.is_ignored(sig) {
+            self.pending |= bit(sig);
+        }
+    }
+
+    /// Make `sig` pending even if it is blocked or ignored, in which case
+    /// its action is reset, so that it kills the process. Used for faults,
+    /// which would happen again.
+    pub fn force(&mut self, sig: u32) {
+        if self.blocked & bit(sig) != 0 || self.actions[sig as usize] == Action::Ignore {
+            self.blocked &= !bit(sig);
+            self.actions[sig as usize] = Action::Default;
+        }
+        self.pending |= bit(sig);
+    }
+
+    /// Whether a signal is pending that isn't blocked, which interrupts
+    /// blocking system calls.
+    #[must_use]
+    pub const fn is_interrupted(&self) -> bool {
+        self.pending & !self.blocked != 0
+    }
+
+    /// Take the lowest pending signal that isn't blocked, with its action.
+    pub fn take(&mut self) -> Option<(u32, Action)> {
+        let deliverable = self.pending & !self.blocked;
+        if deliverable == 0 {
+            return None;
+        }
+        let sig = deliverable.trailing_zeros();
+        self.pending &= !bit(sig);
+        Some((sig, self.actions[sig as usize]))
+    }
+
+    const fn is_ignored(&self, sig: u32) -> bool {
+        match self.actions[sig as usize] {
+            Action::Default => sig == SIGCHLD,
+            Action::Ignore => true,
+            Action::Handler { .. } => false,
+        }
+    }
+}
+
+/// What a signal handler finds above its return address on the stack.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct SignalFrame {
+    /// The registers of the code the signal interrupted.
+    pub registers: TrapFrame,
+    /// The signals it had blocked.
+    pub blocked: u64,
+}
+
+/// Send `sig` to the process `pid`. Signal 0 only checks that the
+/// process exists.
+///
+/// # Errors
+///
+/// If `sig` isn't a signal, [`Error::InvalidArgument`] is returned, and
+/// if there is no such process, [`Error::NoSuchProcess`].
+pub fn send(pid: Pid, sig: u32) -> Result<(), Error> {
+    if sig >= NSIG {
+        return Err(Error::InvalidArgument);
+    }
+    let process = process::get(pid).ok_or(Error::NoSuchProcess)?;
+    if sig != 0 {
+        process.signal(sig);
+    }
+    Ok(())
+}
+
+/// Send `sig` to the calling thread's process because of a fault in its
+/// user code (see [`Signals::force`]). Threads that aren't in a process
+/// (see [`user::run`]) leave user mode instead.
+pub fn force(sig: u32) {
+    match process::current() {
+        Some(process) => process.signals().force(sig),
+        None => user::exit(exit_code(sig)),
+    }
+}
+
+/// Deliver the pending signals of the calling thread's process before it
+/// returns to user mode with `frame`. A handler is called by changing
+/// `frame`, for one signal at a time: the others are delivered once it
+/// returns. Has to be called with interrupts enabled.
+pub fn deliver(frame: &mut TrapFrame) {
+    let Some(process) = process::current() else {
+        return;
+    };
+
+    loop {
+        let mut signals = process.signals();
+        let Some((sig, action)) = signals.take() else {
+            return;
+        };
+
+        match action {
+            Action::Ignore => {}
+            Action::Default if sig == SIGCHLD => {}
+            Action::Default => {
+                drop(signals);
+                drop(process);
+                user::exit(exit_code(sig));
+            }
+            Action::Handler { handler, restorer } => {
+                let blocked = signals.blocked;
+                drop(signals);
+                if push_frame(frame, sig, handler, restorer, blocked).is_ok() {
+                    // until the handler returns
+                    process.signals().blocked |= bit(sig);
+                    return;
+                }
+                // there is no usable stack to call the handler on
+                process.signals().force(SIGSEGV);
+            }
+        }
+    }
+}
+
+/// Push a [`SignalFrame`] and the return address `restorer` onto the
+/// user stack of `frame`, and set it up to call `handler(sig)`.
+fn push_frame(
+    frame: &mut TrapFrame,
+    sig: u32,
+    handler: u64,
+    restorer: u64,
+    blocked: u64,
+) -> Result<(), Error> {
+    let size = size_of::<SignalFrame>() as u64;
+    let top = frame
+        .rsp
+        .checked_sub(RED_ZONE + size)
+        .ok_or(Error::BadAddress)?
+        & !15;
+
+    let signal_frame = SignalFrame {
+        registers: frame.clone(),
+        blocked,
+    };
+    let bytes = unsafe {
+        slice::from_raw_parts(
+            core::ptr::from_ref(&signal_frame).cast::<u8>(),
+            size_of::<SignalFrame>(),
+        )
+    };
+    syscall::user_slice_mut(top, size)?.copy_from_slice(bytes);
+    syscall::user_slice_mut(top - 8, 8)?.copy_from_slice(&restorer.to_ne_bytes());
+
+    frame.rsp = top - 8;
+    frame.rip = handler;
+    frame.rdi = sig.into();
+    // as the calling convention requires
+    frame.rflags &= !RFlags::DIRECTION_FLAG.bits();
+    Ok(())
+}
+
+/// Restore `frame` and the blocked signals from the [`SignalFrame`] at
+/// its stack pointer, once the restorer of a handler has popped the
+/// return address. Returns the restored `rax`, which the system call
+/// returns.
+///
+/// # Errors
+///
+/// If the frame isn't readable, or would return to kernel memory,
+/// [`Error::BadAddress`] is returned, and the process is killed with
+/// `SIGSEGV`.
+pub fn sigreturn(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let process = process::current().ok_or(Error::NoSuchProcess)?;
+    let saved = syscall::user_slice(frame.rsp, size_of::<SignalFrame>() as u64)
+        .map(|bytes| unsafe { bytes.as_ptr().cast::<SignalFrame>().read_unaligned() })
+        .ok()
+        .filter(|saved| saved.registers.rip < memory::USER_END);
+    let Some(saved) = saved else {
+        process.signals().force(SIGSEGV);
+        return Err(Error::BadAddress);
+    };
+
+    // only the flags user code can change are restored
+    let user_flags = RFlags::CARRY_FLAG
+        | RFlags::PARITY_FLAG
+        | RFlags::AUXILIARY_CARRY_FLAG
+        | RFlags::ZERO_FLAG
+        | RFlags::SIGN_FLAG
+        | RFlags::DIRECTION_FLAG
+        | RFlags::OVERFLOW_FLAG;
+    let mut registers = saved.registers;
+    registers.set_user_segments();
+    registers.error_code = 0;
+    registers.rflags = (registers.rflags & user_flags.bits()) | RFlags::INTERRUPT_FLAG.bits() | 0x2;
+    let rax = registers.rax;
+    *frame = registers;
+
+    process.signals().set_blocked(saved.blocked);
+    Ok(rax)
+}
+
+#[test_case]
+fn pending_and_blocked() {
+    let mut signals = Signals::new();
+    signals.send(SIGCHLD);
+    assert_eq!(signals.pending(), 0);
+
+    signals.set_blocked(bit(SIGUSR1) | bit(SIGKILL));
+    assert_eq!(signals.blocked(), bit(SIGUSR1));
+    signals.send(SIGUSR1);
+    assert!(!signals.is_interrupted());
+    signals.send(SIGTERM);
+    assert!(signals.is_interrupted());
+    assert_eq!(signals.take(), Some((SIGTERM, Action::Default)));
+    assert_eq!(signals.take(), None);
+
+    signals.set_blocked(0);
+    assert_eq!(signals.take(), Some((SIGUSR1, Action::Default)));
+}
+
+#[test_case]
+fn actions() {
+    let mut signals = Signals::new();
+    let handler = Action::Handler {
+        handler: 0x1000,
+        restorer: 0x2000,
+    };
+    assert_eq!(
+        signals.set_action(SIGKILL, Action::Ignore),
+        Err(Error::InvalidArgument)
+    );
+    assert_eq!(
+        signals.set_action(NSIG, Action::Ignore),
+        Err(Error::InvalidArgument)
+    );
+    assert_eq!(signals.set_action(SIGINT, handler), Ok(Action::Default));
+
+    signals.send(SIGINT);
+    let child = signals.fork();
+    assert_eq!(child.pending(), 0);
+    assert_eq!(child.action(SIGINT), handler);
+
+    // ignoring discards it
+    assert_eq!(signals.set_action(SIGINT, Action::Ignore), Ok(handler));
+    assert_eq!(signals.pending(), 0);
+
+    // faults can't be ignored
+    signals.set_action(SIGSEGV, Action::Ignore).unwrap();
+    signals.force(SIGSEGV);
+    assert_eq!(signals.take(), Some((SIGSEGV, Action::Default)));
+}
@@ -0,0 +1,298 @@
+//! Starting up the application processors (APs), i.e. all CPUs except
+//! the bootstrap processor (BSP) that runs [`crate::init`].
+//! [OSDev.org](https://wiki.osdev.org/Symmetric_Multiprocessing)
+
+use alloc::vec;
+use bootloader::bootinfo::MemoryRegionType;
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+use x86_64::{
+    instructions::interrupts,
+    registers::control::Cr3,
+    structures::paging::{mapper::MapToError, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    log,
+    sys::{apic, clock, gdt, idt, memory, percpu, syscall, time},
+};
+
+/// CPUs beyond this many are left alone.
+pub const MAX_CPUS: usize = 16;
+
+const STACK_SIZE: usize = 4096 * 4;
+
+/// Where the trampoline is copied to. APs start executing in real
+/// mode, so it has to be below 1 MiB, and page-aligned.
+const TRAMPOLINE: u64 = 0x8000;
+
+/// How long to wait for an AP to come online (seconds).
+const STARTUP_TIMEOUT: f64 = 0.5;
+
+const NO_CPU: u32 = u32::MAX;
+
+/// APIC IDs of the CPUs, indexed by CPU number. The BSP is CPU 0.
+#[allow(clippy::declare_interior_mutable_const)]
+static APIC_IDS: [AtomicU32; MAX_CPUS] = {
+    const UNUSED: AtomicU32 = AtomicU32::new(NO_CPU);
+    [UNUSED; MAX_CPUS]
+};
+
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+// The trampoline is copied to `TRAMPOLINE` and started there by a
+// startup IPI, in real mode. It switches to long mode, using the
+// page table of the BSP, and calls `params.entry` on `params.stack`.
+// Since it isn't executed where it's linked, addresses are computed
+// relative to `TRAMPOLINE` (hardcoded as 0x8000).
+global_asm!(
+    r#"
+    .p2align 12
+    .global aaos_ap_trampoline
+    aaos_ap_trampoline:
+    .code16
+        cli
+        cld
+        xorw %ax, %ax
+        movw %ax, %ds
+        lgdtl (0x8000 + aaos_ap_gdtr - aaos_ap_trampoline)
+        movl %cr0, %eax
+        orl $1, %eax                # protected mode
+        movl %eax, %cr0
+        ljmpl $0x08, $(0x8000 + aaos_ap_start32 - aaos_ap_trampoline)
+
+    .code32
+    aaos_ap_start32:
+        movw $0x10, %ax
+        movw %ax, %ds
+        movw %ax, %es
+        movw %ax, %ss
+        movl %cr4, %eax
+        orl $0x20, %eax             # PAE
+        movl %eax, %cr4
+        movl (0x8000 + aaos_ap_params - aaos_ap_trampoline), %eax
+        movl %eax, %cr3
+        movl $0xc0000080, %ecx      # EFER
+        rdmsr
+        orl $0x900, %eax            # long mode and no-execute
+        wrmsr
+        movl %cr0, %eax
+        orl $0x80010000, %eax       # paging and write protect
+        movl %eax, %cr0
+        ljmpl $0x18, $(0x8000 + aaos_ap_start64 - aaos_ap_trampoline)
+
+    .code64
+    aaos_ap_start64:
+        xorw %ax, %ax
+        movw %ax, %ds
+        movw %ax, %es
+        movw %ax, %ss
+        movw %ax, %fs
+        movw %ax, %gs
+        movq aaos_ap_params+8(%rip), %rsp
+        movq aaos_ap_params+24(%rip), %rdi
+        callq *aaos_ap_params+16(%rip)
+        ud2
+
+        .p2align 3
+    aaos_ap_gdt:
+        .quad 0
+        .quad 0x00cf9a000000ffff    # 32-bit code
+        .quad 0x00cf92000000ffff    # data
+        .quad 0x00af9a000000ffff    # 64-bit code
+    aaos_ap_gdtr:
+        .word aaos_ap_gdtr - aaos_ap_gdt - 1
+        .long 0x8000 + aaos_ap_gdt - aaos_ap_trampoline
+
+        .p2align 3
+    .global aaos_ap_params
+    aaos_ap_params:
+        .quad 0, 0, 0, 0
+    .global aaos_ap_trampoline_end
+    aaos_ap_trampoline_end:
+    "#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static aaos_ap_trampoline: u8;
+    static aaos_ap_params: u8;
+    static aaos_ap_trampoline_end: u8;
+}
+
+/// Handed to an AP through the trampoline.
+#[repr(C)]
+struct Params {
+    /// Physical address of the level 4 page table.
+    cr3: u64,
+    stack: u64,
+    entry: extern "C" fn(usize) -> !,
+    cpu: usize,
+}
+
+/// The number of CPUs that are online.
+#[must_use]
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// The number of the current CPU, from 0 (the BSP) to [`cpu_count`] - 1.
+#[must_use]
+pub fn current_cpu() -> usize {
+    percpu::cpu_number()
+}
+
+/// The APIC ID of an online CPU.
+#[must_use]
+pub fn apic_id(cpu: usize) -> Option<u8> {
+    if cpu >= cpu_count() {
+        return None;
+    }
+
+    let id = APIC_IDS[cpu].load(Ordering::Relaxed);
+    u8::try_from(id).ok()
+}
+
+extern "C" fn ap_main(cpu: usize) -> ! {
+    gdt::init_ap(cpu);
+    idt::init_ap();
+    syscall::init();
+    apic::enable();
+
+    ONLINE.fetch_add(1, Ordering::Release);
+    log!("CPU {} online", cpu);
+
+    // there is nothing for APs to do (yet)
+    interrupts::enable();
+    crate::hlt_loop()
+}
+
+/// Copy the trampoline to `TRAMPOLINE`, identity-mapped so that it
+/// keeps running when the AP enables paging.
+fn install_trampoline() -> Result<(), MapToError<Size4KiB>> {
+    let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(TRAMPOLINE));
+    let page = Page::containing_address(VirtAddr::new(TRAMPOLINE));
+
+    memory::with_mapper(|mapper, frame_allocator| {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {}
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    })?;
+
+    unsafe {
+        let start = core::ptr::addr_of!(aaos_ap_trampoline);
+        let len = core::ptr::addr_of!(aaos_ap_trampoline_end) as usize - start as usize;
+        let dst = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(start, dst, len);
+    }
+
+    Ok(())
+}
+
+fn remove_trampoline() {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TRAMPOLINE));
+    memory::unmap(Page::range(page, page + 1)).expect("trampoline wasn't mapped");
+}
+
+/// Start up an AP with an INIT-SIPI-SIPI sequence. Returns `false` if
+/// it didn't come online in time.
+fn start_ap(cpu: usize, apic_id: u8) -> bool {
+    let stack = vec![0_u8; STACK_SIZE].leak();
+    let stack_top = (stack.as_ptr() as u64 + STACK_SIZE as u64) & !0xf;
+
+    unsafe {
+        let offset = core::ptr::addr_of!(aaos_ap_params) as u64
+            - core::ptr::addr_of!(aaos_ap_trampoline) as u64;
+        let params = memory::phys_to_virt(PhysAddr::new(TRAMPOLINE + offset));
+        params.as_mut_ptr::<Params>().write_volatile(Params {
+            cr3: Cr3::read().0.start_address().as_u64(),
+            stack: stack_top,
+            entry: ap_main,
+            cpu,
+        });
+    }
+
+    let online = cpu_count();
+    #[allow(clippy::cast_possible_truncation)]
+    let page = (TRAMPOLINE / 4096) as u8;
+
+    apic::send_init(apic_id);
+    time::sleep(0.01);
+    for _ in 0..2 {
+        apic::send_startup(apic_id, page);
+        time::sleep(0.0002);
+        if cpu_count() > online {
+            return true;
+        }
+    }
+
+    let start = clock::uptime();
+    while cpu_count() == online && clock::uptime() - start < STARTUP_TIMEOUT {
+        time::halt();
+    }
+    cpu_count() > online
+}
+
+/// Start up all APs listed in the MADT. Must be called after
+/// [`apic::init`], with interrupts enabled.
+pub fn init() {
+    let bsp = apic::id();
+    APIC_IDS[0].store(u32::from(bsp), Ordering::Relaxed);
+
+    if apic::processors().iter().all(|&id| id == bsp) {
+        return;
+    }
+
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    let usable = matches!(
+        memory::region_type(PhysAddr::new(TRAMPOLINE)),
+        Some(MemoryRegionType::Usable | MemoryRegionType::Bootloader)
+    );
+    if !usable || cr3 >= 1 << 32 {
+        log!("SMP disabled: can't start up APs");
+        return;
+    }
+
+    if let Err(e) = install_trampoline() {
+        log!("SMP disabled: {:?}", e);
+        return;
+    }
+
+    for &apic_id in apic::processors().iter().filter(|&&id| id != bsp) {
+        let cpu = cpu_count();
+        if cpu == MAX_CPUS {
+            break;
+        }
+
+        APIC_IDS[cpu].store(u32::from(apic_id), Ordering::Relaxed);
+        if !start_ap(cpu, apic_id) {
+            APIC_IDS[cpu].store(NO_CPU, Ordering::Relaxed);
+            log!("APIC ID {} didn't come online", apic_id);
+        }
+    }
+
+    remove_trampoline();
+}
+
+#[test_case]
+fn all_cpus_online() {
+    let expected = apic::processors().len().min(MAX_CPUS);
+    assert_eq!(cpu_count(), expected);
+    assert_eq!(current_cpu(), 0);
+
+    // every CPU has its own APIC ID
+    for cpu in 0..cpu_count() {
+        let id = APIC_IDS[cpu].load(Ordering::Relaxed);
+        assert_ne!(id, NO_CPU);
+        assert!(APIC_IDS[..cpu]
+            .iter()
+            .all(|other| other.load(Ordering::Relaxed) != id));
+    }
+}
@@ -0,0 +1,37 @@
+//! Sleeping synchronization primitives. Unlike [`spin::Mutex`], a
+//! thread or task waiting for one of these doesn't occupy the CPU:
+//! threads are parked and tasks return [`Poll::Pending`] until they
+//! are woken by whoever releases the primitive, which may also be an
+//! interrupt handler.
+//!
+//! [`IrqSafeMutex`] is the exception: it spins with interrupts
+//! disabled, for data that interrupt handlers need to access.
+//!
+//! Threads and tasks can also pass messages over a [`channel`].
+//!
+//! In debug builds, a thread that would deadlock by blocking on a
+//! [`Mutex`] or on the write side of an [`RwLock`] panics instead.
+//! Only these exclusive locks taken by blocking threads are tracked:
+//! readers of an [`RwLock`] and tasks waiting in `lock_async`,
+//! `read_async` or `write_async` are not, so deadlocks involving them
+//! still hang.
+//!
+//! [`Poll::Pending`]: core::task::Poll::Pending
+
+mod channel;
+mod condvar;
+mod deadlock;
+mod irq_safe;
+mod lock_order;
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod wait_queue;
+
+pub use channel::{channel, Receiver, RecvError, SendError, Sender, TryRecvError, TrySendError};
+pub use condvar::Condvar;
+pub use irq_safe::{holding_spinlocks, IrqSafeMutex, IrqSafeMutexGuard};
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{Semaphore, SemaphorePermit};
+pub use wait_queue::WaitQueue;
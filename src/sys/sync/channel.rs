@@ -0,0 +1,264 @@
+use alloc::{collections::VecDeque, sync::Arc};
+use core::fmt;
+
+use super::{Condvar, Mutex, MutexGuard};
+
+/// Create a channel that holds up to `capacity` messages, which has to
+/// be at least 1. Senders block while it is full, so a slow receiver
+/// holds them back.
+///
+/// # Panics
+///
+/// Panics if `capacity` is 0.
+#[must_use]
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be at least 1");
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            senders: 1,
+            receiving: true,
+        }),
+        changed: Condvar::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    /// Cleared once the receiver is dropped.
+    receiving: bool,
+}
+
+impl<T> State<T> {
+    fn is_full(&self) -> bool {
+        self.queue.len() == self.capacity
+    }
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    /// Notified whenever a message is sent or received, or an end is
+    /// dropped.
+    changed: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn push(&self, mut state: MutexGuard<State<T>>, message: T) {
+        state.queue.push_back(message);
+        drop(state);
+        self.changed.notify_all();
+    }
+
+    fn pop(&self, mut state: MutexGuard<State<T>>) -> Option<T> {
+        let message = state.queue.pop_front();
+        drop(state);
+        if message.is_some() {
+            self.changed.notify_all();
+        }
+        message
+    }
+}
+
+/// The error sending to a channel whose receiver has been dropped, with
+/// the message that wasn't sent.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is full.
+    Full(T),
+    /// The receiver has been dropped.
+    Closed(T),
+}
+
+/// The error receiving from a channel that is empty, and whose senders
+/// have all been dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// The channel is empty, and the senders have all been dropped.
+    Closed,
+}
+
+/// The sending end of a [`channel`], which can be cloned to send from
+/// several threads or tasks.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send `message`, blocking while the channel is full.
+    ///
+    /// # Errors
+    ///
+    /// If the receiver has been dropped, the message is returned.
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        let state = self
+            .shared
+            .changed
+            .wait_while(self.shared.state.lock(), |state| {
+                state.receiving && state.is_full()
+            });
+        if !state.receiving {
+            return Err(SendError(message));
+        }
+        self.shared.push(state, message);
+        Ok(())
+    }
+
+    /// Like [`Sender::send`], but for tasks.
+    ///
+    /// # Errors
+    ///
+    /// If the receiver has been dropped, the message is returned.
+    #[allow(clippy::future_not_send)]
+    pub async fn send_async(&self, message: T) -> Result<(), SendError<T>> {
+        let mut state = self.shared.state.lock_async().await;
+        while state.receiving && state.is_full() {
+            state = self.shared.changed.wait_async(state).await;
+        }
+        if !state.receiving {
+            return Err(SendError(message));
+        }
+        self.shared.push(state, message);
+        Ok(())
+    }
+
+    /// Send `message` if there is room for it.
+    ///
+    /// # Errors
+    ///
+    /// If the channel is full or the receiver has been dropped, the
+    /// message is returned.
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        let state = self.shared.state.lock();
+        if !state.receiving {
+            return Err(TrySendError::Closed(message));
+        }
+        if state.is_full() {
+            return Err(TrySendError::Full(message));
+        }
+        self.shared.push(state, message);
+        Ok(())
+    }
+
+    /// Whether the receiver has been dropped.
+    #[must_use]
+    pub fn is_closed(&self) -> bool {
+        !self.shared.state.lock().receiving
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().senders -= 1;
+        self.shared.changed.notify_all();
+    }
+}
+
+/// The receiving end of a [`channel`]. Messages are received in the
+/// order they were sent in.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receive a message, blocking while the channel is empty.
+    ///
+    /// # Errors
+    ///
+    /// Once the channel is empty and the senders have all been dropped,
+    /// [`RecvError`] is returned.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let state = self
+            .shared
+            .changed
+            .wait_while(self.shared.state.lock(), |state| {
+                state.queue.is_empty() && state.senders > 0
+            });
+        self.shared.pop(state).ok_or(RecvError)
+    }
+
+    /// Like [`Receiver::recv`], but for tasks.
+    ///
+    /// # Errors
+    ///
+    /// Once the channel is empty and the senders have all been dropped,
+    /// [`RecvError`] is returned.
+    #[allow(clippy::future_not_send)]
+    pub async fn recv_async(&self) -> Result<T, RecvError> {
+        let mut state = self.shared.state.lock_async().await;
+        while state.queue.is_empty() && state.senders > 0 {
+            state = self.shared.changed.wait_async(state).await;
+        }
+        self.shared.pop(state).ok_or(RecvError)
+    }
+
+    /// Receive a message if there is one.
+    ///
+    /// # Errors
+    ///
+    /// If the channel is empty, [`TryRecvError::Empty`] is returned, or
+    /// [`TryRecvError::Closed`] once the senders have all been dropped.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let state = self.shared.state.lock();
+        let closed = state.senders == 0;
+        self.shared.pop(state).ok_or(if closed {
+            TryRecvError::Closed
+        } else {
+            TryRecvError::Empty
+        })
+    }
+
+    /// The number of messages waiting to be received.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().queue.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // the messages are dropped now, not with the last sender
+        let messages = {
+            let mut state = self.shared.state.lock();
+            state.receiving = false;
+            core::mem::take(&mut state.queue)
+        };
+        self.shared.changed.notify_all();
+        drop(messages);
+    }
+}
@@ -0,0 +1,70 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{MutexGuard, WaitQueue};
+
+/// A condition variable, for waiting until the data protected by a
+/// [`super::Mutex`] changes.
+#[derive(Default)]
+pub struct Condvar {
+    /// Incremented on every notification, so that waiters can tell
+    /// whether they have been notified since they started waiting.
+    generation: AtomicUsize,
+    queue: WaitQueue,
+}
+
+impl Condvar {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            generation: AtomicUsize::new(0),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// Unlock the mutex and block until notified, then lock it again.
+    /// Like all condition variables, this may wake up spuriously.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let generation = self.generation.load(Ordering::Acquire);
+        let mutex = guard.mutex;
+        drop(guard);
+
+        self.queue
+            .wait_until(|| self.generation.load(Ordering::Acquire) != generation);
+        mutex.lock()
+    }
+
+    /// Block until `condition` returns `false`.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Like [`Condvar::wait`], but for tasks.
+    #[allow(clippy::future_not_send)]
+    pub async fn wait_async<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let generation = self.generation.load(Ordering::Acquire);
+        let mutex = guard.mutex;
+        drop(guard);
+
+        self.queue
+            .wait_until_async(|| self.generation.load(Ordering::Acquire) != generation)
+            .await;
+        mutex.lock_async().await
+    }
+
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.queue.notify_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.queue.notify_all();
+    }
+}
@@ -0,0 +1,91 @@
+//! Deadlock detection for debug builds: keeps track of which thread
+//! holds each lock and which lock each blocked thread is waiting for,
+//! and panics instead of blocking if that would close a cycle.
+
+#[cfg(debug_assertions)]
+mod imp {
+    use alloc::collections::BTreeMap;
+    use spin::Mutex;
+    use x86_64::instructions::interrupts;
+
+    use crate::sys::thread::{self, ThreadId};
+
+    struct Graph {
+        /// Lock address -> holder.
+        owners: BTreeMap<usize, ThreadId>,
+        /// Blocked thread -> address of the lock it's waiting for.
+        waiting: BTreeMap<ThreadId, usize>,
+    }
+
+    static GRAPH: Mutex<Graph> = Mutex::new(Graph {
+        owners: BTreeMap::new(),
+        waiting: BTreeMap::new(),
+    });
+
+    fn current() -> Option<ThreadId> {
+        thread::is_initialized().then(thread::current)
+    }
+
+    pub fn acquired(lock: usize) {
+        if let Some(current) = current() {
+            interrupts::without_interrupts(|| GRAPH.lock().owners.insert(lock, current));
+        }
+    }
+
+    pub fn released(lock: usize) {
+        interrupts::without_interrupts(|| GRAPH.lock().owners.remove(&lock));
+    }
+
+    pub fn wait_for(lock: usize) {
+        let Some(current) = current() else {
+            return;
+        };
+
+        interrupts::without_interrupts(|| {
+            let mut graph = GRAPH.lock();
+
+            // follow the chain of lock holders that are themselves
+            // waiting for a lock
+            let mut next = lock;
+            for _ in 0..=graph.waiting.len() {
+                let owner = match graph.owners.get(&next) {
+                    Some(owner) => *owner,
+                    None => break,
+                };
+                if owner == current {
+                    drop(graph);
+                    panic!("deadlock: {current:?} would wait for a lock it holds");
+                }
+                next = match graph.waiting.get(&owner) {
+                    Some(lock) => *lock,
+                    None => break,
+                };
+            }
+
+            graph.waiting.insert(current, lock);
+        });
+    }
+
+    pub fn done_waiting() {
+        if let Some(current) = current() {
+            interrupts::without_interrupts(|| GRAPH.lock().waiting.remove(&current));
+        }
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod imp {
+    #[inline]
+    pub const fn acquired(_lock: usize) {}
+
+    #[inline]
+    pub const fn released(_lock: usize) {}
+
+    #[inline]
+    pub const fn wait_for(_lock: usize) {}
+
+    #[inline]
+    pub const fn done_waiting() {}
+}
+
+pub use imp::*;
@@ -0,0 +1,193 @@
+use core::{
+    cell::Cell,
+    fmt,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+use x86_64::instructions::interrupts;
+
+use super::lock_order;
+
+percpu! {
+    /// How many [`IrqSafeMutexGuard`]s the CPU holds.
+    static DEPTH: Cell<usize> = Cell::new(0);
+    /// Whether interrupts were enabled before the first guard was taken.
+    static RESTORE: Cell<bool> = Cell::new(false);
+}
+
+/// Disable interrupts, remembering whether they were enabled if this
+/// is the outermost call. Each call must be matched by [`pop_off`].
+fn push_off() {
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    let depth = DEPTH.get();
+    if depth.get() == 0 {
+        RESTORE.with(|restore| restore.set(enabled));
+    }
+    depth.set(depth.get() + 1);
+}
+
+/// Undo a [`push_off`], enabling interrupts again once the outermost
+/// one has been undone (if they were enabled before). Unlike
+/// [`interrupts::without_interrupts`], this works even if guards are
+/// dropped in a different order than they were taken.
+fn pop_off() {
+    let depth = DEPTH.get();
+    depth.set(depth.get() - 1);
+    if depth.get() == 0 && RESTORE.with(Cell::get) {
+        interrupts::enable();
+    }
+}
+
+/// Whether the current CPU holds any [`IrqSafeMutex`]. Nothing that
+/// might block or switch threads may be done while it does.
+#[must_use]
+pub fn holding_spinlocks() -> bool {
+    DEPTH.with(|depth| depth.get() != 0)
+}
+
+/// A spinlock that disables interrupts for as long as it's locked, so
+/// that it can be shared with interrupt handlers without deadlocking.
+///
+/// In debug builds, locking a mutex that is already held by the
+/// current code, or locking two mutexes in the opposite order of
+/// some earlier occasion, panics.
+#[allow(clippy::module_name_repetitions)]
+pub struct IrqSafeMutex<T: ?Sized> {
+    inner: spin::Mutex<T>,
+}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSafeMutex<T> {
+    fn key(&self) -> usize {
+        core::ptr::from_ref(self).cast::<()>() as usize
+    }
+
+    /// Disable interrupts and lock the mutex, spinning while it is locked.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if the mutex is already locked by the
+    /// current code, or if this violates the order in which locks
+    /// have been taken before.
+    pub fn lock(&self) -> IrqSafeMutexGuard<T> {
+        push_off();
+        lock_order::acquire(self.key(), core::any::type_name::<T>());
+
+        IrqSafeMutexGuard {
+            mutex: self,
+            guard: ManuallyDrop::new(self.inner.lock()),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<T>> {
+        push_off();
+        let Some(guard) = self.inner.try_lock() else {
+            pop_off();
+            return None;
+        };
+        lock_order::acquire(self.key(), core::any::type_name::<T>());
+        Some(IrqSafeMutexGuard {
+            mutex: self,
+            guard: ManuallyDrop::new(guard),
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeMutex<T> {
+    fn drop(&mut self) {
+        // the address may be reused by an unrelated lock
+        lock_order::forget(self.key());
+    }
+}
+
+impl<T: Default> Default for IrqSafeMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqSafeMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f
+                .debug_struct("IrqSafeMutex")
+                .field("data", &&*guard)
+                .finish(),
+            None => f.write_str("IrqSafeMutex { <locked> }"),
+        }
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+pub struct IrqSafeMutexGuard<'a, T: ?Sized> {
+    mutex: &'a IrqSafeMutex<T>,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+}
+
+impl<T: ?Sized> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // the lock has to be released before interrupts are enabled
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        lock_order::release(self.mutex.key());
+        pop_off();
+    }
+}
+
+#[test_case]
+fn nested_guards_restore_interrupts() {
+    static A: IrqSafeMutex<()> = IrqSafeMutex::new(());
+    static B: IrqSafeMutex<()> = IrqSafeMutex::new(());
+
+    assert!(interrupts::are_enabled());
+    let a = A.lock();
+    assert!(!interrupts::are_enabled());
+    let b = B.lock();
+    assert!(B.try_lock().is_none());
+
+    // dropped out of order, interrupts stay disabled until both are gone
+    drop(a);
+    assert!(!interrupts::are_enabled());
+    drop(b);
+    assert!(interrupts::are_enabled());
+    assert!(!holding_spinlocks());
+}
+
+#[test_case]
+fn interrupts_stay_disabled() {
+    static A: IrqSafeMutex<()> = IrqSafeMutex::new(());
+
+    interrupts::without_interrupts(|| {
+        drop(A.lock());
+        assert!(!interrupts::are_enabled());
+    });
+}
@@ -0,0 +1,141 @@
+//! Lock-ordering and reentrancy checks for [`super::IrqSafeMutex`] in
+//! debug builds. Every time a lock is taken while others are held,
+//! the pairs are recorded, and taking two locks in the opposite order
+//! of an earlier occasion panics, since the two code paths could
+//! deadlock each other.
+//!
+//! This bookkeeping is always done with interrupts disabled, so the
+//! locks held by each CPU can't change under it.
+
+#[cfg(debug_assertions)]
+mod imp {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use spin::Mutex;
+    use x86_64::instructions::interrupts;
+
+    const MAX_HELD: usize = 16;
+    const MAX_EDGES: usize = 128;
+
+    /// A lock address, and the name of the type it protects.
+    type Lock = (usize, &'static str);
+
+    struct Held {
+        locks: [Lock; MAX_HELD],
+        len: usize,
+    }
+
+    /// Pairs of locks `(a, b)` where `b` has been taken while
+    /// holding `a`. Once full, new pairs are no longer checked.
+    struct Edges {
+        edges: [(Lock, Lock); MAX_EDGES],
+        len: usize,
+    }
+
+    percpu! {
+        /// The locks held by the CPU.
+        static HELD: Mutex<Held> = Mutex::new(Held {
+            locks: [(0, ""); MAX_HELD],
+            len: 0,
+        });
+    }
+
+    static EDGES: Mutex<Edges> = Mutex::new(Edges {
+        edges: [((0, ""), (0, "")); MAX_EDGES],
+        len: 0,
+    });
+
+    /// Only the first violation is reported, as the panic handler
+    /// will likely take some of the locks involved.
+    static REPORTED: AtomicBool = AtomicBool::new(false);
+
+    // `assert!` would have to assert that it *was* reported before
+    #[allow(clippy::manual_assert)]
+    fn report(args: core::fmt::Arguments) {
+        if !REPORTED.swap(true, Ordering::Relaxed) {
+            panic!("{args}");
+        }
+    }
+
+    pub fn acquire(key: usize, name: &'static str) {
+        let cpu_held = HELD.get();
+        let mut held = cpu_held.lock();
+        let held_locks = &held.locks[..held.len];
+
+        if held_locks.iter().any(|&(k, _)| k == key) {
+            drop(held);
+            report(format_args!(
+                "IrqSafeMutex<{name}> locked again while already held"
+            ));
+            return;
+        }
+
+        let mut edges = EDGES.lock();
+        for &(outer, outer_name) in held_locks {
+            let recorded = &edges.edges[..edges.len];
+            if recorded.iter().any(|&(a, b)| a.0 == key && b.0 == outer) {
+                drop((held, edges));
+                report(format_args!(
+                    "lock order violation: IrqSafeMutex<{name}> locked while holding \
+                     IrqSafeMutex<{outer_name}>, but earlier the other way round"
+                ));
+                return;
+            }
+
+            let known = recorded.iter().any(|&(a, b)| a.0 == outer && b.0 == key);
+            if !known && edges.len < MAX_EDGES {
+                let len = edges.len;
+                edges.edges[len] = ((outer, outer_name), (key, name));
+                edges.len += 1;
+            }
+        }
+        drop(edges);
+
+        let len = held.len;
+        assert!(len < MAX_HELD, "too many IrqSafeMutexes held at once");
+        held.locks[len] = (key, name);
+        held.len += 1;
+    }
+
+    pub fn release(key: usize) {
+        let cpu_held = HELD.get();
+        let mut held = cpu_held.lock();
+        let len = held.len;
+        if let Some(i) = held.locks[..len].iter().rposition(|&(k, _)| k == key) {
+            held.locks.copy_within(i + 1..len, i);
+            held.len -= 1;
+        }
+    }
+
+    /// Unlike the others, this is called by a dropped mutex, possibly
+    /// with interrupts enabled.
+    pub fn forget(key: usize) {
+        interrupts::without_interrupts(|| {
+            let mut edges = EDGES.lock();
+            let mut i = 0;
+            while i < edges.len {
+                let (a, b) = edges.edges[i];
+                if a.0 == key || b.0 == key {
+                    let last = edges.len - 1;
+                    edges.edges.swap(i, last);
+                    edges.len -= 1;
+                } else {
+                    i += 1;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod imp {
+    #[inline]
+    pub const fn acquire(_key: usize, _name: &'static str) {}
+
+    #[inline]
+    pub const fn release(_key: usize) {}
+
+    #[inline]
+    pub const fn forget(_key: usize) {}
+}
+
+pub use imp::*;
@@ -0,0 +1,127 @@
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::{deadlock, WaitQueue};
+
+/// A mutual exclusion lock that blocks waiting threads (or suspends
+/// waiting tasks) rather than spinning.
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    queue: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            queue: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    fn key(&self) -> usize {
+        core::ptr::from_ref(self).cast::<()>() as usize
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn guard(&self) -> MutexGuard<T> {
+        deadlock::acquired(self.key());
+        MutexGuard { mutex: self }
+    }
+
+    /// Lock the mutex, blocking the current thread until it is available.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if this would deadlock, e.g. because
+    /// the current thread already holds the lock.
+    pub fn lock(&self) -> MutexGuard<T> {
+        if !self.try_acquire() {
+            deadlock::wait_for(self.key());
+            self.queue.wait_until(|| self.try_acquire());
+            deadlock::done_waiting();
+        }
+
+        self.guard()
+    }
+
+    /// Lock the mutex from a task, without blocking the thread.
+    #[allow(clippy::future_not_send)]
+    pub async fn lock_async(&self) -> MutexGuard<'_, T> {
+        self.queue.wait_until_async(|| self.try_acquire()).await;
+        self.guard()
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        self.try_acquire().then(|| self.guard())
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.write_str("Mutex { <locked> }"),
+        }
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+pub struct MutexGuard<'a, T: ?Sized> {
+    pub(super) mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        deadlock::released(self.mutex.key());
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.queue.notify_one();
+    }
+}
@@ -0,0 +1,165 @@
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::{deadlock, WaitQueue};
+
+/// Set in the state while a writer holds the lock; otherwise the
+/// state is the number of readers.
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+/// A reader-writer lock that blocks waiting threads (or suspends
+/// waiting tasks) rather than spinning.
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    queue: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            queue: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    fn key(&self) -> usize {
+        core::ptr::from_ref(self).cast::<()>() as usize
+    }
+
+    fn try_acquire_read(&self) -> bool {
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                (state & WRITER == 0).then(|| state + 1)
+            })
+            .is_ok()
+    }
+
+    fn try_acquire_write(&self) -> bool {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn write_guard(&self) -> RwLockWriteGuard<T> {
+        deadlock::acquired(self.key());
+        RwLockWriteGuard { lock: self }
+    }
+
+    /// Lock for shared reading, blocking while a writer holds the lock.
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        self.queue.wait_until(|| self.try_acquire_read());
+        RwLockReadGuard { lock: self }
+    }
+
+    /// Lock for exclusive writing, blocking while anyone holds the lock.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if this would deadlock, e.g. because
+    /// the current thread already holds the write lock.
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        if !self.try_acquire_write() {
+            deadlock::wait_for(self.key());
+            self.queue.wait_until(|| self.try_acquire_write());
+            deadlock::done_waiting();
+        }
+
+        self.write_guard()
+    }
+
+    #[allow(clippy::future_not_send)]
+    pub async fn read_async(&self) -> RwLockReadGuard<'_, T> {
+        self.queue
+            .wait_until_async(|| self.try_acquire_read())
+            .await;
+        RwLockReadGuard { lock: self }
+    }
+
+    #[allow(clippy::future_not_send)]
+    pub async fn write_async(&self) -> RwLockWriteGuard<'_, T> {
+        self.queue
+            .wait_until_async(|| self.try_acquire_write())
+            .await;
+        self.write_guard()
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        self.try_acquire_read()
+            .then(|| RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        self.try_acquire_write().then(|| self.write_guard())
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            // last reader, so a writer may proceed
+            self.lock.queue.notify_all();
+        }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        deadlock::released(self.lock.key());
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.queue.notify_all();
+    }
+}
@@ -0,0 +1,79 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::WaitQueue;
+
+/// A counting semaphore. Permits can be added from interrupt
+/// handlers, making it a convenient way for them to wake a thread.
+#[derive(Default)]
+pub struct Semaphore {
+    permits: AtomicUsize,
+    queue: WaitQueue,
+}
+
+impl Semaphore {
+    #[must_use]
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            permits: AtomicUsize::new(permits),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    fn try_take(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Take a permit, blocking the current thread until one is available.
+    pub fn acquire(&self) -> SemaphorePermit {
+        self.queue.wait_until(|| self.try_take());
+        SemaphorePermit { semaphore: self }
+    }
+
+    /// Take a permit from a task, without blocking the thread.
+    pub async fn acquire_async(&self) -> SemaphorePermit<'_> {
+        self.queue.wait_until_async(|| self.try_take()).await;
+        SemaphorePermit { semaphore: self }
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit> {
+        self.try_take().then(|| SemaphorePermit { semaphore: self })
+    }
+
+    /// Add permits, waking as many waiters.
+    pub fn add_permits(&self, permits: usize) {
+        self.permits.fetch_add(permits, Ordering::Release);
+        for _ in 0..permits {
+            if !self.queue.notify_one() {
+                break;
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+/// A permit taken from a [`Semaphore`], which is returned when dropped.
+#[allow(clippy::module_name_repetitions)]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl SemaphorePermit<'_> {
+    /// Consume the permit without returning it to the semaphore.
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
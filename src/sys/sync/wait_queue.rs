@@ -0,0 +1,163 @@
+use super::IrqSafeMutex;
+use crate::sys::thread::{self, ThreadId};
+use alloc::collections::VecDeque;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+enum Waiter {
+    Thread(ThreadId),
+    Task(Waker),
+}
+
+impl Waiter {
+    fn wake(self) {
+        match self {
+            Self::Thread(id) => thread::unpark(id),
+            Self::Task(waker) => waker.wake(),
+        }
+    }
+}
+
+/// A queue of threads and tasks waiting for some condition, in the
+/// order they started waiting.
+#[derive(Default)]
+pub struct WaitQueue {
+    waiters: IrqSafeMutex<VecDeque<(u64, Waiter)>>,
+}
+
+impl WaitQueue {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqSafeMutex::new(VecDeque::new()),
+        }
+    }
+
+    fn next_key() -> u64 {
+        static NEXT_KEY: AtomicU64 = AtomicU64::new(0);
+        NEXT_KEY.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn register(&self, key: u64, waiter: Waiter) {
+        let mut waiters = self.waiters.lock();
+        match waiters.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, existing)) => *existing = waiter,
+            None => waiters.push_back((key, waiter)),
+        }
+    }
+
+    fn deregister(&self, key: u64) {
+        self.waiters.lock().retain(|(k, _)| *k != key);
+    }
+
+    /// Block the current thread until `condition` returns `true`. The
+    /// condition is checked again every time the thread is woken by
+    /// [`WaitQueue::notify_one`] or [`WaitQueue::notify_all`].
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        if condition() {
+            return;
+        }
+
+        if !thread::is_initialized() {
+            // there is nothing to block, so just spin
+            while !condition() {
+                core::hint::spin_loop();
+            }
+            return;
+        }
+
+        let key = Self::next_key();
+        let current = thread::current();
+        loop {
+            self.register(key, Waiter::Thread(current));
+
+            // check again, in case the condition changed just before
+            // registering
+            if condition() {
+                break;
+            }
+
+            thread::park();
+
+            if condition() {
+                break;
+            }
+        }
+        self.deregister(key);
+    }
+
+    /// Wait in a task until `condition` returns `true`. The condition
+    /// is checked again every time the task is woken by
+    /// [`WaitQueue::notify_one`] or [`WaitQueue::notify_all`].
+    pub const fn wait_until_async<F>(&self, condition: F) -> WaitUntil<'_, F>
+    where
+        F: FnMut() -> bool + Unpin,
+    {
+        WaitUntil {
+            queue: self,
+            condition,
+            key: None,
+        }
+    }
+
+    /// Wake the longest waiting thread or task. Returns `false` if
+    /// there was none.
+    pub fn notify_one(&self) -> bool {
+        let waiter = self.waiters.lock().pop_front();
+        waiter.map(|(_, waiter)| waiter.wake()).is_some()
+    }
+
+    /// Wake all waiting threads and tasks. Returns how many there were.
+    pub fn notify_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        let count = waiters.len();
+        for (_, waiter) in waiters {
+            waiter.wake();
+        }
+        count
+    }
+
+    /// Whether no thread or task is waiting.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
+
+/// Future returned by [`WaitQueue::wait_until_async`].
+pub struct WaitUntil<'a, F> {
+    queue: &'a WaitQueue,
+    condition: F,
+    key: Option<u64>,
+}
+
+impl<F: FnMut() -> bool + Unpin> Future for WaitUntil<'_, F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if (self.condition)() {
+            return Poll::Ready(());
+        }
+
+        let key = *self.key.get_or_insert_with(WaitQueue::next_key);
+        self.queue.register(key, Waiter::Task(cx.waker().clone()));
+
+        if (self.condition)() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<F> Drop for WaitUntil<'_, F> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            self.queue.deregister(key);
+        }
+    }
+}
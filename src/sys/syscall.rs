@@ -0,0 +1,557 @@
+//! System calls, made from user mode with `syscall`.
+//!
+//! Like on Linux, the system call number is passed in `rax` and the
+//! arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`. The result
+//! is returned in `rax`, with errors as negative error numbers.
+
+use alloc::sync::Arc;
+use core::{arch::global_asm, mem::size_of};
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::{page::PageRange, PageTableFlags},
+    VirtAddr,
+};
+
+use crate::sys::{
+    file::{Console, File, MemoryFile},
+    gdt, initrd, memory, pipe,
+    process::{self, Pid},
+    signal::{self, Action},
+    trap::TrapFrame,
+    user::{self, AddressSpace},
+    vma::Backing,
+};
+
+pub const EXIT: u64 = 0;
+pub const WRITE: u64 = 1;
+pub const CLOSE: u64 = 2;
+pub const GETPID: u64 = 3;
+pub const WAIT: u64 = 4;
+pub const FORK: u64 = 5;
+pub const MMAP: u64 = 6;
+pub const MUNMAP: u64 = 7;
+pub const MPROTECT: u64 = 8;
+pub const BRK: u64 = 9;
+pub const KILL: u64 = 10;
+pub const SIGACTION: u64 = 11;
+pub const SIGPROCMASK: u64 = 12;
+pub const SIGRETURN: u64 = 13;
+pub const READ: u64 = 14;
+pub const PIPE: u64 = 15;
+pub const OPEN: u64 = 16;
+pub const READDIR: u64 = 17;
+
+// `mmap` and `mprotect` flags, as on Linux. Mapped memory is always
+// readable.
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+// `sigaction` handlers and `sigprocmask` operations, as on Linux.
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+// Interrupts are disabled on entry (see `init`), so nothing can run
+// on the user stack or find the stacks half switched. The frame pushed
+// on the kernel stack is a `TrapFrame`, without the segments, which
+// `syscall` doesn't save.
+//
+// User code may have reset the GS base (see `crate::sys::trap`), so
+// the entry swaps in the kernel GS base to find the kernel stack, and
+// once the registers are saved, makes them both the kernel's again.
+global_asm!(
+    r#"
+    .global aaos_syscall_entry
+    aaos_syscall_entry:
+        swapgs
+        mov gs:[24], rsp
+        mov rsp, gs:[16]
+        push 0
+        push qword ptr gs:[24]
+        push r11
+        push 0
+        push rcx
+        push 0
+        push rax
+        push rbx
+        push rcx
+        push rdx
+        push rsi
+        push rdi
+        push rbp
+        push r8
+        push r9
+        push r10
+        push r11
+        push r12
+        push r13
+        push r14
+        push r15
+        mov ecx, 0xc0000101 # IA32_GS_BASE
+        rdmsr
+        mov ecx, 0xc0000102 # IA32_KERNEL_GS_BASE
+        wrmsr
+        mov rdi, rsp
+        mov rbp, rsp
+        and rsp, -16
+        call aaos_syscall_handler
+        mov rsp, rbp
+        jmp aaos_return_to_user
+    "#
+);
+
+extern "C" {
+    fn aaos_syscall_entry();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// There is no system call with that number.
+    NoSuchSyscall,
+    /// A pointer argument doesn't point to accessible user memory.
+    BadAddress,
+    BadFileDescriptor,
+    InvalidArgument,
+    TooManyFiles,
+    /// There is no such child process to wait for.
+    NoChild,
+    /// The caller isn't a process.
+    NoSuchProcess,
+    OutOfMemory,
+    /// The file can't be mapped into memory.
+    NoDevice,
+    /// A signal was sent to the caller while it was blocked.
+    Interrupted,
+    /// The reading end of the pipe has been closed.
+    BrokenPipe,
+    /// There is no file with that name.
+    NotFound,
+}
+
+impl Error {
+    /// The (negative) Linux error number.
+    #[must_use]
+    pub const fn errno(self) -> i64 {
+        match self {
+            Self::NoSuchSyscall => -38,
+            Self::BadAddress => -14,
+            Self::BadFileDescriptor => -9,
+            Self::InvalidArgument => -22,
+            Self::TooManyFiles => -24,
+            Self::NoChild => -10,
+            Self::NoSuchProcess => -3,
+            Self::OutOfMemory => -12,
+            Self::NoDevice => -19,
+            Self::Interrupted => -4,
+            Self::BrokenPipe => -32,
+            Self::NotFound => -2,
+        }
+    }
+}
+
+type Handler = fn(&mut TrapFrame) -> Result<u64, Error>;
+
+/// Indexed by system call number.
+static SYSCALLS: [Handler; 18] = [
+    sys_exit,
+    sys_write,
+    sys_close,
+    sys_getpid,
+    sys_wait,
+    sys_fork,
+    sys_mmap,
+    sys_munmap,
+    sys_mprotect,
+    sys_brk,
+    sys_kill,
+    sys_sigaction,
+    sys_sigprocmask,
+    sys_sigreturn,
+    sys_read,
+    sys_pipe,
+    sys_open,
+    sys_readdir,
+];
+
+#[no_mangle]
+extern "C" fn aaos_syscall_handler(frame: &mut TrapFrame) {
+    frame.set_user_segments();
+    interrupts::enable();
+
+    let result = usize::try_from(frame.rax)
+        .ok()
+        .and_then(|number| SYSCALLS.get(number))
+        .map_or(Err(Error::NoSuchSyscall), |handler| handler(frame));
+
+    #[allow(clippy::cast_sign_loss)]
+    let rax = match result {
+        Ok(value) => value,
+        Err(e) => e.errno() as u64,
+    };
+    frame.rax = rax;
+    signal::deliver(frame);
+
+    // until `sysretq`, which is already back on the user stack
+    interrupts::disable();
+}
+
+/// Whether the `len` bytes at `addr` are user memory of the calling
+/// thread (and writable, if `write` is set). The memory of a process is
+/// allocated as the kernel accesses it, like when user code does.
+fn is_accessible(addr: VirtAddr, len: u64, write: bool) -> bool {
+    process::current().map_or_else(
+        || memory::is_user_accessible(addr, len, write),
+        |process| {
+            process
+                .space()
+                .as_ref()
+                .map_or(false, |space| space.is_accessible(addr, len, write))
+        },
+    )
+}
+
+/// The `len` bytes of user memory at `ptr`.
+///
+/// # Errors
+///
+/// Unless they are all accessible from user mode, [`Error::BadAddress`]
+/// is returned.
+pub(crate) fn user_slice<'a>(ptr: u64, len: u64) -> Result<&'a [u8], Error> {
+    let addr = VirtAddr::try_new(ptr).map_err(|_| Error::BadAddress)?;
+    if !is_accessible(addr, len, false) {
+        return Err(Error::BadAddress);
+    }
+    let len = usize::try_from(len).map_err(|_| Error::InvalidArgument)?;
+    Ok(unsafe { core::slice::from_raw_parts(addr.as_ptr(), len) })
+}
+
+/// Like [`user_slice`], but the memory also has to be writable.
+pub(crate) fn user_slice_mut<'a>(ptr: u64, len: u64) -> Result<&'a mut [u8], Error> {
+    let addr = VirtAddr::try_new(ptr).map_err(|_| Error::BadAddress)?;
+    if !is_accessible(addr, len, true) {
+        return Err(Error::BadAddress);
+    }
+    let len = usize::try_from(len).map_err(|_| Error::InvalidArgument)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(addr.as_mut_ptr(), len) })
+}
+
+/// The file open as `fd` in the calling process. Code that doesn't run
+/// in a process (see [`user::run`]) can write to the console.
+fn file(fd: u64) -> Result<Arc<dyn File>, Error> {
+    let fd = usize::try_from(fd).map_err(|_| Error::BadFileDescriptor)?;
+    match process::current() {
+        Some(process) => process.files().get(fd),
+        None if fd == 1 || fd == 2 => Ok(Arc::new(Console)),
+        None => Err(Error::BadFileDescriptor),
+    }
+}
+
+/// `exit(code)`: leave user mode, see [`user::enter`].
+fn sys_exit(frame: &mut TrapFrame) -> Result<u64, Error> {
+    #[allow(clippy::cast_possible_truncation)]
+    user::exit(frame.rdi as i32)
+}
+
+/// `write(fd, buf, len)`. Writing to a pipe without a reader also
+/// sends the caller `SIGPIPE`.
+fn sys_write(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let (fd, buf, len) = (frame.rdi, frame.rsi, frame.rdx);
+    let file = file(fd)?;
+    let written = file.write(user_slice(buf, len)?);
+    if let (Err(Error::BrokenPipe), Some(process)) = (written, process::current()) {
+        process.signal(signal::SIGPIPE);
+    }
+    Ok(written? as u64)
+}
+
+/// `read(fd, buf, len)`: returns the number of bytes read, which is 0 at
+/// the end of the file.
+fn sys_read(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let (fd, buf, len) = (frame.rdi, frame.rsi, frame.rdx);
+    let file = file(fd)?;
+    let read = file.read(user_slice_mut(buf, len)?)?;
+    Ok(read as u64)
+}
+
+/// `close(fd)`
+fn sys_close(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let fd = usize::try_from(frame.rdi).map_err(|_| Error::BadFileDescriptor)?;
+    let process = process::current().ok_or(Error::BadFileDescriptor)?;
+    process.files().close(fd)?;
+    Ok(0)
+}
+
+/// `getpid()`
+fn sys_getpid(_frame: &mut TrapFrame) -> Result<u64, Error> {
+    let process = process::current().ok_or(Error::NoSuchProcess)?;
+    Ok(process.pid().as_u64())
+}
+
+/// `wait(pid, status)`: wait for the child `pid`, or any child if it's
+/// -1, to exit, and store its exit code in `*status` unless that's null.
+/// Returns the id of the child.
+fn sys_wait(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let (pid, status) = (frame.rdi, frame.rsi);
+    let pid = match pid {
+        u64::MAX => None,
+        pid => Some(Pid::from_u64(pid)),
+    };
+
+    // checked before blocking, and again after
+    if status != 0 {
+        user_slice_mut(status, size_of::<i32>() as u64)?;
+    }
+    let (pid, code) = process::wait(pid)?;
+    if status != 0 {
+        user_slice_mut(status, size_of::<i32>() as u64)?.copy_from_slice(&code.to_ne_bytes());
+    }
+    Ok(pid.as_u64())
+}
+
+/// `pipe(fds)`: create a pipe (see [`pipe::pipe`]), and store the file
+/// descriptors of its reading and writing ends in the two `i32`s at
+/// `fds`.
+fn sys_pipe(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let fds = frame.rdi;
+    user_slice_mut(fds, 2 * size_of::<i32>() as u64)?;
+    let process = process::current().ok_or(Error::NoSuchProcess)?;
+
+    let (reader, writer) = pipe::pipe();
+    let mut files = process.files();
+    let read_fd = files.insert(Arc::new(reader))?;
+    let write_fd = match files.insert(Arc::new(writer)) {
+        Ok(fd) => fd,
+        Err(e) => {
+            files.close(read_fd)?;
+            return Err(e);
+        }
+    };
+    drop(files);
+
+    let mut bytes = [0; 8];
+    for (chunk, fd) in bytes.chunks_mut(4).zip([read_fd, write_fd]) {
+        let fd = i32::try_from(fd).map_err(|_| Error::TooManyFiles)?;
+        chunk.copy_from_slice(&fd.to_ne_bytes());
+    }
+    user_slice_mut(fds, 8)?.copy_from_slice(&bytes);
+    Ok(0)
+}
+
+/// `open(path, len)`: open the file packed into the kernel as `path`
+/// (see [`initrd`]) for reading, and return its file descriptor.
+fn sys_open(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let (path, len) = (frame.rdi, frame.rsi);
+    let path = core::str::from_utf8(user_slice(path, len)?).map_err(|_| Error::InvalidArgument)?;
+    let data = initrd::find(path).ok_or(Error::NotFound)?;
+    let process = process::current().ok_or(Error::NoSuchProcess)?;
+
+    let fd = process.files().insert(Arc::new(MemoryFile::new(data)))?;
+    Ok(fd as u64)
+}
+
+/// `readdir(index, buf, len)`: copy the name of the `index`th file
+/// packed into the kernel to `buf`, and return its length, or 0 past the
+/// last file.
+fn sys_readdir(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let (index, buf, len) = (frame.rdi, frame.rsi, frame.rdx);
+    let file = usize::try_from(index)
+        .ok()
+        .and_then(|index| initrd::files().nth(index));
+    let name = match file {
+        Some((name, _)) => name.as_bytes(),
+        None => return Ok(0),
+    };
+    if name.len() as u64 > len {
+        return Err(Error::InvalidArgument);
+    }
+    user_slice_mut(buf, name.len() as u64)?.copy_from_slice(name);
+    Ok(name.len() as u64)
+}
+
+/// `fork()`: start a copy of the calling process, see [`process::fork`].
+/// Returns the id of the child, and 0 in the child.
+fn sys_fork(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let pid = process::fork(frame)?;
+    Ok(pid.as_u64())
+}
+
+/// Run `f` with the address space of the calling process.
+fn with_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> Result<R, Error> {
+    let process = process::current().ok_or(Error::NoSuchProcess)?;
+    let mut space = process.space();
+    space.as_mut().map(f).ok_or(Error::NoSuchProcess)
+}
+
+/// The pages of the `len` bytes at `addr`, which has to be page aligned.
+///
+/// # Errors
+///
+/// If they aren't all in user memory, or there are none,
+/// [`Error::InvalidArgument`] is returned.
+fn user_pages(addr: u64, len: u64) -> Result<PageRange, Error> {
+    let in_user_memory = addr.checked_add(len).map_or(false, |end| {
+        addr >= memory::USER_START && end <= memory::USER_END
+    });
+    if addr % 4096 != 0 || len == 0 || !in_user_memory {
+        return Err(Error::InvalidArgument);
+    }
+    Ok(user::page_range(addr, len))
+}
+
+/// The page flags for the `PROT_*` flags in `prot`.
+fn page_flags(prot: u64) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// `mmap(addr, len, prot, flags, fd, offset)`: map `len` bytes of
+/// zeroed memory (with `MAP_ANONYMOUS`), or a copy of the file `fd`
+/// from `offset`, at `addr` (with `MAP_FIXED`) or wherever there is
+/// room. Only private mappings are supported. Returns the address.
+fn sys_mmap(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let (addr, len, prot, flags) = (frame.rdi, frame.rsi, frame.rdx, frame.r10);
+    let (fd, offset) = (frame.r8, frame.r9);
+    if flags & (MAP_SHARED | MAP_PRIVATE) != MAP_PRIVATE || offset % 4096 != 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    let backing = if flags & MAP_ANONYMOUS == 0 {
+        let file = file(fd)?;
+        // checks that the file can be mapped
+        file.read_at(offset, &mut [])?;
+        Backing::File { file, offset }
+    } else {
+        Backing::Anonymous
+    };
+
+    with_space(|space| {
+        let pages = if flags & MAP_FIXED == 0 {
+            if len == 0 {
+                return Err(Error::InvalidArgument);
+            }
+            space.find_free(len).ok_or(Error::OutOfMemory)?
+        } else {
+            user_pages(addr, len)?
+        };
+        space.map_area(pages, page_flags(prot), backing);
+        Ok(pages.start.start_address().as_u64())
+    })?
+}
+
+/// `munmap(addr, len)`
+fn sys_munmap(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let pages = user_pages(frame.rdi, frame.rsi)?;
+    with_space(|space| space.unmap(pages))?;
+    Ok(0)
+}
+
+/// `mprotect(addr, len, prot)`
+fn sys_mprotect(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let pages = user_pages(frame.rdi, frame.rsi)?;
+    with_space(|space| space.protect(pages, page_flags(frame.rdx)))?
+        .map_err(|_| Error::OutOfMemory)?;
+    Ok(0)
+}
+
+/// `brk(addr)`: move the end of the heap to `addr`, see
+/// [`AddressSpace::set_break`]. Returns the new end, which is the old one
+/// if it can't be moved (`brk(0)` returns the current one).
+fn sys_brk(frame: &mut TrapFrame) -> Result<u64, Error> {
+    with_space(|space| space.set_break(frame.rdi))
+}
+
+/// `kill(pid, sig)`: send `sig` to the process `pid`, see [`signal::send`].
+fn sys_kill(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let sig = u32::try_from(frame.rsi).map_err(|_| Error::InvalidArgument)?;
+    signal::send(Pid::from_u64(frame.rdi), sig)?;
+    Ok(0)
+}
+
+/// `sigaction(sig, handler, restorer)`: deliver `sig` by calling
+/// `handler`, which returns to `restorer`, or set its action to
+/// `SIG_DFL` or `SIG_IGN`. Returns the previous handler.
+fn sys_sigaction(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let sig = u32::try_from(frame.rdi).map_err(|_| Error::InvalidArgument)?;
+    let (handler, restorer) = (frame.rsi, frame.rdx);
+    let action = match handler {
+        SIG_DFL => Action::Default,
+        SIG_IGN => Action::Ignore,
+        // user code can only be returned to in user memory
+        _ if handler < memory::USER_END && restorer < memory::USER_END => {
+            Action::Handler { handler, restorer }
+        }
+        _ => return Err(Error::InvalidArgument),
+    };
+
+    let process = process::current().ok_or(Error::NoSuchProcess)?;
+    let old = process.signals().set_action(sig, action)?;
+    Ok(match old {
+        Action::Default => SIG_DFL,
+        Action::Ignore => SIG_IGN,
+        Action::Handler { handler, .. } => handler,
+    })
+}
+
+/// `sigprocmask(how, set)`: block the signals in the mask `set`
+/// (`SIG_BLOCK`), unblock them (`SIG_UNBLOCK`), or block just them
+/// (`SIG_SETMASK`). Returns the previous mask.
+fn sys_sigprocmask(frame: &mut TrapFrame) -> Result<u64, Error> {
+    let (how, set) = (frame.rdi, frame.rsi);
+    let process = process::current().ok_or(Error::NoSuchProcess)?;
+    let mut signals = process.signals();
+    let old = signals.blocked();
+    let blocked = match how {
+        SIG_BLOCK => old | set,
+        SIG_UNBLOCK => old & !set,
+        SIG_SETMASK => set,
+        _ => return Err(Error::InvalidArgument),
+    };
+    signals.set_blocked(blocked);
+    Ok(old)
+}
+
+/// `sigreturn()`: return from a signal handler, see [`signal::sigreturn`].
+fn sys_sigreturn(frame: &mut TrapFrame) -> Result<u64, Error> {
+    signal::sigreturn(frame)
+}
+
+/// Enable `syscall` on the current CPU.
+///
+/// # Panics
+///
+/// Panics if the GDT segments are in the wrong order for `sysret`.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("invalid segments for syscall");
+    LStar::write(VirtAddr::new(aaos_syscall_entry as usize as u64));
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
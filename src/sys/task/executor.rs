@@ -0,0 +1,278 @@
+use alloc::{collections::BTreeMap, sync::Arc, task::Wake, vec::Vec};
+use core::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use x86_64::instructions::interrupts;
+
+use super::{Task, TaskId};
+use crate::sys::time;
+
+/// Number of ready tasks that can be queued. Tasks woken beyond that
+/// are found by looking through all tasks instead.
+const READY_QUEUE_CAPACITY: usize = 256;
+
+/// A cooperative executor. Tasks are polled whenever their waker
+/// has been called, and the CPU is halted while no task is ready.
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    wakers: BTreeMap<TaskId, Arc<TaskWaker>>,
+    ready: Arc<ReadyQueue>,
+    spawned: Arc<SegQueue<Task>>,
+}
+
+/// The tasks to poll next, shared with their wakers.
+struct ReadyQueue {
+    queue: ArrayQueue<TaskId>,
+    /// Set when a task was woken while `queue` was full. The task's
+    /// waker stays marked as queued, so that it is found by
+    /// [`Executor::poll_overflowed`].
+    overflowed: AtomicBool,
+}
+
+impl ReadyQueue {
+    /// Queue `id`. Since wakers may run in interrupt handlers, this
+    /// must neither block nor allocate.
+    fn push(&self, id: TaskId) {
+        if self.queue.push(id).is_err() {
+            self.overflowed.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// A handle for spawning tasks onto an [`Executor`], e.g. from
+/// within another task.
+#[derive(Clone)]
+pub struct Spawner {
+    spawned: Arc<SegQueue<Task>>,
+}
+
+impl Spawner {
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+        let task = Task::new(future);
+        let id = task.id();
+        self.spawned.push(task);
+        id
+    }
+}
+
+impl Executor {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            wakers: BTreeMap::new(),
+            ready: Arc::new(ReadyQueue {
+                queue: ArrayQueue::new(READY_QUEUE_CAPACITY),
+                overflowed: AtomicBool::new(false),
+            }),
+            spawned: Arc::new(SegQueue::new()),
+        }
+    }
+
+    #[must_use]
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawned: self.spawned.clone(),
+        }
+    }
+
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+        self.spawner().spawn(future)
+    }
+
+    /// Run tasks forever, halting the CPU whenever none is ready.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Run tasks until none is ready. Returns the number of tasks
+    /// that haven't completed yet.
+    pub fn run_until_idle(&mut self) -> usize {
+        while !self.is_idle() {
+            self.run_ready_tasks();
+        }
+        self.tasks.len()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.ready.queue.is_empty()
+            && !self.ready.overflowed.load(Ordering::Acquire)
+            && self.spawned.is_empty()
+    }
+
+    fn accept_spawned_tasks(&mut self) {
+        while let Some(task) = self.spawned.pop() {
+            let id = task.id();
+            assert!(self.tasks.insert(id, task).is_none(), "task id reused");
+            self.wakers
+                .insert(id, TaskWaker::new(id, self.ready.clone()));
+            self.ready.push(id);
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        self.accept_spawned_tasks();
+
+        while let Some(id) = self.ready.queue.pop() {
+            self.poll_task(id);
+        }
+        self.poll_overflowed();
+    }
+
+    /// Poll the tasks that were woken while the ready queue was full.
+    fn poll_overflowed(&mut self) {
+        if !self.ready.overflowed.swap(false, Ordering::AcqRel) {
+            return;
+        }
+
+        let woken: Vec<TaskId> = self
+            .wakers
+            .values()
+            .filter(|waker| waker.queued.load(Ordering::Acquire))
+            .map(|waker| waker.id)
+            .collect();
+        for id in woken {
+            self.poll_task(id);
+        }
+    }
+
+    fn poll_task(&mut self, id: TaskId) {
+        let (Some(task), Some(waker)) = (self.tasks.get_mut(&id), self.wakers.get(&id)) else {
+            return; // woken after completing
+        };
+        // wakes from now on have to queue the task again; one that has
+        // been polled since it was queued (by `poll_overflowed`) is skipped
+        if !waker.queued.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        let waker = Waker::from(waker.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        if task.poll(&mut cx) == Poll::Ready(()) {
+            self.tasks.remove(&id);
+            self.wakers.remove(&id);
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // an interrupt between checking the queues and halting could
+        // wake a task without the CPU noticing
+        interrupts::disable();
+        if self.is_idle() {
+            time::halt();
+        }
+        interrupts::enable();
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct TaskWaker {
+    id: TaskId,
+    ready: Arc<ReadyQueue>,
+    /// Whether the task has been woken since it was last polled, so
+    /// that repeated wakes (e.g. from an interrupt handler) queue it
+    /// once.
+    queued: AtomicBool,
+}
+
+impl TaskWaker {
+    fn new(id: TaskId, ready: Arc<ReadyQueue>) -> Arc<Self> {
+        Arc::new(Self {
+            id,
+            ready,
+            queued: AtomicBool::new(true),
+        })
+    }
+
+    fn wake_task(&self) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.ready.push(self.id);
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+#[test_case]
+fn run_tasks_to_completion() {
+    use core::sync::atomic::AtomicUsize;
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+
+    executor.spawn(async move {
+        for _ in 0..3 {
+            COUNT.fetch_add(1, Ordering::Relaxed);
+            super::yield_now().await;
+        }
+
+        spawner.spawn(async {
+            COUNT.fetch_add(10, Ordering::Relaxed);
+        });
+    });
+    executor.spawn(core::future::pending());
+
+    assert_eq!(executor.run_until_idle(), 1);
+    assert_eq!(COUNT.load(Ordering::Relaxed), 13);
+}
+
+#[test_case]
+fn repeated_wakes_queue_once() {
+    use core::{future::poll_fn, sync::atomic::AtomicUsize};
+
+    static POLLS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    executor.spawn(poll_fn(|cx| {
+        if POLLS.fetch_add(1, Ordering::Relaxed) == 0 {
+            for _ in 0..2 * READY_QUEUE_CAPACITY {
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }));
+
+    assert_eq!(executor.run_until_idle(), 0);
+    assert_eq!(POLLS.load(Ordering::Relaxed), 2);
+}
+
+#[test_case]
+fn more_ready_tasks_than_queue_capacity() {
+    use core::sync::atomic::AtomicUsize;
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    for _ in 0..2 * READY_QUEUE_CAPACITY {
+        executor.spawn(async {
+            super::yield_now().await;
+            COUNT.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    assert_eq!(executor.run_until_idle(), 0);
+    assert_eq!(COUNT.load(Ordering::Relaxed), 2 * READY_QUEUE_CAPACITY);
+}
@@ -0,0 +1,758 @@
+//! Preemptive kernel threads, scheduled from the timer interrupt
+//! according to a pluggable [`Policy`].
+
+use alloc::{boxed::Box, collections::BTreeMap, vec};
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::{
+    instructions::interrupts, registers::control::Cr3, structures::paging::PhysFrame, VirtAddr,
+};
+
+use crate::sys::{
+    gdt, memory, percpu,
+    process::Pid,
+    sync::{self, IrqSafeMutex},
+    time::{self, PIT_FREQUENCY},
+};
+
+// `thread::Info` would read like information about the current thread
+#[allow(clippy::module_name_repetitions)]
+pub use self::policy::{Fair, FixedPriority, Policy, RoundRobin, ThreadInfo};
+
+pub mod policy;
+
+const STACK_SIZE: usize = 4096 * 4;
+
+pub const DEFAULT_PRIORITY: u8 = 128;
+
+static SCHEDULER: IrqSafeMutex<Option<Scheduler>> = IrqSafeMutex::new(None);
+
+// The stack of a suspended thread looks like this, from the top:
+// the return address into whatever called `switch_context`, and
+// the callee-saved registers. Everything else has already been
+// saved by the caller (or by the interrupt handler that preempted
+// the thread).
+global_asm!(
+    r#"
+    .global aaos_switch_context
+    aaos_switch_context:
+        push rbp
+        push rbx
+        push r12
+        push r13
+        push r14
+        push r15
+        mov [rdi], rsp
+        mov rsp, rsi
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbx
+        pop rbp
+        ret
+
+    .global aaos_thread_trampoline
+    aaos_thread_trampoline:
+        mov rdi, r12
+        call r13
+        ud2
+    "#
+);
+
+extern "C" {
+    /// Save the callee-saved registers to the current stack, store the
+    /// stack pointer in `*old_rsp` and resume the thread whose stack
+    /// pointer is `new_rsp`.
+    fn aaos_switch_context(old_rsp: *mut u64, new_rsp: u64);
+
+    /// The first code run by a new thread: calls `r13(r12)`.
+    fn aaos_thread_trampoline();
+}
+
+// named after `std::thread::ThreadId`
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    #[must_use]
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    Blocked,
+    Exited,
+}
+
+/// Scheduling statistics for a thread.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    /// Time spent running (seconds).
+    pub runtime: f64,
+    /// Time spent ready to run, waiting for the CPU (seconds).
+    pub wait_time: f64,
+    /// Number of times the thread has been switched to.
+    pub switches: u64,
+}
+
+/// Accounting in PIT cycles, see [`time::cycles`].
+#[derive(Debug, Default)]
+struct Accounting {
+    runtime: u64,
+    wait_time: u64,
+    switches: u64,
+    /// When the thread last started running or became ready.
+    since: u64,
+}
+
+struct Thread {
+    id: ThreadId,
+    state: State,
+    priority: u8,
+    nice: i8,
+    accounting: Accounting,
+    /// Saved stack pointer, valid while the thread isn't running.
+    rsp: u64,
+    /// `None` for the boot thread, which keeps the bootloader's stack.
+    stack: Option<Box<[u8]>>,
+    /// Whether to forget the thread as soon as it exits, since no one
+    /// holds a [`JoinHandle`] to it.
+    detached: bool,
+    /// Thread blocked in [`JoinHandle::join`], waiting for this one.
+    joiner: Option<ThreadId>,
+    /// Set by [`unpark`] if the thread wasn't parked, making the next
+    /// call to [`park`] return immediately.
+    unpark_token: bool,
+    /// The stack pointer to switch to when entering the kernel from
+    /// user mode, or 0 if the thread isn't running user code.
+    kernel_stack: u64,
+    /// The level 4 page table of the thread's address space, or `None`
+    /// for the kernel's.
+    page_table: Option<PhysFrame>,
+    /// The process the thread belongs to, if any.
+    process: Option<Pid>,
+}
+
+impl Thread {
+    fn new<F: FnOnce() + Send + 'static>(entry: F, priority: u8, nice: i8) -> Self {
+        let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+        let top = (stack.as_mut_ptr() as u64 + STACK_SIZE as u64) & !0xf;
+
+        let entry = Box::into_raw(Box::new(entry));
+
+        // the initial frame popped by `aaos_switch_context`, ending
+        // 16-byte aligned so that the call in the trampoline leaves
+        // the stack aligned the way the ABI expects
+        let frame: [u64; 7] = [
+            0,                                      // r15
+            0,                                      // r14
+            thread_start::<F> as usize as u64,      // r13
+            entry as u64,                           // r12
+            0,                                      // rbx
+            0,                                      // rbp
+            aaos_thread_trampoline as usize as u64, // return address
+        ];
+        let rsp = top - core::mem::size_of_val(&frame) as u64;
+        unsafe { (rsp as *mut [u64; 7]).write(frame) };
+
+        Self {
+            id: ThreadId::new(),
+            state: State::Ready,
+            priority,
+            nice,
+            accounting: Accounting {
+                since: time::cycles(),
+                ..Accounting::default()
+            },
+            rsp,
+            stack: Some(stack),
+            detached: false,
+            joiner: None,
+            unpark_token: false,
+            kernel_stack: 0,
+            page_table: None,
+            process: None,
+        }
+    }
+
+    /// The thread that is already running when threading is initialized.
+    fn boot() -> Self {
+        Self {
+            id: ThreadId::new(),
+            state: State::Running,
+            priority: DEFAULT_PRIORITY,
+            nice: 0,
+            accounting: Accounting {
+                since: time::cycles(),
+                switches: 1,
+                ..Accounting::default()
+            },
+            rsp: 0,
+            stack: None,
+            detached: true,
+            joiner: None,
+            unpark_token: false,
+            kernel_stack: 0,
+            page_table: None,
+            process: None,
+        }
+    }
+
+    const fn info(&self, slice_ticks: usize) -> ThreadInfo {
+        ThreadInfo {
+            id: self.id,
+            priority: self.priority,
+            nice: self.nice,
+            slice_ticks,
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn stats(&self) -> Stats {
+        let now = time::cycles();
+        let accounting = &self.accounting;
+        let (running, waiting) = match self.state {
+            State::Running => (now - accounting.since, 0),
+            State::Ready => (0, now - accounting.since),
+            State::Blocked | State::Exited => (0, 0),
+        };
+
+        Stats {
+            runtime: (accounting.runtime + running) as f64 / PIT_FREQUENCY,
+            wait_time: (accounting.wait_time + waiting) as f64 / PIT_FREQUENCY,
+            switches: accounting.switches,
+        }
+    }
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    policy: Box<dyn Policy>,
+    current: ThreadId,
+    /// Runs when no other thread is ready. Never queued in the policy.
+    idle: ThreadId,
+    /// Ticks since the current thread was scheduled.
+    slice_ticks: usize,
+}
+
+impl Scheduler {
+    fn add(&mut self, thread: Thread) -> ThreadId {
+        self.reap();
+
+        let id = thread.id;
+        self.policy.add(&thread.info(0));
+        if thread.state == State::Ready {
+            self.policy.enqueue(&thread.info(0));
+        }
+        self.threads.insert(id, Box::new(thread));
+        id
+    }
+
+    fn thread_mut(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("no such thread")
+    }
+
+    /// Make a blocked thread ready to run, or make sure it doesn't
+    /// block the next time it parks.
+    fn unpark(&mut self, id: ThreadId) {
+        let Some(thread) = self.threads.get_mut(&id) else {
+            return;
+        };
+
+        match thread.state {
+            State::Blocked => {
+                thread.state = State::Ready;
+                thread.accounting.since = time::cycles();
+                let info = thread.info(0);
+                self.policy.enqueue(&info);
+            }
+            State::Ready | State::Running => thread.unpark_token = true,
+            State::Exited => {}
+        }
+    }
+
+    /// Block the current thread, unless it has an unpark token.
+    /// Returns whether it was blocked.
+    fn park_current(&mut self) -> bool {
+        let current = self.current;
+        let thread = self.thread_mut(current);
+        if thread.unpark_token {
+            thread.unpark_token = false;
+            false
+        } else {
+            thread.state = State::Blocked;
+            true
+        }
+    }
+
+    /// Mark the current thread as exited and wake its joiner, if any.
+    fn exit_current(&mut self) {
+        let current = self.current;
+        let thread = self.thread_mut(current);
+        thread.state = State::Exited;
+        let joiner = thread.joiner.take();
+
+        self.policy.exited(current);
+        if let Some(joiner) = joiner {
+            self.unpark(joiner);
+        }
+    }
+
+    /// Change the scheduling parameters of a thread, requeuing it
+    /// if necessary.
+    fn update(&mut self, id: ThreadId, f: impl FnOnce(&mut Thread)) -> bool {
+        let queued = match self.threads.get(&id) {
+            Some(thread) => thread.state == State::Ready && id != self.idle,
+            None => return false,
+        };
+
+        if queued {
+            self.policy.remove(id);
+        }
+        let thread = self.thread_mut(id);
+        f(thread);
+        if queued {
+            let info = thread.info(0);
+            self.policy.enqueue(&info);
+        }
+        true
+    }
+
+    /// Free the stacks of exited threads. The current thread might
+    /// still be running on its stack even if it has exited, so it is
+    /// left alone.
+    ///
+    /// Done when threads are spawned and joined rather than when
+    /// switching threads, since that happens in the timer interrupt,
+    /// which must not free memory.
+    fn reap(&mut self) {
+        let current = self.current;
+        self.threads.retain(|id, thread| {
+            if thread.state != State::Exited || *id == current {
+                return true;
+            }
+            thread.stack = None;
+            !thread.detached
+        });
+    }
+
+    /// Pick the thread to run next and mark it as running. Returns
+    /// pointers to where the current thread's stack pointer should be
+    /// saved and to the next thread's stack pointer, or `None` if the
+    /// current thread should keep running.
+    fn switch(&mut self) -> Option<(*mut u64, u64)> {
+        let prev = self.current;
+        let runnable = self.thread_mut(prev).state == State::Running;
+
+        // the current thread competes with the queued ones
+        if runnable && prev != self.idle {
+            let slice_ticks = self.slice_ticks;
+            let info = self.thread_mut(prev).info(slice_ticks);
+            self.policy.enqueue(&info);
+        }
+
+        let next = match self.policy.pick_next() {
+            Some(next) => next,
+            None if runnable => prev,
+            None => self.idle,
+        };
+
+        if next == prev {
+            return None;
+        }
+
+        let now = time::cycles();
+
+        let prev_thread = self.thread_mut(prev);
+        prev_thread.accounting.runtime += now - prev_thread.accounting.since;
+        prev_thread.accounting.since = now;
+        if runnable {
+            prev_thread.state = State::Ready;
+        }
+
+        let next_thread = self.thread_mut(next);
+        next_thread.accounting.wait_time += now - next_thread.accounting.since;
+        next_thread.accounting.since = now;
+        next_thread.accounting.switches += 1;
+        next_thread.state = State::Running;
+        if next_thread.kernel_stack != 0 {
+            gdt::set_kernel_stack(VirtAddr::new(next_thread.kernel_stack));
+        }
+        load_page_table(next_thread.page_table);
+
+        self.current = next;
+        self.slice_ticks = 0;
+
+        let prev_rsp = &mut self.thread_mut(prev).rsp as *mut u64;
+        let next_rsp = self.thread_mut(next).rsp;
+        Some((prev_rsp, next_rsp))
+    }
+}
+
+/// Switch to the next thread, if any. Must be called with interrupts
+/// disabled, which they will also be when this thread resumes.
+fn schedule() {
+    let switch = SCHEDULER.lock().as_mut().and_then(Scheduler::switch);
+
+    if let Some((prev_rsp, next_rsp)) = switch {
+        debug_assert!(
+            !sync::holding_spinlocks(),
+            "switching threads while holding a spinlock"
+        );
+        debug_assert!(
+            !percpu::preemption_disabled(),
+            "switching threads with preemption disabled"
+        );
+
+        // the lock has been released, and the thread pointers stay
+        // valid since threads are boxed and never freed while running
+        unsafe { aaos_switch_context(prev_rsp, next_rsp) };
+    }
+}
+
+/// Run `f` with exclusive access to the scheduler.
+///
+/// # Panics
+///
+/// Panics if threading hasn't been initialized.
+fn with_scheduler<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    f(SCHEDULER
+        .lock()
+        .as_mut()
+        .expect("threading not initialized"))
+}
+
+extern "C" fn thread_start<F: FnOnce()>(entry: *mut F) -> ! {
+    // threads are always switched to with interrupts disabled
+    interrupts::enable();
+
+    // move the closure out of its box first, so that the box isn't
+    // leaked if the closure calls `exit`
+    let entry = unsafe { *Box::from_raw(entry) };
+    entry();
+
+    exit()
+}
+
+/// A handle for waiting for a thread to exit. If it is dropped, the
+/// thread is detached.
+#[derive(Debug)]
+pub struct JoinHandle {
+    id: ThreadId,
+}
+
+impl JoinHandle {
+    #[must_use]
+    pub const fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Block until the thread has exited.
+    pub fn join(self) {
+        loop {
+            let exited = with_scheduler(|scheduler| {
+                let current = scheduler.current;
+                match scheduler.threads.get_mut(&self.id) {
+                    Some(thread) if thread.state != State::Exited => {
+                        thread.joiner = Some(current);
+                        false
+                    }
+                    _ => true,
+                }
+            });
+
+            if exited {
+                break;
+            }
+            park();
+        }
+        // dropping the handle forgets the thread
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        with_scheduler(|scheduler| {
+            scheduler.reap();
+
+            let current = scheduler.current;
+            if let Some(thread) = scheduler.threads.get_mut(&self.id) {
+                // an exited thread that isn't running is no longer using
+                // its stack
+                if thread.state == State::Exited && self.id != current {
+                    scheduler.threads.remove(&self.id);
+                } else {
+                    thread.detached = true;
+                }
+            }
+        });
+    }
+}
+
+/// Configuration for a new thread.
+#[derive(Debug, Clone, Copy)]
+pub struct Builder {
+    priority: u8,
+    nice: i8,
+}
+
+impl Builder {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            priority: DEFAULT_PRIORITY,
+            nice: 0,
+        }
+    }
+
+    /// Priority used by [`FixedPriority`]. Higher is more important.
+    #[must_use]
+    pub const fn priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Nice value used by [`Fair`], from -20 (highest share) to 19.
+    #[must_use]
+    pub fn nice(mut self, nice: i8) -> Self {
+        self.nice = nice.clamp(-20, 19);
+        self
+    }
+
+    /// Start a new thread running `f`.
+    pub fn spawn(self, f: impl FnOnce() + Send + 'static) -> JoinHandle {
+        let thread = Thread::new(f, self.priority, self.nice);
+        let id = with_scheduler(|scheduler| scheduler.add(thread));
+        JoinHandle { id }
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Start a new thread running `f`.
+pub fn spawn(f: impl FnOnce() + Send + 'static) -> JoinHandle {
+    Builder::new().spawn(f)
+}
+
+/// Block the current thread until it is unparked with [`unpark`].
+/// Returns immediately if it has been unparked since it last parked.
+///
+/// Like [`std::thread::park`], this may also return spuriously, so
+/// callers should check whatever they are waiting for in a loop.
+///
+/// [`std::thread::park`]: https://doc.rust-lang.org/std/thread/fn.park.html
+pub fn park() {
+    interrupts::without_interrupts(|| {
+        let blocked = SCHEDULER
+            .lock()
+            .as_mut()
+            .is_some_and(Scheduler::park_current);
+
+        if blocked {
+            schedule();
+        }
+    });
+}
+
+/// Wake a thread blocked in [`park`]. Safe to call from interrupt
+/// handlers.
+pub fn unpark(id: ThreadId) {
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.unpark(id);
+    }
+}
+
+/// Whether [`init`] has been called, i.e. whether it is possible to block.
+#[must_use]
+pub fn is_initialized() -> bool {
+    SCHEDULER.lock().is_some()
+}
+
+/// Let other threads run.
+pub fn yield_now() {
+    interrupts::without_interrupts(schedule);
+}
+
+/// Exit the current thread.
+///
+/// Nothing on the thread's stack is dropped, including the captures of
+/// the closure it was spawned with; return from the closure instead
+/// to drop them.
+pub fn exit() -> ! {
+    interrupts::disable();
+    with_scheduler(Scheduler::exit_current);
+    schedule();
+
+    unreachable!("exited thread was scheduled")
+}
+
+/// The id of the calling thread.
+#[must_use]
+pub fn current() -> ThreadId {
+    with_scheduler(|scheduler| scheduler.current)
+}
+
+/// Switch to the page table `page_table` (or the kernel's), unless it's
+/// already active.
+fn load_page_table(page_table: Option<PhysFrame>) {
+    let page_table = page_table.unwrap_or_else(memory::kernel_page_table);
+    let (active, flags) = Cr3::read();
+    if active != page_table {
+        unsafe { Cr3::write(page_table, flags) };
+    }
+}
+
+/// Switch the calling thread to the address space whose level 4 page
+/// table is `page_table` (or back to the kernel's, for `None`).
+pub fn set_page_table(page_table: Option<PhysFrame>) {
+    interrupts::without_interrupts(|| {
+        with_scheduler(|scheduler| {
+            let current = scheduler.current;
+            scheduler.thread_mut(current).page_table = page_table;
+        });
+        load_page_table(page_table);
+    });
+}
+
+/// The process the calling thread belongs to, if any.
+#[must_use]
+pub fn current_process() -> Option<Pid> {
+    with_scheduler(|scheduler| scheduler.threads[&scheduler.current].process)
+}
+
+/// Make the calling thread part of `process`.
+pub(crate) fn set_process(process: Option<Pid>) {
+    with_scheduler(|scheduler| {
+        let current = scheduler.current;
+        scheduler.thread_mut(current).process = process;
+    });
+}
+
+/// Where [`crate::sys::user`] keeps the kernel stack pointer of the
+/// calling thread while it runs user code.
+pub(crate) fn kernel_stack_slot() -> *mut u64 {
+    with_scheduler(|scheduler| {
+        let current = scheduler.current;
+        core::ptr::from_mut(&mut scheduler.thread_mut(current).kernel_stack)
+    })
+}
+
+/// The state of a thread, or `None` if it doesn't exist (anymore).
+#[must_use]
+pub fn state(id: ThreadId) -> Option<State> {
+    with_scheduler(|scheduler| scheduler.threads.get(&id).map(|thread| thread.state))
+}
+
+/// Scheduling statistics of a thread, or `None` if it doesn't exist.
+#[must_use]
+pub fn stats(id: ThreadId) -> Option<Stats> {
+    with_scheduler(|scheduler| scheduler.threads.get(&id).map(|thread| thread.stats()))
+}
+
+/// Set the priority of a thread (see [`FixedPriority`]). Returns
+/// `false` if the thread doesn't exist.
+#[must_use]
+pub fn set_priority(id: ThreadId, priority: u8) -> bool {
+    with_scheduler(|scheduler| scheduler.update(id, |thread| thread.priority = priority))
+}
+
+/// Set the nice value of a thread (see [`Fair`]), from -20 to 19.
+/// Returns `false` if the thread doesn't exist.
+#[must_use]
+pub fn set_nice(id: ThreadId, nice: i8) -> bool {
+    let nice = nice.clamp(-20, 19);
+    with_scheduler(|scheduler| scheduler.update(id, |thread| thread.nice = nice))
+}
+
+/// Replace the scheduling policy, moving all ready threads to it.
+pub fn set_policy(policy: impl Policy + 'static) {
+    with_scheduler(|scheduler| {
+        scheduler.policy = Box::new(policy);
+
+        let idle = scheduler.idle;
+        for thread in scheduler.threads.values() {
+            if thread.state != State::Exited && thread.id != idle {
+                scheduler.policy.add(&thread.info(0));
+            }
+        }
+        for thread in scheduler.threads.values() {
+            if thread.state == State::Ready && thread.id != idle {
+                scheduler.policy.enqueue(&thread.info(0));
+            }
+        }
+    });
+}
+
+/// Called on every timer tick, with interrupts disabled.
+pub(crate) fn tick() {
+    if percpu::preemption_disabled() {
+        return;
+    }
+
+    let preempt = match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => {
+            scheduler.slice_ticks += 1;
+
+            if scheduler.current == scheduler.idle {
+                !scheduler.policy.is_empty()
+            } else {
+                let current = scheduler.current;
+                let slice_ticks = scheduler.slice_ticks;
+                let info = scheduler.thread_mut(current).info(slice_ticks);
+                scheduler.policy.tick(&info)
+            }
+        }
+        None => false,
+    };
+
+    if preempt {
+        schedule();
+    }
+}
+
+fn idle() {
+    loop {
+        time::halt();
+    }
+}
+
+/// Turn the running code into the first thread. Must be called after
+/// the heap has been initialized.
+pub fn init() {
+    interrupts::without_interrupts(|| {
+        let boot = Thread::boot();
+        let current = boot.id;
+        let idle = Thread::new(idle, 0, 19);
+        let idle_id = idle.id;
+
+        let mut scheduler = Scheduler {
+            threads: BTreeMap::new(),
+            policy: Box::new(RoundRobin::new()),
+            current,
+            idle: idle_id,
+            slice_ticks: 0,
+        };
+        scheduler.policy.add(&boot.info(0));
+        scheduler.threads.insert(current, Box::new(boot));
+        scheduler.threads.insert(idle_id, Box::new(idle));
+
+        *SCHEDULER.lock() = Some(scheduler);
+    });
+}
@@ -0,0 +1,283 @@
+//! Scheduling policies, deciding which ready thread runs next and
+//! when the running thread is preempted.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+
+use super::ThreadId;
+
+/// Number of timer ticks a thread may run before being preempted by
+/// another thread of the same priority.
+pub const TIME_SLICE_TICKS: usize = 10;
+
+/// The parameters of a thread that a [`Policy`] may base its
+/// decisions on.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    /// Higher is more important.
+    pub priority: u8,
+    /// -20 (highest share) through 19 (lowest share).
+    pub nice: i8,
+    /// Ticks since the thread was last switched to. Only meaningful
+    /// for the running thread.
+    pub slice_ticks: usize,
+}
+
+/// A scheduling policy. The scheduler keeps track of the threads
+/// themselves; the policy only orders the ready ones.
+///
+/// All methods but [`Policy::add`] and [`Policy::exited`] may be
+/// called from the timer interrupt, so they must not allocate or free
+/// memory.
+pub trait Policy: Send {
+    /// A thread has been created, or the policy is taking over an
+    /// existing one. Never called from an interrupt handler, so this
+    /// is where the policy reserves the memory it needs to queue the
+    /// thread later.
+    fn add(&mut self, _thread: &ThreadInfo) {}
+
+    /// Add a thread that has become ready to run.
+    fn enqueue(&mut self, thread: &ThreadInfo);
+
+    /// Remove and return the thread that should run next.
+    fn pick_next(&mut self) -> Option<ThreadId>;
+
+    /// Remove a queued thread, e.g. because its parameters are
+    /// about to change.
+    fn remove(&mut self, id: ThreadId);
+
+    /// Called on every timer tick while `current` is running.
+    /// Returns whether it should be preempted.
+    fn tick(&mut self, current: &ThreadInfo) -> bool;
+
+    fn is_empty(&self) -> bool;
+
+    /// The thread has exited, so any state kept for it can be dropped.
+    fn exited(&mut self, _id: ThreadId) {}
+}
+
+/// Every thread gets the same time slice, in turn.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    queue: VecDeque<ThreadId>,
+    /// Threads added and not exited, which the queue has room for.
+    threads: usize,
+}
+
+impl RoundRobin {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            threads: 0,
+        }
+    }
+}
+
+impl Policy for RoundRobin {
+    fn add(&mut self, _thread: &ThreadInfo) {
+        self.threads += 1;
+        self.queue.reserve(self.threads - self.queue.len());
+    }
+
+    fn enqueue(&mut self, thread: &ThreadInfo) {
+        debug_assert!(self.queue.len() < self.queue.capacity());
+        self.queue.push_back(thread.id);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        self.queue.pop_front()
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        self.queue.retain(|queued| *queued != id);
+    }
+
+    fn tick(&mut self, current: &ThreadInfo) -> bool {
+        current.slice_ticks >= TIME_SLICE_TICKS && !self.queue.is_empty()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    fn exited(&mut self, _id: ThreadId) {
+        self.threads -= 1;
+    }
+}
+
+/// The ready thread with the highest priority always runs. Threads
+/// of equal priority are scheduled round-robin.
+#[derive(Debug, Default)]
+pub struct FixedPriority {
+    /// The ready threads and their priorities, in the order they were
+    /// queued.
+    queue: Vec<(u8, ThreadId)>,
+    /// Threads added and not exited, which the queue has room for.
+    threads: usize,
+}
+
+impl FixedPriority {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            queue: Vec::new(),
+            threads: 0,
+        }
+    }
+
+    fn highest_queued(&self) -> Option<u8> {
+        self.queue.iter().map(|&(priority, _)| priority).max()
+    }
+}
+
+impl Policy for FixedPriority {
+    fn add(&mut self, _thread: &ThreadInfo) {
+        self.threads += 1;
+        self.queue.reserve(self.threads - self.queue.len());
+    }
+
+    fn enqueue(&mut self, thread: &ThreadInfo) {
+        debug_assert!(self.queue.len() < self.queue.capacity());
+        self.queue.push((thread.priority, thread.id));
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let priority = self.highest_queued()?;
+        let index = self
+            .queue
+            .iter()
+            .position(|&(queued, _)| queued == priority)?;
+        Some(self.queue.remove(index).1)
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        self.queue.retain(|&(_, queued)| queued != id);
+    }
+
+    fn tick(&mut self, current: &ThreadInfo) -> bool {
+        match self.highest_queued() {
+            Some(priority) if priority > current.priority => true,
+            Some(priority) if priority == current.priority => {
+                current.slice_ticks >= TIME_SLICE_TICKS
+            }
+            _ => false,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    fn exited(&mut self, _id: ThreadId) {
+        self.threads -= 1;
+    }
+}
+
+/// Load weights for nice values -20 through 19, each step giving
+/// roughly 10% less CPU time. Same table as Linux.
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+/// Virtual runtime added per tick for a thread of nice 0.
+const NICE_0_TICK: u64 = 1 << 20;
+
+/// A thread is only preempted in favor of one that has received at
+/// least this much less (virtual) CPU time, to avoid switching on
+/// every tick.
+const FAIR_GRANULARITY: u64 = 4 * NICE_0_TICK;
+
+/// Shares the CPU between threads in proportion to the weights
+/// given by their nice values, always running the thread that has
+/// received the least weighted ("virtual") runtime. Inspired by
+/// Linux's CFS.
+#[derive(Debug, Default)]
+pub struct Fair {
+    /// The ready threads, with their virtual runtimes when queued.
+    queue: Vec<(u64, ThreadId)>,
+    /// The virtual runtime of every thread added and not exited.
+    vruntimes: BTreeMap<ThreadId, u64>,
+    /// Lower bound for the virtual runtime of newly queued threads,
+    /// so that threads that have been blocked for a long time can't
+    /// monopolize the CPU when they wake up.
+    min_vruntime: u64,
+}
+
+impl Fair {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            queue: Vec::new(),
+            vruntimes: BTreeMap::new(),
+            min_vruntime: 0,
+        }
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    fn weight(nice: i8) -> u64 {
+        NICE_TO_WEIGHT[(nice.clamp(-20, 19) + 20) as usize]
+    }
+
+    /// The index of the queued thread with the least virtual runtime.
+    fn next(&self) -> Option<usize> {
+        (0..self.queue.len()).min_by_key(|&i| self.queue[i])
+    }
+}
+
+impl Policy for Fair {
+    fn add(&mut self, thread: &ThreadInfo) {
+        self.vruntimes.insert(thread.id, self.min_vruntime);
+        self.queue.reserve(self.vruntimes.len() - self.queue.len());
+    }
+
+    fn enqueue(&mut self, thread: &ThreadInfo) {
+        debug_assert!(self.queue.len() < self.queue.capacity());
+        let min_vruntime = self.min_vruntime;
+        let vruntime = self
+            .vruntimes
+            .get_mut(&thread.id)
+            .map_or(min_vruntime, |v| {
+                *v = (*v).max(min_vruntime);
+                *v
+            });
+        self.queue.push((vruntime, thread.id));
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let (vruntime, id) = self.queue.swap_remove(self.next()?);
+        self.min_vruntime = self.min_vruntime.max(vruntime);
+        Some(id)
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        self.queue.retain(|&(_, queued)| queued != id);
+    }
+
+    fn tick(&mut self, current: &ThreadInfo) -> bool {
+        let Some(vruntime) = self.vruntimes.get_mut(&current.id) else {
+            return false;
+        };
+        *vruntime += NICE_0_TICK * Self::weight(0) / Self::weight(current.nice);
+        let vruntime = *vruntime;
+
+        match self.next() {
+            Some(next) => self.queue[next].0 + FAIR_GRANULARITY < vruntime,
+            None => false,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    fn exited(&mut self, id: ThreadId) {
+        self.remove(id);
+        self.vruntimes.remove(&id);
+    }
+}
@@ -1,24 +1,81 @@
-use x86_64::{
-    instructions::{interrupts, port::Port},
-    structures::idt::InterruptStackFrame,
-};
+use x86_64::instructions::{interrupts, port::Port};
 
 use crate::sys::{
     clock::uptime,
     pic::{Irq, PICS},
+    signal, thread,
+    trap::TrapFrame,
 };
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 
 pub const PIT_FREQUENCY: f64 = 3_579_545.0 / 3.0; // 1_193_181.666 Hz
-const PIT_DIVIDER: u32 = 1193;
-pub const PIT_INTERVAL_SECS: f64 = (PIT_DIVIDER as f64) / PIT_FREQUENCY;
+const DEFAULT_PIT_DIVIDER: u32 = 1193;
+/// The default tick rate (~1 kHz).
+pub const DEFAULT_FREQUENCY: f64 = PIT_FREQUENCY / (DEFAULT_PIT_DIVIDER as f64);
+
+/// A divider of 65536 (written as 0) gives the lowest possible rate, ~18.2 Hz.
+const MAX_PIT_DIVIDER: u32 = 1 << 16;
+/// The square wave mode can't divide by 1.
+const MIN_PIT_DIVIDER: u32 = 2;
 
 static PIT_TICKS: AtomicUsize = AtomicUsize::new(0);
+static PIT_DIVIDER: AtomicU32 = AtomicU32::new(DEFAULT_PIT_DIVIDER);
+/// Number of PIT input clock cycles elapsed since boot, i.e. the sum
+/// of the dividers of all ticks so far. Unlike [`ticks`], this can be
+/// converted to seconds regardless of how the tick rate has changed.
+static PIT_CYCLES: AtomicU64 = AtomicU64::new(0);
 
 pub fn ticks() -> usize {
     PIT_TICKS.load(Ordering::Relaxed)
 }
 
+/// Number of PIT input clock cycles (at [`PIT_FREQUENCY`]) elapsed since boot.
+#[must_use]
+pub fn cycles() -> u64 {
+    PIT_CYCLES.load(Ordering::Relaxed)
+}
+
+/// Current tick rate (Hz).
+#[must_use]
+pub fn frequency() -> f64 {
+    PIT_FREQUENCY / f64::from(PIT_DIVIDER.load(Ordering::Relaxed))
+}
+
+/// Current time between two ticks (seconds).
+#[must_use]
+pub fn interval() -> f64 {
+    f64::from(PIT_DIVIDER.load(Ordering::Relaxed)) / PIT_FREQUENCY
+}
+
+/// Set the system tick rate (Hz). The rate is rounded to the nearest
+/// one the PIT can generate, between ~18.2 Hz and half of
+/// [`PIT_FREQUENCY`].
+///
+/// [`uptime`] keeps counting continuously across the change.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+pub fn set_frequency(hz: f64) {
+    let divider = (PIT_FREQUENCY / hz + 0.5) as u32;
+    set_divider(divider.clamp(MIN_PIT_DIVIDER, MAX_PIT_DIVIDER));
+}
+
+fn set_divider(divider: u32) {
+    #[allow(clippy::cast_possible_truncation)]
+    let pit_divider = if divider < MAX_PIT_DIVIDER {
+        divider as _
+    } else {
+        0
+    };
+    let channel = 0;
+
+    // the interrupt handler must never see the new divider before
+    // the PIT has actually been reprogrammed (or vice versa)
+    interrupts::without_interrupts(|| {
+        set_pit_frequency_divider(pit_divider, channel);
+        PIT_DIVIDER.store(divider, Ordering::Relaxed);
+    });
+}
+
 pub fn halt() {
     let disabled = !interrupts::are_enabled();
     interrupts::enable_and_hlt();
@@ -52,19 +109,56 @@ fn set_pit_frequency_divider(divider: u16, channel: u8) {
     });
 }
 
-pub(crate) extern "x86-interrupt" fn handle_timer_interrupt(_stack_frame: InterruptStackFrame) {
+/// Called from `crate::sys::trap::timer_entry`, which delivers signals
+/// to the interrupted user code.
+#[no_mangle]
+extern "C" fn aaos_handle_timer(frame: &mut TrapFrame) {
     PIT_TICKS.fetch_add(1, Ordering::Relaxed);
+    PIT_CYCLES.fetch_add(
+        u64::from(PIT_DIVIDER.load(Ordering::Relaxed)),
+        Ordering::Relaxed,
+    );
 
     unsafe { PICS.lock().notify_end_of_interrupt(Irq::Timer.as_u8()) }
+
+    // this might switch to another thread, so the interrupt has to
+    // be acknowledged first
+    thread::tick();
+
+    if frame.is_user() {
+        interrupts::enable();
+        signal::deliver(frame);
+        interrupts::disable();
+    }
 }
 
 pub fn init() {
-    #[allow(clippy::cast_possible_truncation)]
-    let divider = if PIT_DIVIDER < u16::MAX.into() {
-        PIT_DIVIDER as _
-    } else {
-        0
-    };
-    let channel = 0;
-    set_pit_frequency_divider(divider, channel);
+    set_divider(DEFAULT_PIT_DIVIDER);
+}
+
+#[test_case]
+fn set_frequency_keeps_uptime_continuous() {
+    let before = uptime();
+
+    set_frequency(10_000.0);
+    assert!((9_990.0..10_010.0).contains(&frequency()));
+    let ticks_before = ticks();
+    sleep(0.01);
+    assert!(ticks() - ticks_before >= 100);
+
+    set_frequency(DEFAULT_FREQUENCY);
+    assert_eq!(PIT_DIVIDER.load(Ordering::Relaxed), DEFAULT_PIT_DIVIDER);
+
+    let after = uptime();
+    assert!(after >= before + 0.01);
+    assert!(after < before + 1.0);
+}
+
+#[test_case]
+fn lowest_frequency() {
+    set_frequency(1.0);
+    assert_eq!(PIT_DIVIDER.load(Ordering::Relaxed), MAX_PIT_DIVIDER);
+    assert!((18.2..18.3).contains(&frequency()));
+
+    set_frequency(DEFAULT_FREQUENCY);
 }
@@ -0,0 +1,254 @@
+//! Entering the kernel from user mode, and returning to it.
+//!
+//! System calls, faults and timer interrupts save the registers
+//! of the code they interrupt in a [`TrapFrame`] on the kernel stack,
+//! and return to user mode through `aaos_return_to_user`, after pending
+//! signals have been delivered (see [`crate::sys::signal`]). Other
+//! interrupts return to user mode directly.
+//!
+//! User code can reset the GS base by loading a selector into `gs`, so
+//! every entry from user mode first reloads it from the kernel GS base,
+//! which only the kernel can change (see [`crate::sys::percpu`]).
+
+use core::arch::global_asm;
+use x86_64::{registers::rflags::RFlags, VirtAddr};
+
+use crate::sys::{apic, clock, gdt, idt, ipi, keyboard, mouse};
+
+// `aaos_return_to_user` takes the fast way back with `sysretq` when
+// `rcx` and `r11` hold what it would load them with anyway, like after
+// a system call, and uses `iretq` otherwise.
+//
+// The entry points push the registers onto what the CPU pushed (with
+// an error code of 0 if it didn't push one), and call their handler with
+// a 16-byte aligned stack. Traps from kernel mode return there directly.
+//
+// The other interrupts are handled by `x86-interrupt` functions, which
+// their entry points jump to once the GS base has been reloaded.
+global_asm!(
+    r#"
+    # `cs_offset` is the offset of the interrupted `cs` on the stack
+    .macro load_kernel_gs_base cs_offset
+        test qword ptr [rsp + \cs_offset], 3
+        jz 1f
+        push rax
+        push rcx
+        push rdx
+        mov ecx, 0xc0000102 # IA32_KERNEL_GS_BASE
+        rdmsr
+        mov ecx, 0xc0000101 # IA32_GS_BASE
+        wrmsr
+        pop rdx
+        pop rcx
+        pop rax
+    1:
+    .endm
+
+    .macro interrupt_entry name, handler, cs_offset
+    .global \name
+    \name:
+        load_kernel_gs_base \cs_offset
+        jmp \handler
+    .endm
+
+    # `cs_offset` is 16 if the CPU pushed an error code, and 8 otherwise
+    .macro trap_entry name, handler, cs_offset
+    .global \name
+    \name:
+        load_kernel_gs_base \cs_offset
+        .if \cs_offset == 8
+        push 0
+        .endif
+        push_registers
+        mov rdi, rsp
+        mov rbp, rsp
+        and rsp, -16
+        call \handler
+        jmp aaos_return_from_trap
+    .endm
+
+    .macro push_registers
+        push rax
+        push rbx
+        push rcx
+        push rdx
+        push rsi
+        push rdi
+        push rbp
+        push r8
+        push r9
+        push r10
+        push r11
+        push r12
+        push r13
+        push r14
+        push r15
+    .endm
+
+    .macro pop_registers
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop r11
+        pop r10
+        pop r9
+        pop r8
+        pop rbp
+        pop rdi
+        pop rsi
+        pop rdx
+        pop rcx
+        pop rbx
+        pop rax
+    .endm
+
+    .global aaos_return_to_user
+    aaos_return_to_user:
+        pop_registers
+        add rsp, 8
+        cmp rcx, [rsp]
+        jne aaos_iret_to_user
+        cmp r11, [rsp + 16]
+        jne aaos_iret_to_user
+        mov rsp, [rsp + 24]
+        sysretq
+    aaos_iret_to_user:
+        iretq
+
+    aaos_return_from_trap:
+        mov rsp, rbp
+        test qword ptr [rsp + 136], 3
+        jnz aaos_return_to_user
+        pop_registers
+        add rsp, 8
+        iretq
+
+    trap_entry aaos_page_fault_entry, aaos_handle_page_fault, 16
+    trap_entry aaos_timer_entry, aaos_handle_timer, 8
+    trap_entry aaos_divide_error_entry, aaos_handle_divide_error, 8
+    trap_entry aaos_bound_range_entry, aaos_handle_bound_range, 8
+    trap_entry aaos_invalid_opcode_entry, aaos_handle_invalid_opcode, 8
+    trap_entry aaos_device_not_available_entry, aaos_handle_device_not_available, 8
+    trap_entry aaos_stack_segment_entry, aaos_handle_stack_segment, 16
+    trap_entry aaos_general_protection_entry, aaos_handle_general_protection, 16
+    trap_entry aaos_alignment_check_entry, aaos_handle_alignment_check, 16
+
+    interrupt_entry aaos_double_fault_entry, {double_fault}, 16
+    interrupt_entry aaos_keyboard_entry, {keyboard}, 8
+    interrupt_entry aaos_rtc_entry, {rtc}, 8
+    interrupt_entry aaos_mouse_entry, {mouse}, 8
+    interrupt_entry aaos_call_entry, {call}, 8
+    interrupt_entry aaos_spurious_entry, {spurious}, 8
+    "#,
+    double_fault = sym idt::handle_double_fault,
+    keyboard = sym keyboard::handle_interrupt,
+    rtc = sym clock::handle_rtc_interrupt,
+    mouse = sym mouse::handle_interrupt,
+    call = sym ipi::handle_call_interrupt,
+    spurious = sym apic::handle_spurious_interrupt,
+);
+
+macro_rules! entry_points {
+    ($($(#[$attr:meta])* $name:ident => $symbol:ident;)*) => {
+        extern "C" {
+            $(fn $symbol();)*
+        }
+
+        $(
+            $(#[$attr])*
+            #[must_use]
+            pub fn $name() -> VirtAddr {
+                VirtAddr::new($symbol as usize as u64)
+            }
+        )*
+    };
+}
+
+entry_points! {
+    /// The entry point for page faults, which calls `aaos_handle_page_fault`.
+    page_fault_entry => aaos_page_fault_entry;
+    /// The entry point for timer interrupts, which calls `aaos_handle_timer`.
+    timer_entry => aaos_timer_entry;
+    // faults that user code can cause, which call the `aaos_handle_*`
+    // function of the same name
+    divide_error_entry => aaos_divide_error_entry;
+    bound_range_entry => aaos_bound_range_entry;
+    invalid_opcode_entry => aaos_invalid_opcode_entry;
+    device_not_available_entry => aaos_device_not_available_entry;
+    stack_segment_entry => aaos_stack_segment_entry;
+    general_protection_entry => aaos_general_protection_entry;
+    alignment_check_entry => aaos_alignment_check_entry;
+    double_fault_entry => aaos_double_fault_entry;
+    keyboard_entry => aaos_keyboard_entry;
+    rtc_entry => aaos_rtc_entry;
+    mouse_entry => aaos_mouse_entry;
+    call_entry => aaos_call_entry;
+    spurious_entry => aaos_spurious_entry;
+}
+
+/// The registers of interrupted (user) code, as saved on the kernel
+/// stack.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    /// The system call number, replaced by the result.
+    pub rax: u64,
+    /// Pushed by the CPU for some exceptions, 0 otherwise.
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// The registers to start user code at `entry` with: `stack` as the
+    /// stack pointer, interrupts enabled and everything else zeroed.
+    #[must_use]
+    pub fn new(entry: VirtAddr, stack: VirtAddr) -> Self {
+        let mut frame = Self {
+            rip: entry.as_u64(),
+            rflags: RFlags::INTERRUPT_FLAG.bits() | 0x2,
+            rsp: stack.as_u64(),
+            ..Self::default()
+        };
+        frame.set_user_segments();
+        frame
+    }
+
+    /// Whether the frame is of code running in user mode.
+    #[must_use]
+    pub const fn is_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+
+    /// Whether interrupts were enabled in the interrupted code.
+    #[must_use]
+    pub const fn interrupts_enabled(&self) -> bool {
+        self.rflags & RFlags::INTERRUPT_FLAG.bits() != 0
+    }
+
+    /// Set the code and stack segments to user mode's.
+    pub fn set_user_segments(&mut self) {
+        let selectors = gdt::selectors();
+        self.cs = u64::from(selectors.user_code.0);
+        self.ss = u64::from(selectors.user_data.0);
+    }
+}
@@ -0,0 +1,877 @@
+//! Running code in ring 3.
+//!
+//! A thread enters user mode with [`enter`] (see [`run`], or
+//! [`crate::sys::process::spawn`]), and stays there (apart from
+//! interrupts and system calls, which run on its kernel stack) until
+//! the code makes an `exit` system call, or is killed by a signal.
+
+use alloc::vec::Vec;
+use core::{arch::global_asm, cmp::Ordering, ops::Range};
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, MappedFrame, Translate, TranslateResult},
+        page::PageRange,
+        page_table::PageTableEntry,
+        Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::sys::{
+    gdt,
+    memory::{self, GlobalFrameAllocator, COPY_ON_WRITE, USER_END},
+    thread,
+    trap::TrapFrame,
+    vma::{Area, Areas, Backing},
+};
+
+/// Where [`run`] loads the code.
+pub const CODE_START: u64 = memory::USER_START;
+/// The top of the user stack.
+pub const STACK_TOP: u64 = memory::USER_END;
+pub const STACK_SIZE: u64 = 4096 * 4;
+/// Where memory mapped without a fixed address goes, above the heap.
+pub const MMAP_START: u64 = 0x2000_0000_0000;
+
+/// The level 4 entries covering user memory, which every address space
+/// has its own of. All the others are the kernel's.
+#[allow(clippy::cast_possible_truncation)]
+const USER_ENTRIES: Range<usize> =
+    (memory::USER_START >> 39) as usize..(memory::USER_END >> 39) as usize;
+
+// `aaos_enter_user` saves the callee-saved registers like
+// `aaos_switch_context` does, and the resulting (16-byte aligned)
+// stack pointer becomes the kernel stack of the thread, which
+// `aaos_leave_user` later returns to. No other register may leak
+// kernel data to user mode, so all of them are loaded from the frame,
+// the way traps return (see `crate::sys::trap`).
+global_asm!(
+    r#"
+    .global aaos_enter_user
+    aaos_enter_user:
+        push rbp
+        push rbx
+        push r12
+        push r13
+        push r14
+        push r15
+        sub rsp, 8
+        mov [rsi], rsp
+        mov [rdx], rsp
+        mov gs:[16], rsp
+        mov rsp, rdi
+        jmp aaos_return_to_user
+
+    .global aaos_leave_user
+    aaos_leave_user:
+        mov rsp, rdi
+        mov eax, esi
+        add rsp, 8
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbx
+        pop rbp
+        ret
+    "#
+);
+
+extern "C" {
+    /// Switch to user mode with the registers in `frame`. The kernel
+    /// stack pointer is stored in `*thread_stack` and `*tss_stack` (and
+    /// as the system call stack). Returns the exit code once user code
+    /// calls [`exit`], with interrupts disabled.
+    fn aaos_enter_user(frame: *const TrapFrame, thread_stack: *mut u64, tss_stack: *mut u64)
+        -> i32;
+
+    /// Return from `aaos_enter_user` with `code`, where the kernel
+    /// stack pointer was `kernel_stack`.
+    fn aaos_leave_user(kernel_stack: u64, code: i32) -> !;
+}
+
+/// A level 4 page table of its own, sharing the kernel's mappings, and
+/// the areas of user memory mapped into it (see [`Areas`]), which are
+/// freed when it's dropped.
+///
+/// The kernel must not add level 4 entries of its own once address
+/// spaces exist, since they wouldn't be shared.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_table: PhysFrame,
+    areas: Areas,
+    /// Where the heap starts, or 0 if there is none.
+    break_start: u64,
+    /// Where the heap ends, see [`AddressSpace::set_break`].
+    program_break: u64,
+}
+
+impl AddressSpace {
+    /// An address space with no user memory mapped yet.
+    ///
+    /// # Errors
+    ///
+    /// If there is no memory for the page table, an error is returned.
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let frame = memory::allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        let table = unsafe { table_mut(frame) };
+        let kernel_table: &PageTable =
+            unsafe { &*memory::phys_to_virt(memory::kernel_page_table().start_address()).as_ptr() };
+
+        for (i, entry) in table.iter_mut().enumerate() {
+            if USER_ENTRIES.contains(&i) {
+                entry.set_unused();
+            } else {
+                *entry = kernel_table[i].clone();
+            }
+        }
+
+        Ok(Self {
+            level_4_table: frame,
+            areas: Areas::new(),
+            break_start: 0,
+            program_break: 0,
+        })
+    }
+
+    /// The frame to load into CR3 to switch to the address space.
+    #[must_use]
+    pub const fn level_4_table(&self) -> PhysFrame {
+        self.level_4_table
+    }
+
+    /// The areas of user memory mapped.
+    #[must_use]
+    pub const fn areas(&self) -> &Areas {
+        &self.areas
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { mapper(self.level_4_table) }
+    }
+
+    /// A copy of the address space, which shares all user memory with
+    /// it: pages become [`COPY_ON_WRITE`] pages in both, which are
+    /// read-only until they are copied on the first write.
+    ///
+    /// # Errors
+    ///
+    /// If there is no memory for the page tables, an error is returned.
+    pub fn fork(&mut self) -> Result<Self, MapToError<Size4KiB>> {
+        let mut pages = Vec::new();
+        self.for_each_page(|page, entry| {
+            let flags = (entry.flags() - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+            entry.set_flags(flags);
+            if let Ok(frame) = entry.frame() {
+                pages.push((page, frame, flags));
+            }
+        });
+        if Cr3::read().0 == self.level_4_table {
+            tlb::flush_all();
+        }
+
+        let mut child = Self::new()?;
+        child.areas = self.areas.clone();
+        child.break_start = self.break_start;
+        child.program_break = self.program_break;
+
+        let mut mapper = child.mapper();
+        for (page, frame, flags) in pages {
+            unsafe {
+                mapper
+                    .map_to_with_table_flags(
+                        page,
+                        frame,
+                        flags,
+                        TABLE_FLAGS,
+                        &mut GlobalFrameAllocator,
+                    )?
+                    .ignore();
+            }
+            memory::share_frame(frame);
+        }
+        Ok(child)
+    }
+
+    /// Call `f` with every user page mapped, and its page table entry.
+    fn for_each_page(&mut self, mut f: impl FnMut(Page, &mut PageTableEntry)) {
+        let table = unsafe { table_mut(self.level_4_table) };
+        let user_entries = table.iter().enumerate().take(USER_ENTRIES.end);
+        for (i, entry) in user_entries.skip(USER_ENTRIES.start) {
+            if let Ok(frame) = entry.frame() {
+                unsafe { walk_table(frame, 3, (i as u64) << 39, &mut f) };
+            }
+        }
+    }
+
+    /// Map `pages` to zeroed memory right away, with the given `flags` in
+    /// addition to user access, replacing whatever was mapped there.
+    ///
+    /// # Errors
+    ///
+    /// If there isn't enough memory, an error is returned. The pages
+    /// stay mapped, to be allocated on first access.
+    pub fn map(
+        &mut self,
+        pages: PageRange<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        self.map_area(pages, flags, Backing::Anonymous);
+        for page in pages {
+            self.fault_in(page, false)?;
+        }
+        Ok(())
+    }
+
+    /// Map `pages` to memory holding `backing`, with the given `flags`
+    /// in addition to user access, replacing whatever was mapped there.
+    /// The memory is only allocated when it's first accessed.
+    pub fn map_area(
+        &mut self,
+        pages: PageRange<Size4KiB>,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) {
+        self.unmap(pages);
+        self.areas
+            .insert(Area::new(address_range(pages), flags, backing));
+    }
+
+    /// Map the user stack, ending at [`STACK_TOP`].
+    ///
+    /// # Errors
+    ///
+    /// Mapping errors will be propagated.
+    pub fn map_stack(&mut self) -> Result<(), MapToError<Size4KiB>> {
+        self.map(
+            page_range(STACK_TOP - STACK_SIZE, STACK_SIZE),
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+    }
+
+    /// Unmap `pages`, and free the memory they were mapped to. Pages
+    /// that aren't mapped are skipped.
+    pub fn unmap(&mut self, pages: PageRange<Size4KiB>) {
+        let removed = self.areas.remove(&address_range(pages));
+        let mut mapper = self.mapper();
+        for area in removed {
+            for page in page_range(area.range.start, area.range.end - area.range.start) {
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { memory::release_frame(frame) };
+                }
+            }
+        }
+    }
+
+    /// Change the flags of the mapped `pages` (in addition to user access).
+    ///
+    /// # Errors
+    ///
+    /// If some of them aren't mapped, nothing is changed and
+    /// [`FlagUpdateError::PageNotMapped`] is returned.
+    pub fn protect(
+        &mut self,
+        pages: PageRange<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<(), FlagUpdateError> {
+        let range = address_range(pages);
+        if !self.areas.covers(range.clone(), PageTableFlags::empty()) {
+            return Err(FlagUpdateError::PageNotMapped);
+        }
+        self.areas.protect(&range, flags);
+
+        let mut mapper = self.mapper();
+        for page in pages {
+            if let TranslateResult::Mapped { flags: current, .. } =
+                mapper.translate(page.start_address())
+            {
+                let mut new = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+                // still shared
+                if current.contains(COPY_ON_WRITE) {
+                    new = (new - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                }
+                unsafe { mapper.update_flags(page, new)? }.flush();
+            }
+        }
+        Ok(())
+    }
+
+    /// The lowest free range of `len` bytes (rounded up to whole pages)
+    /// where [`AddressSpace::map_area`] can map memory without a fixed
+    /// address.
+    #[must_use]
+    pub fn find_free(&self, len: u64) -> Option<PageRange<Size4KiB>> {
+        let len = len.checked_add(4095)? & !4095;
+        let start = self.areas.find_free(len, MMAP_START..USER_END)?;
+        Some(page_range(start, len))
+    }
+
+    /// Whether the `len` bytes at `addr` are all mapped user memory (and
+    /// writable, if `write` is set), which may not have been accessed yet.
+    #[must_use]
+    pub fn is_accessible(&self, addr: VirtAddr, len: u64, write: bool) -> bool {
+        let flags = if write {
+            PageTableFlags::WRITABLE
+        } else {
+            PageTableFlags::empty()
+        };
+        let start = addr.as_u64();
+        start
+            .checked_add(len)
+            .map_or(false, |end| self.areas.covers(start..end, flags))
+    }
+
+    /// Start the heap at `addr` (rounded up to a page), which is usually
+    /// the end of the executable.
+    pub fn set_break_start(&mut self, addr: VirtAddr) {
+        self.break_start = addr.align_up(4096u64).as_u64();
+        self.program_break = self.break_start;
+    }
+
+    /// Move the program break, the end of the heap, to `addr`, mapping
+    /// or unmapping memory as needed, and return the new break. If the
+    /// heap can't end at `addr`, since it's before its start or the heap
+    /// would run into other memory, the break stays where it was and is
+    /// returned instead.
+    pub fn set_break(&mut self, addr: u64) -> u64 {
+        if self.break_start == 0 || !(self.break_start..=MMAP_START).contains(&addr) {
+            return self.program_break;
+        }
+        let old_end = (self.program_break + 4095) & !4095;
+        let new_end = (addr + 4095) & !4095;
+
+        match new_end.cmp(&old_end) {
+            Ordering::Greater => {
+                if !self.areas.is_free(&(old_end..new_end)) {
+                    return self.program_break;
+                }
+                self.map_area(
+                    page_range(old_end, new_end - old_end),
+                    PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+                    Backing::Anonymous,
+                );
+            }
+            Ordering::Less => self.unmap(page_range(new_end, old_end - new_end)),
+            Ordering::Equal => {}
+        }
+        self.program_break = addr;
+        addr
+    }
+
+    /// Handle a page fault at `addr` (on a write, if `write` is set):
+    /// allocate the page if it's in an area but wasn't accessed yet, or
+    /// copy it if it's a [`COPY_ON_WRITE`] page being written to. Returns
+    /// `false` if the access isn't allowed.
+    pub fn handle_fault(&mut self, addr: VirtAddr, write: bool) -> bool {
+        match self.areas.find(addr.as_u64()) {
+            Some(area) if area.flags.contains(PageTableFlags::WRITABLE) || !write => {}
+            _ => return false,
+        }
+        matches!(
+            self.fault_in(Page::containing_address(addr), write),
+            Ok(true)
+        )
+    }
+
+    /// Make sure `page` is mapped if it's in an area, and has a frame of
+    /// its own if `unshare` is set. Returns whether anything had to be
+    /// done.
+    ///
+    /// # Errors
+    ///
+    /// If there isn't enough memory, an error is returned.
+    fn fault_in(&mut self, page: Page, unshare: bool) -> Result<bool, MapToError<Size4KiB>> {
+        let area = match self.areas.find(page.start_address().as_u64()) {
+            Some(area) => area.clone(),
+            None => return Ok(false),
+        };
+        let flags = area.flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let mut mapper = self.mapper();
+
+        match mapper.translate(page.start_address()) {
+            TranslateResult::NotMapped => {
+                let frame = memory::allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+                let bytes = unsafe { frame_bytes(frame) };
+                bytes.fill(0);
+                let offset = page.start_address().as_u64() - area.range.start;
+                area.backing.fill(offset, bytes);
+
+                let result = unsafe {
+                    mapper.map_to_with_table_flags(
+                        page,
+                        frame,
+                        flags,
+                        TABLE_FLAGS,
+                        &mut GlobalFrameAllocator,
+                    )
+                };
+                match result {
+                    Ok(flush) => flush.flush(),
+                    Err(e) => {
+                        unsafe { memory::deallocate_frame(frame) };
+                        return Err(e);
+                    }
+                }
+                Ok(true)
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags: current,
+                ..
+            } if unshare && current.contains(COPY_ON_WRITE) => {
+                unshare_page(&mut mapper, page, frame, flags)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Copy `bytes` to `addr`, which doesn't need to be in the active
+    /// address space. Returns `false` if some of it isn't mapped (or a
+    /// copy-on-write page can't be copied).
+    #[must_use]
+    pub fn write(&mut self, addr: VirtAddr, bytes: &[u8]) -> bool {
+        self.for_each_chunk(addr, bytes.len(), true, |ptr, range| {
+            let src = &bytes[range];
+            unsafe { ptr.copy_from_nonoverlapping(src.as_ptr(), src.len()) };
+        })
+    }
+
+    /// Copy the memory at `addr` into `buf`, like [`AddressSpace::write`].
+    #[must_use]
+    pub fn read(&mut self, addr: VirtAddr, buf: &mut [u8]) -> bool {
+        self.for_each_chunk(addr, buf.len(), false, |ptr, range| {
+            let dst = &mut buf[range];
+            unsafe { dst.as_mut_ptr().copy_from_nonoverlapping(ptr, dst.len()) };
+        })
+    }
+
+    /// Call `f` with a pointer to each piece of the `len` bytes at `addr`
+    /// that is in one page, and its range within the `len` bytes. Pages
+    /// not accessed yet are allocated, and if they are going to be
+    /// written, copy-on-write pages are copied. Returns `false` if some
+    /// of it isn't mapped.
+    fn for_each_chunk(
+        &mut self,
+        addr: VirtAddr,
+        len: usize,
+        write: bool,
+        mut f: impl FnMut(*mut u8, Range<usize>),
+    ) -> bool {
+        let mut done = 0;
+
+        while done < len {
+            let addr = addr + done;
+            if self
+                .fault_in(Page::containing_address(addr), write)
+                .is_err()
+            {
+                return false;
+            }
+            let Some(phys) = self.mapper().translate_addr(addr) else {
+                return false;
+            };
+            #[allow(clippy::cast_possible_truncation)]
+            let chunk = (len - done).min(4096 - (addr.as_u64() % 4096) as usize);
+            f(memory::phys_to_virt(phys).as_mut_ptr(), done..done + chunk);
+            done += chunk;
+        }
+        true
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            if Cr3::read().0 == self.level_4_table {
+                thread::set_page_table(None);
+            }
+        });
+
+        let table = unsafe { table_mut(self.level_4_table) };
+        let user_entries = table.iter().take(USER_ENTRIES.end).skip(USER_ENTRIES.start);
+        for entry in user_entries {
+            if let Ok(frame) = entry.frame() {
+                unsafe { free_table(frame, 3) };
+            }
+        }
+        unsafe { memory::deallocate_frame(self.level_4_table) };
+    }
+}
+
+/// The flags of the page tables mapping user memory, which leave access
+/// to the pages' own flags.
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+/// # Safety
+///
+/// `frame` must hold a page table that isn't referenced elsewhere.
+unsafe fn table_mut<'a>(frame: PhysFrame) -> &'a mut PageTable {
+    &mut *memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// # Safety
+///
+/// `frame` must not be in use elsewhere.
+unsafe fn frame_bytes<'a>(frame: PhysFrame) -> &'a mut [u8; 4096] {
+    &mut *memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// # Safety
+///
+/// See [`table_mut`].
+unsafe fn mapper<'a>(level_4_table: PhysFrame) -> OffsetPageTable<'a> {
+    let offset = memory::phys_to_virt(PhysAddr::zero());
+    OffsetPageTable::new(table_mut(level_4_table), offset)
+}
+
+/// Call `f` with every page mapped by the page table at `frame` of the
+/// given `level`, which starts at `start`, and its page table entry.
+///
+/// # Safety
+///
+/// See [`table_mut`].
+unsafe fn walk_table(
+    frame: PhysFrame,
+    level: u8,
+    start: u64,
+    f: &mut impl FnMut(Page, &mut PageTableEntry),
+) {
+    let size = 4096 << (9 * (level - 1));
+    for (i, entry) in table_mut(frame).iter_mut().enumerate() {
+        let addr = start + i as u64 * size;
+        match entry.frame() {
+            Ok(next) if level > 1 => walk_table(next, level - 1, addr, f),
+            Ok(_) => f(Page::containing_address(VirtAddr::new(addr)), entry),
+            Err(_) => {}
+        }
+    }
+}
+
+/// Give `page`, a [`COPY_ON_WRITE`] page mapped to `frame`, a frame of
+/// its own (unless it's the only reference to `frame` already), and map
+/// it with `flags` instead.
+///
+/// # Errors
+///
+/// If there is no memory for the copy, an error is returned.
+fn unshare_page(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    if memory::frame_references(frame) == 1 {
+        unsafe { mapper.update_flags(page, flags) }
+            .expect("page not mapped")
+            .flush();
+        return Ok(());
+    }
+
+    let copy = memory::allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+    unsafe {
+        frame_bytes(copy).copy_from_slice(frame_bytes(frame));
+        mapper.unmap(page).expect("page not mapped").1.ignore();
+        mapper
+            .map_to_with_table_flags(page, copy, flags, TABLE_FLAGS, &mut GlobalFrameAllocator)?
+            .flush();
+        memory::release_frame(frame);
+    }
+    Ok(())
+}
+
+/// The user memory range of `pages`.
+const fn address_range(pages: PageRange<Size4KiB>) -> Range<u64> {
+    pages.start.start_address().as_u64()..pages.end.start_address().as_u64()
+}
+
+/// Free the page table at `frame` of the given `level` and the tables
+/// below it, and release the memory they map.
+///
+/// # Safety
+///
+/// None of it may be in use anymore.
+unsafe fn free_table(frame: PhysFrame, level: u8) {
+    let table = table_mut(frame);
+    for entry in table.iter() {
+        if let Ok(next) = entry.frame() {
+            if level > 1 {
+                free_table(next, level - 1);
+            } else {
+                memory::release_frame(next);
+            }
+        }
+    }
+    memory::deallocate_frame(frame);
+}
+
+/// Switch to user mode with the registers in `frame` (see
+/// [`TrapFrame::new`]), and return the exit code once the code
+/// calls `exit`. The address space of the thread (see
+/// [`thread::set_page_table`]) has to be set up. Must be called from a
+/// thread.
+#[must_use]
+pub fn enter(frame: &TrapFrame) -> i32 {
+    interrupts::without_interrupts(|| {
+        let thread_stack = thread::kernel_stack_slot();
+        let status = unsafe { aaos_enter_user(frame, thread_stack, gdt::kernel_stack_slot()) };
+        unsafe { thread_stack.write(0) };
+        status
+    })
+}
+
+/// Copy `code` into a new address space and run it in ring 3, passing
+/// `arg` in `rdi`, until it calls `exit`. Returns the exit code. Must
+/// be called from a thread.
+///
+/// # Errors
+///
+/// If the user memory can't be mapped, an error is returned.
+///
+/// # Panics
+///
+/// Panics if `code` can't be copied into the pages mapped for it.
+pub fn run(code: &[u8], arg: u64) -> Result<i32, MapToError<Size4KiB>> {
+    let mut space = AddressSpace::new()?;
+
+    let code_pages = page_range(CODE_START, code.len() as u64);
+    space.map(code_pages, PageTableFlags::WRITABLE)?;
+    assert!(space.write(VirtAddr::new(CODE_START), code));
+    space
+        .protect(code_pages, PageTableFlags::empty())
+        .expect("user code not mapped");
+    space.map_stack()?;
+
+    thread::set_page_table(Some(space.level_4_table()));
+    let status = enter(&TrapFrame {
+        rdi: arg,
+        ..TrapFrame::new(VirtAddr::new(CODE_START), VirtAddr::new(STACK_TOP))
+    });
+    thread::set_page_table(None);
+    Ok(status)
+}
+
+/// Leave user mode, returning `code` from [`enter`]. Called by the `exit`
+/// system call.
+pub(crate) fn exit(code: i32) -> ! {
+    interrupts::disable();
+    let kernel_stack = unsafe { thread::kernel_stack_slot().read() };
+    unsafe { aaos_leave_user(kernel_stack, code) }
+}
+
+/// The pages containing the `len` bytes at `start`.
+pub(crate) fn page_range(start: u64, len: u64) -> PageRange<Size4KiB> {
+    let first = Page::containing_address(VirtAddr::new(start));
+    let end = Page::containing_address(VirtAddr::new(start + len + 4095));
+    Page::range(first, end)
+}
+
+#[cfg(test)]
+global_asm!(
+    r#"
+    .section .rodata
+    .global aaos_test_user_code
+    aaos_test_user_code:
+        lea rsi, [rip + aaos_test_user_message]
+        test rdi, rdi
+        cmovnz rsi, rdi
+        mov eax, 1
+        mov edi, 1
+        lea rdx, [rip + aaos_test_user_message_end]
+        lea rcx, [rip + aaos_test_user_message]
+        sub rdx, rcx
+        syscall
+        mov rdi, rax
+        xor eax, eax
+        syscall
+        ud2
+    aaos_test_user_message:
+        .ascii "hello from user mode\n"
+    aaos_test_user_message_end:
+    .global aaos_test_user_code_end
+    aaos_test_user_code_end:
+
+    .global aaos_test_gs_code
+    aaos_test_gs_code:
+        mov ax, ss
+        mov gs, ax
+        mov ecx, 0x1000000
+    1:
+        loop 1b
+        mov edi, 42
+        xor eax, eax
+        syscall
+        ud2
+    .global aaos_test_gs_code_end
+    aaos_test_gs_code_end:
+
+    .global aaos_test_fault_code
+    aaos_test_fault_code:
+        test rdi, rdi
+        jz 1f
+        xor ecx, ecx
+        div ecx
+    1:
+        ud2
+    .global aaos_test_fault_code_end
+    aaos_test_fault_code_end:
+    .text
+    "#
+);
+
+#[cfg(test)]
+unsafe fn code_between(start: *const u8, end: *const u8) -> &'static [u8] {
+    core::slice::from_raw_parts(start, end as usize - start as usize)
+}
+
+/// User code that writes 21 bytes from the address in its argument
+/// (or its own message if that's 0) and exits with the result.
+#[cfg(test)]
+fn test_code() -> &'static [u8] {
+    extern "C" {
+        static aaos_test_user_code: u8;
+        static aaos_test_user_code_end: u8;
+    }
+
+    unsafe { code_between(&aaos_test_user_code, &aaos_test_user_code_end) }
+}
+
+/// User code that resets its GS base by loading a selector into `gs`,
+/// spins long enough to be interrupted by the timer, and exits with 42.
+#[cfg(test)]
+fn gs_test_code() -> &'static [u8] {
+    extern "C" {
+        static aaos_test_gs_code: u8;
+        static aaos_test_gs_code_end: u8;
+    }
+
+    unsafe { code_between(&aaos_test_gs_code, &aaos_test_gs_code_end) }
+}
+
+/// User code that divides by zero if its argument isn't 0, and executes
+/// an invalid instruction otherwise.
+#[cfg(test)]
+fn fault_test_code() -> &'static [u8] {
+    extern "C" {
+        static aaos_test_fault_code: u8;
+        static aaos_test_fault_code_end: u8;
+    }
+
+    unsafe { code_between(&aaos_test_fault_code, &aaos_test_fault_code_end) }
+}
+
+#[test_case]
+fn write_syscall() {
+    assert_eq!(run(test_code(), 0).unwrap(), 21);
+}
+
+#[test_case]
+fn gs_base_survives_reset_by_user_code() {
+    assert_eq!(run(gs_test_code(), 0).unwrap(), 42);
+    assert_eq!(crate::sys::percpu::cpu_number(), 0);
+}
+
+#[test_case]
+fn faults_in_user_code_exit_with_signals() {
+    use crate::sys::signal::{exit_code, SIGFPE, SIGILL};
+
+    assert_eq!(run(fault_test_code(), 0).unwrap(), exit_code(SIGILL));
+    assert_eq!(run(fault_test_code(), 1).unwrap(), exit_code(SIGFPE));
+}
+
+#[test_case]
+fn write_syscall_rejects_kernel_memory() {
+    static SECRET: [u8; 21] = [0; 21];
+
+    let status = run(test_code(), SECRET.as_ptr() as u64).unwrap();
+    #[allow(clippy::cast_possible_truncation)]
+    let bad_address = crate::sys::syscall::Error::BadAddress.errno() as i32;
+    assert_eq!(status, bad_address);
+}
+
+#[test_case]
+fn fork_copies_on_write() {
+    let addr = VirtAddr::new(CODE_START);
+    let mut parent = AddressSpace::new().unwrap();
+    parent
+        .map(page_range(CODE_START, 4096), PageTableFlags::WRITABLE)
+        .unwrap();
+    assert!(parent.write(addr, b"parent"));
+
+    let mut child = parent.fork().unwrap();
+    let shared = parent.mapper().translate_addr(addr).unwrap();
+    let frame = PhysFrame::containing_address(shared);
+    assert_eq!(child.mapper().translate_addr(addr), Some(shared));
+    assert_eq!(memory::frame_references(frame), 2);
+
+    assert!(child.write(addr, b"child!"));
+    assert_ne!(child.mapper().translate_addr(addr), Some(shared));
+    assert_eq!(memory::frame_references(frame), 1);
+
+    let mut buf = [0; 6];
+    assert!(parent.read(addr, &mut buf));
+    assert_eq!(&buf, b"parent");
+    assert!(child.read(addr, &mut buf));
+    assert_eq!(&buf, b"child!");
+
+    // the last reference is made writable without copying it
+    assert!(parent.write(addr, b"again!"));
+    assert_eq!(parent.mapper().translate_addr(addr), Some(shared));
+}
+
+#[test_case]
+fn lazy_allocation() {
+    let addr = VirtAddr::new(MMAP_START);
+    let contents = b"file contents".to_vec();
+    let file = alloc::sync::Arc::new(crate::sys::file::MemoryFile::new(contents));
+    let mut space = AddressSpace::new().unwrap();
+    space.map_area(
+        page_range(MMAP_START, 4096 * 2),
+        PageTableFlags::empty(),
+        Backing::File { file, offset: 0 },
+    );
+    assert!(space.mapper().translate_addr(addr).is_none());
+    assert!(space.is_accessible(addr, 4096 * 2, false));
+    assert!(!space.is_accessible(addr, 4096 * 2, true));
+
+    assert!(!space.handle_fault(addr, true));
+    assert!(space.handle_fault(addr, false));
+    assert!(space.mapper().translate_addr(addr).is_some());
+    assert!(space.mapper().translate_addr(addr + 4096u64).is_none());
+
+    let mut buf = [0xff; 16];
+    assert!(space.read(addr, &mut buf));
+    assert_eq!(&buf[..13], b"file contents");
+    assert_eq!(buf[13..], [0; 3]);
+
+    space.unmap(page_range(MMAP_START, 4096));
+    assert!(space.mapper().translate_addr(addr).is_none());
+    assert!(!space.handle_fault(addr, false));
+    assert!(space.handle_fault(addr + 4096u64, false));
+}
+
+#[test_case]
+fn program_break() {
+    let mut space = AddressSpace::new().unwrap();
+    assert_eq!(space.set_break(CODE_START), 0);
+
+    space.set_break_start(VirtAddr::new(CODE_START + 100));
+    let start = CODE_START + 4096;
+    assert_eq!(space.set_break(0), start);
+    assert_eq!(space.set_break(start + 10000), start + 10000);
+    assert!(space.is_accessible(VirtAddr::new(start), 10000, true));
+    assert_eq!(space.set_break(start + 10), start + 10);
+    assert!(!space.is_accessible(VirtAddr::new(start + 4096), 1, false));
+
+    space.map_area(
+        page_range(start + 4096 * 4, 4096),
+        PageTableFlags::empty(),
+        Backing::Anonymous,
+    );
+    assert_eq!(space.set_break(start + 4096 * 8), start + 10);
+}
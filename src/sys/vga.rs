@@ -3,9 +3,10 @@ use core::fmt;
 
 use bit_field::BitField;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use vte::{Params, Parser, Perform};
-use x86_64::instructions::{interrupts, port::Port};
+use x86_64::instructions::port::Port;
+
+use crate::sys::sync::IrqSafeMutex;
 
 pub mod font;
 
@@ -22,10 +23,8 @@ const INPUT_STATUS_REG: u16 = 0x3da;
 const CRTC_REG: u16 = 0x3d4;
 
 lazy_static! {
-    static ref PARSER: Mutex<Parser> = Mutex::new(Parser::new());
-    /// Remember to disable interrupts when the lock is held,
-    /// as an unexpected interrupt might result in a deadlock.
-    static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    static ref PARSER: IrqSafeMutex<Parser> = IrqSafeMutex::new(Parser::new());
+    static ref WRITER: IrqSafeMutex<Writer> = IrqSafeMutex::new(Writer {
         writer_position: (0, 0),
         cursor_position: (0, 0),
         color_code: CharColor::new(FG, BG),
@@ -322,15 +321,11 @@ fn set_blink(enabled: bool) {
 }
 
 pub fn set_font(font: &Font) {
-    interrupts::without_interrupts(|| {
-        WRITER.lock().set_font(font);
-    });
+    WRITER.lock().set_font(font);
 }
 
 pub fn init() {
-    interrupts::without_interrupts(|| {
-        WRITER.lock().clear_screen();
-    });
+    WRITER.lock().clear_screen();
     set_blink(false);
     set_font(&font::IBM_BIOS);
 }
@@ -342,9 +337,7 @@ pub fn init() {
 pub fn print_fmt(args: fmt::Arguments) {
     use core::fmt::Write;
 
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    WRITER.lock().write_fmt(args).unwrap();
 }
 
 #[macro_export]
@@ -373,15 +366,12 @@ fn test_println_many() {
 #[test_case]
 fn test_println_output() {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
     let s = "Some test string that fits on a single line";
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
-        writeln!(writer, "\n{}", s).expect("writeln failed");
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i];
-            assert_eq!(char::from(screen_char.ascii_character), c);
-        }
-    });
+    let mut writer = WRITER.lock();
+    writeln!(writer, "\n{s}").expect("writeln failed");
+    for (i, c) in s.chars().enumerate() {
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i];
+        assert_eq!(char::from(screen_char.ascii_character), c);
+    }
 }
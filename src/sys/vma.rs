@@ -0,0 +1,264 @@
+//! The areas of user memory mapped into an address space (see
+//! [`crate::sys::user::AddressSpace`]), which are only backed by
+//! memory once their pages are accessed.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{fmt, ops::Range};
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::sys::file::File;
+
+/// What the pages of an area hold before they are written to.
+#[derive(Clone)]
+pub enum Backing {
+    /// Zeroes.
+    Anonymous,
+    /// A private copy of `file`, from `offset`.
+    File { file: Arc<dyn File>, offset: u64 },
+}
+
+impl fmt::Debug for Backing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Anonymous => f.write_str("Anonymous"),
+            Self::File { offset, .. } => f.debug_struct("File").field("offset", offset).finish(),
+        }
+    }
+}
+
+impl Backing {
+    /// The backing of what is `delta` bytes into the area.
+    fn advance(&self, delta: u64) -> Self {
+        match self {
+            Self::Anonymous => Self::Anonymous,
+            Self::File { file, offset } => Self::File {
+                file: file.clone(),
+                offset: offset + delta,
+            },
+        }
+    }
+
+    /// Fill the zeroed `page`, `offset` bytes into the area. What is
+    /// past the end of a file stays zeroed.
+    pub fn fill(&self, offset: u64, page: &mut [u8]) {
+        let (file, start) = match self {
+            Self::Anonymous => return,
+            Self::File { file, offset } => (file, offset),
+        };
+
+        let mut filled = 0;
+        while filled < page.len() {
+            match file.read_at(start + offset + filled as u64, &mut page[filled..]) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => filled += read,
+            }
+        }
+    }
+}
+
+/// A page-aligned range of user memory.
+#[derive(Debug, Clone)]
+pub struct Area {
+    pub range: Range<u64>,
+    /// The flags of its pages, in addition to user access.
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+}
+
+impl Area {
+    #[must_use]
+    pub const fn new(range: Range<u64>, flags: PageTableFlags, backing: Backing) -> Self {
+        Self {
+            range,
+            flags,
+            backing,
+        }
+    }
+
+    /// The part of the area in `range`, which has to overlap it.
+    fn slice(&self, range: &Range<u64>) -> Self {
+        let start = self.range.start.max(range.start);
+        let end = self.range.end.min(range.end);
+        Self {
+            range: start..end,
+            flags: self.flags,
+            backing: self.backing.advance(start - self.range.start),
+        }
+    }
+
+    /// Whether `next` directly follows the area, and could be part of it.
+    #[allow(clippy::suspicious_operation_groupings)]
+    fn joins(&self, next: &Self) -> bool {
+        self.range.end == next.range.start
+            && self.flags == next.flags
+            && matches!(
+                (&self.backing, &next.backing),
+                (Backing::Anonymous, Backing::Anonymous)
+            )
+    }
+}
+
+/// Areas that don't overlap, in order.
+#[derive(Debug, Clone, Default)]
+pub struct Areas {
+    /// Indexed by start address.
+    areas: BTreeMap<u64, Area>,
+}
+
+impl Areas {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Area> {
+        self.areas.values()
+    }
+
+    /// The area containing `addr`.
+    #[must_use]
+    pub fn find(&self, addr: u64) -> Option<&Area> {
+        self.areas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.range.contains(&addr))
+    }
+
+    /// Whether all of `range` is in areas with (at least) `flags`.
+    #[must_use]
+    pub fn covers(&self, range: Range<u64>, flags: PageTableFlags) -> bool {
+        let mut addr = range.start;
+        while addr < range.end {
+            match self.find(addr) {
+                Some(area) if area.flags.contains(flags) => addr = area.range.end,
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Whether none of `range` is in an area.
+    #[must_use]
+    pub fn is_free(&self, range: &Range<u64>) -> bool {
+        self.overlapping(range).next().is_none()
+    }
+
+    /// The lowest `len` bytes within `bounds` that aren't in an area.
+    #[must_use]
+    pub fn find_free(&self, len: u64, bounds: Range<u64>) -> Option<u64> {
+        let mut start = bounds.start;
+        for area in self.areas.values() {
+            if area.range.end <= start {
+                continue;
+            }
+            if area.range.start >= start.checked_add(len)? {
+                break;
+            }
+            start = area.range.end;
+        }
+        (start.checked_add(len)? <= bounds.end).then_some(start)
+    }
+
+    /// Add `area`, removing whatever was in its range (see
+    /// [`Areas::remove`]).
+    pub fn insert(&mut self, area: Area) -> Vec<Area> {
+        let removed = self.remove(&area.range);
+        let start = area.range.start;
+        self.areas.insert(start, area);
+
+        self.join_next(start);
+        if let Some(&prev) = self
+            .areas
+            .range(..start)
+            .next_back()
+            .map(|(start, _)| start)
+        {
+            self.join_next(prev);
+        }
+        removed
+    }
+
+    /// Remove everything in `range`, splitting the areas that are only
+    /// partly in it, and return the parts removed.
+    pub fn remove(&mut self, range: &Range<u64>) -> Vec<Area> {
+        let starts: Vec<u64> = self
+            .overlapping(range)
+            .map(|area| area.range.start)
+            .collect();
+
+        let overlapping: Vec<Area> = starts
+            .iter()
+            .filter_map(|start| self.areas.remove(start))
+            .collect();
+
+        let mut removed = Vec::new();
+        for area in overlapping {
+            if area.range.start < range.start {
+                let before = area.slice(&(area.range.start..range.start));
+                self.areas.insert(before.range.start, before);
+            }
+            if area.range.end > range.end {
+                let after = area.slice(&(range.end..area.range.end));
+                self.areas.insert(after.range.start, after);
+            }
+            removed.push(area.slice(range));
+        }
+        removed
+    }
+
+    /// Change the flags of everything in `range`.
+    pub fn protect(&mut self, range: &Range<u64>, flags: PageTableFlags) {
+        for mut area in self.remove(range) {
+            area.flags = flags;
+            self.insert(area);
+        }
+    }
+
+    /// Join the area at `start` and the one right after it, if they can
+    /// be joined.
+    fn join_next(&mut self, start: u64) {
+        let area = &self.areas[&start];
+        let end = area.range.end;
+        if self.areas.get(&end).map_or(false, |next| area.joins(next)) {
+            let next = self.areas.remove(&end).unwrap();
+            self.areas.get_mut(&start).unwrap().range.end = next.range.end;
+        }
+    }
+
+    fn overlapping<'a>(&'a self, range: &Range<u64>) -> impl Iterator<Item = &'a Area> {
+        let start = range.start;
+        self.areas
+            .range(..range.end)
+            .map(|(_, area)| area)
+            .filter(move |area| area.range.end > start)
+    }
+}
+
+#[test_case]
+fn split_and_merge() {
+    let rw = PageTableFlags::WRITABLE;
+    let mut areas = Areas::new();
+    areas.insert(Area::new(0x1000..0x3000, rw, Backing::Anonymous));
+    areas.insert(Area::new(0x3000..0x5000, rw, Backing::Anonymous));
+    assert_eq!(areas.iter().count(), 1);
+    assert!(areas.covers(0x1000..0x5000, rw));
+
+    areas.protect(&(0x2000..0x3000), PageTableFlags::empty());
+    assert_eq!(areas.iter().count(), 3);
+    assert!(!areas.covers(0x1000..0x5000, rw));
+    assert!(areas.covers(0x1000..0x5000, PageTableFlags::empty()));
+
+    let removed = areas.remove(&(0x1800..0x4000));
+    assert_eq!(removed.len(), 3);
+    assert_eq!(
+        areas.find(0x1000).map(|a| a.range.clone()),
+        Some(0x1000..0x1800)
+    );
+    assert!(areas.find(0x2000).is_none());
+    assert!(areas.is_free(&(0x1800..0x4000)));
+    assert_eq!(areas.find_free(0x1000, 0x1000..0x6000), Some(0x1800));
+    assert_eq!(areas.find_free(0x3000, 0x1000..0x6000), None);
+}
@@ -0,0 +1,34 @@
+#![no_std]
+#![no_main]
+
+use aaos::{exit_qemu, serial_print, serial_println, sys::sync::Mutex, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("test mutex_deadlock ... ");
+
+    aaos::init(boot_info);
+
+    let mutex = Mutex::new(());
+    let _guard = mutex.lock();
+    let _again = mutex.lock();
+
+    serial_println!("\x1b[31mfailed\x1b[0m");
+    serial_println!("Error: relocking a held mutex didn't panic");
+    exit_qemu(QemuExitCode::Failed);
+
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("\x1b[32mok\x1b[0m");
+    exit_qemu(QemuExitCode::Success);
+
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
@@ -0,0 +1,263 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(aaos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use aaos::sys::{
+    clock,
+    sync::{channel, Condvar, Mutex, RwLock, Semaphore, TryRecvError, TrySendError},
+    task::{executor::Executor, yield_now},
+    thread,
+};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    aaos::init(boot_info);
+
+    test_main();
+
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    aaos::test_panic_handler(info)
+}
+
+#[test_case]
+fn mutex_contention() {
+    let counter = Arc::new(Mutex::new(0_usize));
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    let mut counter = counter.lock();
+                    let value = *counter;
+                    // invite a context switch while holding the lock
+                    thread::yield_now();
+                    *counter = value + 1;
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+
+    assert_eq!(*counter.lock(), 4000);
+}
+
+#[test_case]
+fn semaphore_from_interrupt() {
+    static SEMAPHORE: Semaphore = Semaphore::new(0);
+
+    clock::enable_periodic(256, || SEMAPHORE.add_permits(1)).unwrap();
+    for _ in 0..16 {
+        SEMAPHORE.acquire().forget();
+    }
+    clock::disable_periodic();
+}
+
+#[test_case]
+fn semaphore_permits() {
+    let semaphore = Semaphore::new(2);
+
+    let a = semaphore.try_acquire().unwrap();
+    let _b = semaphore.try_acquire().unwrap();
+    assert!(semaphore.try_acquire().is_none());
+
+    drop(a);
+    assert_eq!(semaphore.available_permits(), 1);
+}
+
+#[test_case]
+fn condvar_producer_consumer() {
+    let shared = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
+
+    let consumer = {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let (queue, condvar) = &*shared;
+            let mut sum = 0;
+            let mut queue = queue.lock();
+            loop {
+                queue = condvar.wait_while(queue, |queue| queue.is_empty());
+                match queue.pop_front().unwrap() {
+                    0 => break,
+                    value => sum += value,
+                }
+            }
+            assert_eq!(sum, 5050);
+        })
+    };
+
+    let (queue, condvar) = &*shared;
+    for value in (1..=100).chain(Some(0)) {
+        queue.lock().push_back(value);
+        condvar.notify_one();
+    }
+    consumer.join();
+}
+
+#[test_case]
+fn rwlock_readers_and_writers() {
+    let lock = Arc::new(RwLock::new(0_usize));
+
+    {
+        let a = lock.read();
+        let b = lock.read();
+        assert_eq!(*a + *b, 0);
+        assert!(lock.try_write().is_none());
+    }
+
+    let handles: Vec<_> = (0..3)
+        .map(|_| {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    *lock.write() += 1;
+                    let value = *lock.read();
+                    assert!(value > 0);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+
+    assert!(lock.try_read().is_some());
+    assert_eq!(*lock.read(), 300);
+}
+
+#[test_case]
+fn async_mutex() {
+    static MUTEX: Mutex<usize> = Mutex::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    for _ in 0..8 {
+        executor.spawn(async {
+            for _ in 0..10 {
+                let mut value = MUTEX.lock_async().await;
+                *value += 1;
+                // the other tasks have to wait for the guard
+                yield_now().await;
+            }
+            DONE.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    executor.run_until_idle();
+
+    assert_eq!(DONE.load(Ordering::Relaxed), 8);
+    assert_eq!(*MUTEX.lock(), 80);
+}
+
+#[test_case]
+fn channel_close() {
+    let (sender, receiver) = channel(2);
+    sender.send(1).unwrap();
+    assert_eq!(sender.try_send(2), Ok(()));
+    assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+    assert_eq!(receiver.len(), 2);
+
+    let other = sender.clone();
+    drop(sender);
+    assert_eq!(receiver.recv(), Ok(1));
+    drop(other);
+    // what was sent can still be received
+    assert_eq!(receiver.try_recv(), Ok(2));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+    assert!(receiver.recv().is_err());
+
+    let (sender, receiver) = channel(1);
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(sender.send(4).map_err(|e| e.0), Err(4));
+}
+
+#[test_case]
+fn channel_under_load() {
+    const CAPACITY: usize = 4;
+    let (sender, receiver) = channel(CAPACITY);
+
+    let producers: Vec<_> = (0..4_usize)
+        .map(|producer| {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for i in 0..500 {
+                    sender.send((producer, i)).unwrap();
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+
+    // messages from each producer arrive in order, and senders wait
+    // while the channel is full
+    let mut next = [0; 4];
+    while let Ok((producer, i)) = receiver.recv() {
+        assert!(receiver.len() <= CAPACITY);
+        assert_eq!(i, next[producer]);
+        next[producer] += 1;
+    }
+    assert_eq!(next, [500; 4]);
+
+    for producer in producers {
+        producer.join();
+    }
+}
+
+#[test_case]
+fn channel_close_wakes_blocked_sender() {
+    let (sender, receiver) = channel(1);
+    sender.send(0).unwrap();
+
+    let blocked = thread::spawn(move || {
+        // blocks until the receiver is dropped
+        assert!(sender.send(1).is_err());
+    });
+    thread::yield_now();
+    drop(receiver);
+    blocked.join();
+}
+
+#[test_case]
+fn async_channel() {
+    static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+    let (sender, receiver) = channel(2);
+    let mut executor = Executor::new();
+    for task in 0..4 {
+        let sender = sender.clone();
+        executor.spawn(async move {
+            for i in 0..25 {
+                sender.send_async(task * 25 + i).await.unwrap();
+            }
+        });
+    }
+    drop(sender);
+    executor.spawn(async move {
+        let mut sum = 0;
+        while let Ok(value) = receiver.recv_async().await {
+            sum += value;
+        }
+        RECEIVED.store(sum, Ordering::Relaxed);
+    });
+    executor.run_until_idle();
+
+    assert_eq!(RECEIVED.load(Ordering::Relaxed), 4950);
+}
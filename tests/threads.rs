@@ -0,0 +1,200 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(aaos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use aaos::sys::{thread, time};
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    aaos::init(boot_info);
+
+    test_main();
+
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    aaos::test_panic_handler(info)
+}
+
+#[test_case]
+fn spawn_and_join() {
+    let value = Arc::new(AtomicUsize::new(0));
+
+    let handle = {
+        let value = value.clone();
+        thread::spawn(move || value.store(42, Ordering::Relaxed))
+    };
+    handle.join();
+
+    assert_eq!(value.load(Ordering::Relaxed), 42);
+}
+
+#[test_case]
+fn preemption_interleaves_threads() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static PROGRESS: [AtomicUsize; 3] = [
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+    ];
+
+    // none of the threads ever yields, so they can only all make
+    // progress if they are preempted
+    let handles: Vec<_> = (0..3)
+        .map(|i| {
+            thread::spawn(move || {
+                while !STOP.load(Ordering::Relaxed) {
+                    PROGRESS[i].fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+
+    time::sleep(0.1);
+    let progress: Vec<_> = PROGRESS.iter().map(|p| p.load(Ordering::Relaxed)).collect();
+    STOP.store(true, Ordering::Relaxed);
+
+    for handle in handles {
+        handle.join();
+    }
+
+    assert!(progress.iter().all(|&p| p > 0), "{progress:?}");
+}
+
+#[test_case]
+fn yield_now_alternates() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+
+    let handles: Vec<_> = (0..2)
+        .map(|i| {
+            let log = log.clone();
+            thread::spawn(move || {
+                for _ in 0..3 {
+                    log.lock().push(i);
+                    thread::yield_now();
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join();
+    }
+
+    let log = log.lock();
+    assert_eq!(log.len(), 6);
+    assert!(log.windows(2).filter(|w| w[0] != w[1]).count() >= 3);
+}
+
+#[test_case]
+fn exit_early() {
+    static REACHED: AtomicBool = AtomicBool::new(false);
+
+    #[allow(unreachable_code)]
+    let handle = thread::spawn(|| {
+        thread::exit();
+        REACHED.store(true, Ordering::Relaxed);
+    });
+    let id = handle.id();
+    handle.join();
+
+    assert!(!REACHED.load(Ordering::Relaxed));
+    assert_eq!(thread::state(id), None);
+}
+
+#[test_case]
+fn detached_thread_is_freed_on_spawn() {
+    let id = thread::spawn(|| {}).id();
+    while thread::state(id).is_some_and(|state| state != thread::State::Exited) {
+        thread::yield_now();
+    }
+
+    thread::spawn(|| {}).join();
+    assert_eq!(thread::state(id), None);
+}
+
+#[test_case]
+fn fixed_priority_starves_lower_priorities() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static LOW_PROGRESS: AtomicUsize = AtomicUsize::new(0);
+    static LOW_PROGRESS_DURING_HIGH: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+    thread::set_policy(thread::FixedPriority::new());
+
+    let low = thread::Builder::new().priority(1).spawn(|| {
+        while !STOP.load(Ordering::Relaxed) {
+            LOW_PROGRESS.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    let high = thread::Builder::new().priority(200).spawn(|| {
+        let before = LOW_PROGRESS.load(Ordering::Relaxed);
+        let start = aaos::sys::clock::uptime();
+        while aaos::sys::clock::uptime() - start < 0.05 {}
+        let after = LOW_PROGRESS.load(Ordering::Relaxed);
+        LOW_PROGRESS_DURING_HIGH.store(after - before, Ordering::Relaxed);
+    });
+
+    high.join();
+    STOP.store(true, Ordering::Relaxed);
+    low.join();
+    thread::set_policy(thread::RoundRobin::new());
+
+    assert_eq!(LOW_PROGRESS_DURING_HIGH.load(Ordering::Relaxed), 0);
+}
+
+#[test_case]
+fn fair_shares_by_nice_value() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    thread::set_policy(thread::Fair::new());
+
+    let busy = || {
+        while !STOP.load(Ordering::Relaxed) {
+            core::hint::spin_loop();
+        }
+    };
+    let nice = thread::Builder::new().nice(0).spawn(busy);
+    let nicer = thread::Builder::new().nice(10).spawn(busy);
+
+    time::sleep(0.3);
+    let stats = thread::stats(nice.id()).unwrap();
+    let nicer_stats = thread::stats(nicer.id()).unwrap();
+
+    STOP.store(true, Ordering::Relaxed);
+    nice.join();
+    nicer.join();
+    thread::set_policy(thread::RoundRobin::new());
+
+    // the weights differ by a factor of ~9
+    assert!(
+        stats.runtime > 3.0 * nicer_stats.runtime,
+        "{stats:?} {nicer_stats:?}"
+    );
+    assert!(nicer_stats.wait_time > 0.0);
+    assert!(stats.switches > 1);
+}
+
+#[test_case]
+fn stats_of_exited_thread() {
+    let handle = thread::spawn(|| {});
+    let id = handle.id();
+    handle.join();
+
+    assert_eq!(thread::stats(id), None);
+    assert!(thread::stats(thread::current()).unwrap().switches > 0);
+}
@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(aaos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use aaos::sys::{
+    file::{Console, File, FileTable},
+    initrd, pipe, process,
+};
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    aaos::init(boot_info);
+
+    test_main();
+
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    aaos::test_panic_handler(info)
+}
+
+/// Run the program `argv[0]` with `input` as its standard input, and
+/// return its exit code and standard output.
+fn run(argv: &[&str], input: &[u8]) -> (i32, Vec<u8>) {
+    let (stdin, writer) = pipe::pipe();
+    assert_eq!(writer.write(input), Ok(input.len()));
+    drop(writer);
+    let (reader, stdout) = pipe::pipe();
+
+    let mut files = FileTable::new();
+    files.insert(Arc::new(stdin)).unwrap();
+    files.insert(Arc::new(stdout)).unwrap();
+    files.insert(Arc::new(Console)).unwrap();
+
+    let image = initrd::find(argv[0]).unwrap();
+    let pid = process::spawn_with_files(image, argv, &[], files).unwrap();
+
+    let mut output = Vec::new();
+    let mut buf = [0; 256];
+    loop {
+        match reader.read(&mut buf).unwrap() {
+            0 => break,
+            read => output.extend_from_slice(&buf[..read]),
+        }
+    }
+    let (_, code) = process::wait(Some(pid)).unwrap();
+    (code, output)
+}
+
+#[test_case]
+fn echo() {
+    assert_eq!(
+        run(&["echo", "hello", "world"], b""),
+        (0, b"hello world\n".to_vec())
+    );
+    assert_eq!(run(&["echo", "-n", "hello"], b""), (0, b"hello".to_vec()));
+    assert_eq!(run(&["echo"], b""), (0, b"\n".to_vec()));
+}
+
+#[test_case]
+fn cat() {
+    assert_eq!(
+        run(&["cat"], b"from a pipe\n"),
+        (0, b"from a pipe\n".to_vec())
+    );
+    assert_eq!(
+        run(&["cat", "motd", "-"], b"and stdin\n"),
+        (0, b"Welcome to aaos!\nand stdin\n".to_vec())
+    );
+    assert_eq!(run(&["cat", "missing"], b""), (1, Vec::new()));
+}
+
+#[test_case]
+fn ls() {
+    assert_eq!(run(&["ls"], b""), (0, b"cat\necho\nls\nmotd\n".to_vec()));
+}
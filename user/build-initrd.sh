@@ -0,0 +1,32 @@
+#!/bin/sh
+# Build the user programs and copy them to `src/sys/initrd`, or with
+# `--check`, fail if the copies there aren't what the sources build to.
+#
+# Paths that differ between machines are remapped, so that the build
+# is reproducible with the toolchain from `rust-toolchain.toml`.
+
+set -eu
+
+cd "$(dirname "$0")"
+
+sysroot=$(rustc --print sysroot)
+cargo_home=${CARGO_HOME:-$HOME/.cargo}
+cargo build --release --config "build.rustflags = [
+    '--remap-path-prefix=$sysroot=/rustc',
+    '--remap-path-prefix=$cargo_home=/cargo',
+]"
+
+status=0
+for program in cat echo ls; do
+    built=target/x86_64-unknown-none/release/$program
+    packed=../src/sys/initrd/$program
+    if [ "${1:-}" = --check ]; then
+        if ! cmp -s "$built" "$packed"; then
+            echo "$packed is out of date, run user/build-initrd.sh" >&2
+            status=1
+        fi
+    else
+        cp "$built" "$packed"
+    fi
+done
+exit $status
@@ -0,0 +1,51 @@
+//! `cat [FILE]...`: print the files, or standard input if there are
+//! none, or for `-`.
+
+#![no_std]
+#![no_main]
+
+use runtime::{
+    entry, env, eprintln,
+    io::{self, STDIN, STDOUT},
+    syscall,
+};
+
+entry!(main);
+
+fn main() -> i32 {
+    let mut code = 0;
+    let mut files = env::args().skip(1).peekable();
+    if files.peek().is_none() {
+        return cat("-");
+    }
+    for file in files {
+        code = code.max(cat(file));
+    }
+    code
+}
+
+fn cat(name: &str) -> i32 {
+    let fd = if name == "-" {
+        STDIN
+    } else {
+        match syscall::open(name) {
+            Ok(fd) => fd,
+            Err(e) => {
+                eprintln!("cat: {}: {}", name, e);
+                return 1;
+            }
+        }
+    };
+
+    let result = io::copy(fd, STDOUT);
+    if fd != STDIN {
+        let _ = syscall::close(fd);
+    }
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("cat: {}: {}", name, e);
+            1
+        }
+    }
+}
@@ -0,0 +1,25 @@
+//! `echo [-n] [ARG]...`: print the arguments, separated by spaces, and a
+//! newline unless `-n` is given.
+
+#![no_std]
+#![no_main]
+
+use runtime::{entry, env, print, println};
+
+entry!(main);
+
+fn main() -> i32 {
+    let mut args = env::args().skip(1).peekable();
+    let newline = args.next_if_eq(&"-n").is_none();
+
+    if let Some(first) = args.next() {
+        print!("{}", first);
+        for arg in args {
+            print!(" {}", arg);
+        }
+    }
+    if newline {
+        println!();
+    }
+    0
+}
@@ -0,0 +1,32 @@
+//! `ls`: list the files packed into the kernel, in order, one per line.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use runtime::{entry, eprintln, println, syscall};
+
+entry!(main);
+
+fn main() -> i32 {
+    let mut names = Vec::new();
+    let mut buf = [0; 256];
+    loop {
+        match syscall::readdir(names.len(), &mut buf) {
+            Ok(0) => break,
+            Ok(len) => names.push(String::from_utf8_lossy(&buf[..len]).into_owned()),
+            Err(e) => {
+                eprintln!("ls: {}", e);
+                return 1;
+            }
+        }
+    }
+
+    names.sort();
+    for name in names {
+        println!("{}", name);
+    }
+    0
+}
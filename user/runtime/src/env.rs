@@ -0,0 +1,58 @@
+//! The arguments and environment the program was started with.
+
+use core::{ptr, slice};
+
+static mut ARGV: &[*const u8] = &[];
+static mut ENVP: &[*const u8] = &[];
+
+/// Remember the argument and environment vectors from the initial
+/// stack: `argc`, then `argc` pointers, a null pointer, and the
+/// environment up to another null pointer.
+///
+/// # Safety
+///
+/// `stack` has to be the initial stack pointer, and this must only be
+/// called once, before the main function.
+pub(crate) unsafe fn init(stack: *const u64) {
+    #[allow(clippy::cast_possible_truncation)]
+    let arg_count = *stack as usize;
+    let args = stack.add(1).cast::<*const u8>();
+    let vars = args.add(arg_count + 1);
+    let mut var_count = 0;
+    while !(*vars.add(var_count)).is_null() {
+        var_count += 1;
+    }
+
+    ARGV = slice::from_raw_parts(args, arg_count);
+    ENVP = slice::from_raw_parts(vars, var_count);
+}
+
+/// The strings at `pointers`, which aren't valid UTF-8 are empty.
+fn strings(pointers: &'static [*const u8]) -> impl Iterator<Item = &'static str> {
+    pointers.iter().map(|&pointer| {
+        let bytes = unsafe {
+            let mut len = 0;
+            while *pointer.add(len) != 0 {
+                len += 1;
+            }
+            slice::from_raw_parts(pointer, len)
+        };
+        core::str::from_utf8(bytes).unwrap_or_default()
+    })
+}
+
+/// The arguments of the program, starting with its name.
+pub fn args() -> impl Iterator<Item = &'static str> {
+    strings(unsafe { *ptr::addr_of!(ARGV) })
+}
+
+/// The environment variables of the program, as `(name, value)`.
+pub fn vars() -> impl Iterator<Item = (&'static str, &'static str)> {
+    strings(unsafe { *ptr::addr_of!(ENVP) }).map(|var| var.split_once('=').unwrap_or((var, "")))
+}
+
+/// The value of the environment variable `name`.
+#[must_use]
+pub fn var(name: &str) -> Option<&'static str> {
+    vars().find(|(var, _)| *var == name).map(|(_, value)| value)
+}
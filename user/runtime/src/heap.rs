@@ -0,0 +1,113 @@
+//! The global allocator. Small blocks come from the heap (see
+//! [`syscall::brk`]), in power-of-two sizes that are kept in free
+//! lists once freed. Larger ones are mapped on their own.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    ptr,
+};
+
+use crate::syscall::{self, PROT_READ, PROT_WRITE};
+
+const PAGE_SIZE: usize = 4096;
+/// The smallest block, which can hold the free list link.
+const MIN_BLOCK: usize = 16;
+/// Blocks of 16 bytes to 2 KiB come from the heap.
+const CLASSES: usize = 8;
+/// How much the heap grows by at a time.
+const GROW_BY: u64 = 16 * PAGE_SIZE as u64;
+
+struct State {
+    /// Freed blocks of each size, linked through their first word.
+    free: [*mut u8; CLASSES],
+    /// The unused part of the heap.
+    next: u64,
+    end: u64,
+}
+
+struct Allocator(UnsafeCell<State>);
+
+// Processes have a single thread, and signal handlers mustn't
+// allocate.
+unsafe impl Sync for Allocator {}
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator(UnsafeCell::new(State {
+    free: [ptr::null_mut(); CLASSES],
+    next: 0,
+    end: 0,
+}));
+
+/// The size class of `layout`, unless it is mapped on its own.
+fn class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(MIN_BLOCK);
+    let class = size.next_power_of_two().trailing_zeros() - MIN_BLOCK.trailing_zeros();
+    let class = class as usize;
+    (class < CLASSES).then_some(class)
+}
+
+const fn block_size(class: usize) -> usize {
+    MIN_BLOCK << class
+}
+
+impl State {
+    /// Take `size` bytes, aligned to `size`, from the heap.
+    fn carve(&mut self, size: usize) -> *mut u8 {
+        let size = size as u64;
+        if self.end == 0 {
+            self.end = syscall::brk(0);
+            self.next = self.end;
+        }
+
+        let start = (self.next + size - 1) & !(size - 1);
+        if start + size > self.end {
+            let end = (start + size).max(self.end + GROW_BY);
+            let end = (end + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1);
+            if syscall::brk(end) != end {
+                return ptr::null_mut();
+            }
+            self.end = end;
+        }
+        self.next = start + size;
+        start as *mut u8
+    }
+}
+
+// blocks are aligned to their size, at least `MIN_BLOCK`
+#[allow(clippy::cast_ptr_alignment)]
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let state = &mut *self.0.get();
+        match class(&layout) {
+            Some(class) => {
+                let block = state.free[class];
+                if block.is_null() {
+                    state.carve(block_size(class))
+                } else {
+                    state.free[class] = block.cast::<*mut u8>().read();
+                    block
+                }
+            }
+            // mappings are page aligned
+            None if layout.align() <= PAGE_SIZE => {
+                syscall::mmap_anonymous(layout.size(), PROT_READ | PROT_WRITE)
+                    .unwrap_or(ptr::null_mut())
+            }
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+        let state = &mut *self.0.get();
+        match class(&layout) {
+            Some(class) => {
+                block.cast::<*mut u8>().write(state.free[class]);
+                state.free[class] = block;
+            }
+            None => {
+                let _ = syscall::munmap(block, layout.size());
+            }
+        }
+    }
+}
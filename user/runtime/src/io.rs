@@ -0,0 +1,84 @@
+//! Standard input, output and error, and printing to them.
+
+use core::fmt;
+
+use crate::syscall::{self, Error};
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+/// Write all of `buf` to `fd`.
+///
+/// # Errors
+///
+/// If `fd` can't be written to, an error is returned.
+pub fn write_all(fd: usize, mut buf: &[u8]) -> Result<(), Error> {
+    while !buf.is_empty() {
+        match syscall::write(fd, buf) {
+            Ok(written) => buf = &buf[written..],
+            Err(Error::INTERRUPTED) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Copy everything `from` can be read from to `to`, and return the
+/// number of bytes copied.
+///
+/// # Errors
+///
+/// If reading or writing fails, an error is returned.
+pub fn copy(from: usize, to: usize) -> Result<usize, Error> {
+    let mut buf = [0; 512];
+    let mut copied = 0;
+    loop {
+        match syscall::read(from, &mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(read) => {
+                write_all(to, &buf[..read])?;
+                copied += read;
+            }
+            Err(Error::INTERRUPTED) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A file descriptor that can be written to with [`write!`].
+pub struct Writer(pub usize);
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_all(self.0, s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+#[doc(hidden)]
+pub fn print_fmt(fd: usize, args: fmt::Arguments) {
+    // there is nowhere to report errors on standard output or error
+    let _ = fmt::Write::write_fmt(&mut Writer(fd), args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::print_fmt($crate::io::STDOUT, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::print_fmt($crate::io::STDERR, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
@@ -0,0 +1,50 @@
+//! The runtime of user programs: system calls, printing, a heap and the
+//! entry point, which calls the function given to [`entry!`] with the
+//! arguments of the program (see [`env`]) and exits with what it returns.
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! use runtime::{entry, env, println};
+//!
+//! entry!(main);
+//!
+//! fn main() -> i32 {
+//!     println!("{} arguments", env::args().count());
+//!     0
+//! }
+//! ```
+
+#![no_std]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+extern crate alloc;
+
+pub mod env;
+mod heap;
+#[macro_use]
+pub mod io;
+mod start;
+pub mod syscall;
+
+use core::panic::PanicInfo;
+
+/// Make `main`, a `fn() -> i32`, the main function of the program.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[export_name = "aaos_main"]
+        fn __aaos_main() -> i32 {
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{}", info);
+    syscall::exit(101)
+}
@@ -0,0 +1,28 @@
+use core::arch::global_asm;
+
+use crate::{env, syscall};
+
+// The kernel starts programs with the arguments on the stack, which
+// is aligned for them, not for a call.
+global_asm!(
+    r#"
+    .global _start
+    _start:
+        mov rdi, rsp
+        and rsp, -16
+        call aaos_start
+        ud2
+    "#
+);
+
+extern "Rust" {
+    /// Defined by [`crate::entry!`].
+    fn aaos_main() -> i32;
+}
+
+#[no_mangle]
+unsafe extern "C" fn aaos_start(stack: *const u64) -> ! {
+    env::init(stack);
+    let code = aaos_main();
+    syscall::exit(code)
+}
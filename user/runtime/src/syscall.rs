@@ -0,0 +1,267 @@
+//! The system calls of the kernel (see `aaos::sys::syscall`), returning
+//! their errors as [`Error`].
+
+use core::{arch::asm, fmt};
+
+pub const EXIT: u64 = 0;
+pub const WRITE: u64 = 1;
+pub const CLOSE: u64 = 2;
+pub const GETPID: u64 = 3;
+pub const WAIT: u64 = 4;
+pub const FORK: u64 = 5;
+pub const MMAP: u64 = 6;
+pub const MUNMAP: u64 = 7;
+pub const MPROTECT: u64 = 8;
+pub const BRK: u64 = 9;
+pub const KILL: u64 = 10;
+pub const SIGACTION: u64 = 11;
+pub const SIGPROCMASK: u64 = 12;
+pub const SIGRETURN: u64 = 13;
+pub const READ: u64 = 14;
+pub const PIPE: u64 = 15;
+pub const OPEN: u64 = 16;
+pub const READDIR: u64 = 17;
+
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// A (negative) error number returned by a system call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error(pub i64);
+
+impl Error {
+    pub const NOT_FOUND: Self = Self(-2);
+    pub const INTERRUPTED: Self = Self(-4);
+    pub const BAD_FILE_DESCRIPTOR: Self = Self(-9);
+    pub const NO_CHILD: Self = Self(-10);
+    pub const OUT_OF_MEMORY: Self = Self(-12);
+    pub const BAD_ADDRESS: Self = Self(-14);
+    pub const INVALID_ARGUMENT: Self = Self(-22);
+    pub const TOO_MANY_FILES: Self = Self(-24);
+    pub const BROKEN_PIPE: Self = Self(-32);
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match *self {
+            Self::NOT_FOUND => "no such file",
+            Self::INTERRUPTED => "interrupted",
+            Self::BAD_FILE_DESCRIPTOR => "bad file descriptor",
+            Self::NO_CHILD => "no child process",
+            Self::OUT_OF_MEMORY => "out of memory",
+            Self::BAD_ADDRESS => "bad address",
+            Self::INVALID_ARGUMENT => "invalid argument",
+            Self::TOO_MANY_FILES => "too many open files",
+            Self::BROKEN_PIPE => "broken pipe",
+            Self(errno) => return write!(f, "error {}", -errno),
+        };
+        f.write_str(message)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Make the system call `number` with up to six arguments, and return
+/// what it returns in `rax`.
+///
+/// # Safety
+///
+/// The arguments have to be valid for the system call, which may write
+/// to memory they point to.
+#[must_use]
+pub unsafe fn syscall(number: u64, args: [u64; 6]) -> i64 {
+    let result: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+        in("r9") args[5],
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    result
+}
+
+/// The result of a system call, which returns errors as -4095 to -1.
+#[allow(clippy::cast_sign_loss)]
+const fn check(result: i64) -> Result<u64> {
+    if result < 0 && result >= -4095 {
+        Err(Error(result))
+    } else {
+        Ok(result as u64)
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+const fn to_usize(value: u64) -> usize {
+    value as usize
+}
+
+pub fn exit(code: i32) -> ! {
+    #[allow(clippy::cast_sign_loss)]
+    unsafe {
+        let _ = syscall(EXIT, [code as u64, 0, 0, 0, 0, 0]);
+    }
+    unreachable!("exit returned")
+}
+
+/// Write (some of) `buf` to `fd`, returning the number of bytes written.
+///
+/// # Errors
+///
+/// If it can't be written to, an error is returned.
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize> {
+    let args = [fd as u64, buf.as_ptr() as u64, buf.len() as u64, 0, 0, 0];
+    check(unsafe { syscall(WRITE, args) }).map(to_usize)
+}
+
+/// Read from `fd` into `buf`, returning the number of bytes read, which
+/// is 0 at the end of the file.
+///
+/// # Errors
+///
+/// If it can't be read from, an error is returned.
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize> {
+    let args = [
+        fd as u64,
+        buf.as_mut_ptr() as u64,
+        buf.len() as u64,
+        0,
+        0,
+        0,
+    ];
+    check(unsafe { syscall(READ, args) }).map(to_usize)
+}
+
+/// # Errors
+///
+/// If `fd` isn't open, [`Error::BAD_FILE_DESCRIPTOR`] is returned.
+pub fn close(fd: usize) -> Result<()> {
+    check(unsafe { syscall(CLOSE, [fd as u64, 0, 0, 0, 0, 0]) }).map(drop)
+}
+
+/// Open the file packed into the kernel as `name`, and return its file
+/// descriptor.
+///
+/// # Errors
+///
+/// If there is no such file, [`Error::NOT_FOUND`] is returned.
+pub fn open(name: &str) -> Result<usize> {
+    let args = [name.as_ptr() as u64, name.len() as u64, 0, 0, 0, 0];
+    check(unsafe { syscall(OPEN, args) }).map(to_usize)
+}
+
+/// Copy the name of the `index`th file packed into the kernel into
+/// `buf`, and return its length, which is 0 past the last file.
+///
+/// # Errors
+///
+/// If the name doesn't fit, [`Error::INVALID_ARGUMENT`] is returned.
+pub fn readdir(index: usize, buf: &mut [u8]) -> Result<usize> {
+    let args = [
+        index as u64,
+        buf.as_mut_ptr() as u64,
+        buf.len() as u64,
+        0,
+        0,
+        0,
+    ];
+    check(unsafe { syscall(READDIR, args) }).map(to_usize)
+}
+
+/// Create a pipe, and return the file descriptors of its reading and
+/// writing ends.
+///
+/// # Errors
+///
+/// If too many files are open, [`Error::TOO_MANY_FILES`] is returned.
+pub fn pipe() -> Result<(usize, usize)> {
+    let mut fds = [0_i32; 2];
+    check(unsafe { syscall(PIPE, [fds.as_mut_ptr() as u64, 0, 0, 0, 0, 0]) })?;
+    #[allow(clippy::cast_sign_loss)]
+    Ok((fds[0] as usize, fds[1] as usize))
+}
+
+#[must_use]
+pub fn getpid() -> u64 {
+    check(unsafe { syscall(GETPID, [0; 6]) }).unwrap_or_default()
+}
+
+/// Start a copy of the process, returning the id of the child, or 0 in
+/// the child.
+///
+/// # Errors
+///
+/// If the process can't be copied, [`Error::OUT_OF_MEMORY`] is returned.
+pub fn fork() -> Result<u64> {
+    check(unsafe { syscall(FORK, [0; 6]) })
+}
+
+/// Wait for the child `pid` (or any child, for `None`) to exit, and
+/// return its id and exit code.
+///
+/// # Errors
+///
+/// If there is no such child, [`Error::NO_CHILD`] is returned.
+pub fn wait(pid: Option<u64>) -> Result<(u64, i32)> {
+    let mut code = 0_i32;
+    let args = [
+        pid.unwrap_or(u64::MAX),
+        core::ptr::addr_of_mut!(code) as u64,
+        0,
+        0,
+        0,
+        0,
+    ];
+    let pid = check(unsafe { syscall(WAIT, args) })?;
+    Ok((pid, code))
+}
+
+/// Send the signal `sig` to the process `pid`.
+///
+/// # Errors
+///
+/// If there is no such process, an error is returned.
+pub fn kill(pid: u64, sig: u32) -> Result<()> {
+    check(unsafe { syscall(KILL, [pid, sig.into(), 0, 0, 0, 0]) }).map(drop)
+}
+
+/// Map `len` bytes of zeroed memory with the protection `prot`, and
+/// return its address.
+///
+/// # Errors
+///
+/// If there is no room for it, [`Error::OUT_OF_MEMORY`] is returned.
+pub fn mmap_anonymous(len: usize, prot: u64) -> Result<*mut u8> {
+    let flags = MAP_PRIVATE | MAP_ANONYMOUS;
+    let args = [0, len as u64, prot, flags, u64::MAX, 0];
+    check(unsafe { syscall(MMAP, args) }).map(|addr| addr as *mut u8)
+}
+
+/// Unmap the `len` bytes at `addr`.
+///
+/// # Safety
+///
+/// Nothing may use the memory anymore.
+///
+/// # Errors
+///
+/// If `addr` isn't page aligned, [`Error::INVALID_ARGUMENT`] is returned.
+pub unsafe fn munmap(addr: *mut u8, len: usize) -> Result<()> {
+    check(syscall(MUNMAP, [addr as u64, len as u64, 0, 0, 0, 0])).map(drop)
+}
+
+/// Move the end of the heap to `addr`, and return the new end, which is
+/// the old one if it can't be moved there (`brk(0)` returns it).
+#[must_use]
+pub fn brk(addr: u64) -> u64 {
+    check(unsafe { syscall(BRK, [addr, 0, 0, 0, 0, 0]) }).unwrap_or_default()
+}